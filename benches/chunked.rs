@@ -0,0 +1,48 @@
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use simple_webserver::http10::request::ChunkedFraming;
+
+// A chunked upload of len bytes in 1 KiB chunks
+fn chunked_body(len: usize) -> Vec<u8> {
+    let mut body = Vec::new();
+    for _ in 0..len / 1024 {
+        body.extend_from_slice(b"400\r\n");
+        body.extend_from_slice(&[b'a'; 1024]);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(b"0\r\n\r\n");
+    body
+}
+
+// Looking for the end of a body after every 4 KiB read, the way read_request does.
+// Rescanning from the start each time is what it did before framing kept its place.
+fn framing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("chunked_framing");
+    for len in [64 * 1024, 1024 * 1024] {
+        let body = chunked_body(len);
+        let reads: Vec<usize> = (4096..body.len())
+            .step_by(4096)
+            .chain([body.len()])
+            .collect();
+        group.bench_with_input(BenchmarkId::new("rescan", len), &body, |b, body| {
+            b.iter(|| {
+                for &end in &reads {
+                    black_box(ChunkedFraming::default().end(&body[..end]));
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("incremental", len), &body, |b, body| {
+            b.iter(|| {
+                let mut framing = ChunkedFraming::default();
+                for &end in &reads {
+                    black_box(framing.end(&body[..end]));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, framing);
+criterion_main!(benches);
@@ -0,0 +1,67 @@
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use simple_webserver::http10::headers::DEFAULT_MAX_HEADERS;
+use simple_webserver::http10::request::{self, BodyLength, HTTPRequest};
+
+// An upload with a Content-Length body of len bytes
+fn upload(len: usize) -> Vec<u8> {
+    let mut req = format!(
+        "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n",
+        len
+    )
+    .into_bytes();
+    req.resize(req.len() + len, b'a');
+    req
+}
+
+// Reading the upload 4 KiB at a time. Before, the whole request was run through the
+// parser after every read until it stopped reporting a short body. Now read_request
+// parses the head once and only compares lengths until the body is in.
+fn reading(c: &mut Criterion) {
+    let mut group = c.benchmark_group("content_length_body");
+    group.sample_size(10);
+    for len in [1024 * 1024, 4 * 1024 * 1024] {
+        let raw = upload(len);
+        group.bench_with_input(BenchmarkId::new("reparse", len), &raw, |b, raw| {
+            b.iter(|| {
+                let mut request = Vec::new();
+                for read in raw.chunks(4096) {
+                    request.extend_from_slice(read);
+                    if let Ok(parsed) = HTTPRequest::try_from(&request) {
+                        return black_box(parsed);
+                    }
+                }
+                unreachable!()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("head_once", len), &raw, |b, raw| {
+            b.iter(|| {
+                let mut request = Vec::new();
+                let mut expected = None;
+                for read in raw.chunks(4096) {
+                    request.extend_from_slice(read);
+                    if expected.is_none() {
+                        if let Some(head_len) = request::head_length(&request) {
+                            expected = match request::expected_body(
+                                &request[..head_len],
+                                DEFAULT_MAX_HEADERS,
+                            ) {
+                                Ok(BodyLength::Exact(len)) => Some(head_len + len),
+                                _ => unreachable!(),
+                            };
+                        }
+                    }
+                    if expected.is_some_and(|end| request.len() >= end) {
+                        return black_box(HTTPRequest::try_from(&request).unwrap());
+                    }
+                }
+                unreachable!()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, reading);
+criterion_main!(benches);
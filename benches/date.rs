@@ -0,0 +1,24 @@
+use std::hint::black_box;
+
+use chrono::{DateTime, FixedOffset, Utc};
+use criterion::{criterion_group, criterion_main, Criterion};
+use simple_webserver::http10::headers::Header;
+
+// The Date field every response carries, formatted afresh each time versus through
+// the cache of the last formatted second that Header::Date goes through
+fn date_field(c: &mut Criterion) {
+    let mut group = c.benchmark_group("date_field");
+    group.bench_function("uncached", |b| {
+        b.iter(|| {
+            let now: DateTime<FixedOffset> = Utc::now().into();
+            black_box(format!("Date: {}", now.to_rfc2822()))
+        })
+    });
+    group.bench_function("cached", |b| {
+        b.iter(|| black_box(Header::Date(Utc::now().into()).to_string()))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, date_field);
+criterion_main!(benches);
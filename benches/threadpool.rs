@@ -0,0 +1,38 @@
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use simple_webserver::threadpool::{QueueOrder, ThreadPoolQ};
+
+const JOBS: usize = 20_000;
+
+// Pushes JOBS small jobs and waits for the pool to finish them, so adding workers
+// should show up as more jobs per second unless pushing and taking contend
+fn throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pool_throughput");
+    group.throughput(Throughput::Elements(JOBS as u64));
+    for order in [QueueOrder::Fifo, QueueOrder::Lifo] {
+        for size in [1, 2, 4, 8] {
+            let id = BenchmarkId::new(format!("{:?}", order), size);
+            group.bench_with_input(id, &size, |b, &size| {
+                b.iter(|| {
+                    let mut tp = ThreadPoolQ::new(size, order, |num: usize| {
+                        let mut acc = num;
+                        for i in 0..200 {
+                            acc = acc.wrapping_mul(31).wrapping_add(i);
+                        }
+                        black_box(acc);
+                    });
+                    for job in 0..JOBS {
+                        tp.push_job(job);
+                    }
+                    // Dropping waits for the queues to drain
+                    drop(tp);
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
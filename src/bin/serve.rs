@@ -1,7 +1,12 @@
-use clap::builder::PossibleValuesParser;
-use clap::{value_parser, Arg, Command};
+use clap::builder::{PossibleValuesParser, RangedU64ValueParser};
+use clap::{value_parser, Arg, ArgAction, Command};
 use simple_webserver::http_server::*;
 use simple_webserver::*;
+use std::io::Read;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
 fn main() {
     let matches = Command::new("Simple Rust HTTP Server")
@@ -12,9 +17,64 @@ fn main() {
         .arg(Arg::new("protocol").default_value("HTTP/1.0").long("protocol"))
         .arg(Arg::new("bind").default_value("127.0.0.1").short('b').long("bind"))
         .arg(Arg::new("directory").default_value("./").short('d').long("directory"))
-        .arg(Arg::new("poolsize").value_parser(value_parser!(usize)).default_value("5").short('s').long("poolsize"))
+        .arg(Arg::new("vhost").action(ArgAction::Append).long("vhost").help("Serve a directory for one Host in the form of host=directory, the bound port may be left off, may be repeated"))
+        .arg(Arg::new("poolsize").value_parser(RangedU64ValueParser::<usize>::new().range(1..)).default_value("5").short('s').long("poolsize"))
         .arg(Arg::new("auth").help("Basic auth in the form of username:password").short('a').long("auth"))
-        .arg(Arg::new("level").default_value("Info").short('l').long("log-level").value_parser(PossibleValuesParser::new(["Debug", "Info", "Warn"])))
+        .arg(Arg::new("lifo").action(ArgAction::SetTrue).long("lifo").help("Serve queued connections newest-first under load"))
+        .arg(Arg::new("queue-timeout").value_parser(value_parser!(u64)).long("queue-timeout").help("Drop connections that waited in the queue longer than this many seconds"))
+        .arg(Arg::new("trusted-proxy").value_parser(value_parser!(IpAddr)).action(ArgAction::Append).long("trusted-proxy").help("Proxy address whose X-Forwarded-For header is trusted, may be repeated"))
+        .arg(Arg::new("hide-server").action(ArgAction::SetTrue).long("hide-server").help("Leave the Server header off all responses"))
+        .arg(Arg::new("verbose-errors").action(ArgAction::SetTrue).long("verbose-errors").help("Describe why a request failed to parse in 400 responses"))
+        .arg(Arg::new("max-headers").value_parser(value_parser!(usize)).default_value("100").long("max-headers").help("Maximum number of header lines accepted per request"))
+        .arg(Arg::new("max-decoded-body").value_parser(value_parser!(usize)).default_value("16777216").long("max-decoded-body").help("Bytes a compressed request body may decode to before it's refused with 413"))
+        .arg(Arg::new("max-requests").value_parser(value_parser!(usize)).default_value("100").long("max-requests").help("Requests served per kept-alive connection before closing it, 0 for unlimited"))
+        .arg(Arg::new("compress-type").action(ArgAction::Append).long("compress-type").help("MIME type (or type/*) to always compress, may be repeated"))
+        .arg(Arg::new("skip-compress-type").action(ArgAction::Append).long("skip-compress-type").help("MIME type (or type/*) to never compress, may be repeated"))
+        .arg(Arg::new("mime").action(ArgAction::Append).long("mime").help("MIME type override in the form of extension=type, may be repeated"))
+        .arg(Arg::new("cache-control").action(ArgAction::Append).long("cache-control").help("Cache-Control for files in the form of extension=directive, type/subtype=directive or type/*=directive, */* for the rest, may be repeated"))
+        .arg(Arg::new("early-hint").action(ArgAction::Append).long("early-hint").help("Link sent in a 103 Early Hints before responses under a path prefix in the form of /prefix=link, HTTP/1.1 only, may be repeated"))
+        .arg(Arg::new("charset").default_value("utf-8").long("charset").help("Charset declared on text Content-Types"))
+        .arg(Arg::new("no-charset").action(ArgAction::SetTrue).long("no-charset").help("Leave the charset off text Content-Types"))
+        .arg(Arg::new("strict-accept").action(ArgAction::SetTrue).long("strict-accept").help("Answer 406 when a file's type isn't in the request's Accept header"))
+        .arg(Arg::new("recv-buffer").value_parser(value_parser!(usize)).long("recv-buffer").help("Receive buffer size in bytes of accepted connections (SO_RCVBUF), the OS default when unset"))
+        .arg(Arg::new("send-buffer").value_parser(value_parser!(usize)).long("send-buffer").help("Send buffer size in bytes of accepted connections (SO_SNDBUF), larger helps with big files, the OS default when unset"))
+        .arg(Arg::new("write-buffer").value_parser(value_parser!(usize)).default_value("8192").long("write-buffer").help("Bytes of a response gathered before writing it out for throughput, 0 sends each part as soon as it's ready for latency"))
+        .arg(Arg::new("keepalive-idle").value_parser(value_parser!(u64)).long("keepalive-idle").help("Send TCP keepalive probes after a connection idles this many seconds"))
+        .arg(Arg::new("keepalive-interval").value_parser(value_parser!(u64)).default_value("15").long("keepalive-interval").help("Seconds between unanswered TCP keepalive probes"))
+        .arg(Arg::new("auth-rule").action(ArgAction::Append).long("auth-rule").help("Basic auth for a path prefix in the form of /prefix=username:password, may be repeated"))
+        .arg(Arg::new("accept-rate").value_parser(value_parser!(u32)).long("accept-rate").help("Accept at most this many connections a second, holding back bursts beyond it rather than refusing them"))
+        .arg(Arg::new("max-connections-per-ip").value_parser(value_parser!(usize)).default_value("0").long("max-connections-per-ip").help("Connections one peer address may have open before new ones get a 503, 0 for unlimited. Clients behind a proxy share the proxy's limit"))
+        .arg(Arg::new("max-connections").value_parser(value_parser!(usize)).default_value("0").long("max-connections").help("Connections queued or in flight before new ones get a 503, 0 for unlimited"))
+        .arg(Arg::new("cors-origin").long("cors-origin").help("Origin allowed to make cross-origin requests, * for any"))
+        .arg(Arg::new("cors-max-age").value_parser(value_parser!(u64)).long("cors-max-age").help("Seconds browsers may cache a CORS preflight response"))
+        .arg(Arg::new("no-listing").action(ArgAction::SetTrue).long("no-listing").help("Answer 403 for directories without an index file instead of listing them"))
+        .arg(Arg::new("reuse-port").action(ArgAction::SetTrue).long("reuse-port").help("Let several server instances share the port (SO_REUSEPORT)"))
+        .arg(Arg::new("no-compression").action(ArgAction::SetTrue).long("no-compression").help("Never compress responses, e.g. behind a compressing proxy"))
+        .arg(Arg::new("precompress").action(ArgAction::SetTrue).long("precompress").help("Gzip compressible files into memory at startup instead of on every request"))
+        .arg(Arg::new("precompress-budget").value_parser(value_parser!(usize)).default_value("67108864").long("precompress-budget").help("Bytes of gzipped files --precompress keeps in memory at most"))
+        .arg(Arg::new("min-compress-size").value_parser(value_parser!(usize)).default_value("0").long("min-compress-size").help("Send bodies smaller than this many bytes uncompressed"))
+        .arg(Arg::new("check").action(ArgAction::SetTrue).long("check").help("Validate the configuration, print a summary and exit without serving"))
+        .arg(Arg::new("listen-fd").value_parser(value_parser!(i32)).long("listen-fd").help("Serve on this already listening socket instead of binding, systemd socket activation is detected automatically"))
+        .arg(Arg::new("writable").action(ArgAction::Append).long("writable").help("Allow PUT and DELETE to change files under this path prefix, may be repeated"))
+        .arg(Arg::new("empty-as-no-content").action(ArgAction::SetTrue).long("empty-as-no-content").help("Answer empty files with 204 No Content"))
+        .arg(Arg::new("stdin").action(ArgAction::SetTrue).long("stdin").help("Read a single document from stdin and serve it at every path instead of the directory"))
+        .arg(Arg::new("stdin-type").long("stdin-type").default_value("application/octet-stream").help("Content type of the document read with --stdin"))
+        .arg(Arg::new("min-compression-gain").value_parser(value_parser!(u8)).default_value("0").long("min-compression-gain").help("Send bodies uncompressed unless compression saves at least this percentage of bytes"))
+        .arg(Arg::new("trace").action(ArgAction::SetTrue).long("trace").help("Answer TRACE requests by echoing them back"))
+        .arg(Arg::new("disable-method").action(ArgAction::Append).long("disable-method").help("Refuse this method with 405 Method Not Allowed, may be repeated"))
+        .arg(Arg::new("connection-deadline").value_parser(value_parser!(u64)).long("connection-deadline").help("Shut down connections still being served after this many seconds"))
+        .arg(Arg::new("url-prefix").long("url-prefix").help("Path the site is mounted at behind a reverse proxy, e.g. /app"))
+        .arg(Arg::new("default-mime").long("default-mime").default_value("application/octet-stream").help("Content type of files with an unknown extension, e.g. text/plain"))
+        .arg(Arg::new("shutdown-grace").value_parser(value_parser!(u64)).long("shutdown-grace").help("Stop waiting for busy workers this many seconds into shutdown"))
+        .arg(Arg::new("stack-size").value_parser(value_parser!(usize)).long("stack-size").help("Stack size in bytes of the threads serving connections"))
+        .arg(Arg::new("allow-dotfiles").action(ArgAction::SetTrue).long("allow-dotfiles").help("Serve and list files and directories whose names start with a dot"))
+        .arg(Arg::new("method-override").action(ArgAction::SetTrue).long("method-override").help("Take the method of POST requests from their X-HTTP-Method-Override header"))
+        .arg(Arg::new("case-insensitive").action(ArgAction::SetTrue).long("case-insensitive").help("Serve the file whose name only differs in case when the exact one doesn't exist"))
+        .arg(Arg::new("drain").action(ArgAction::SetTrue).long("drain").help("On SIGTERM stop accepting connections and exit once those in flight are done"))
+        .arg(Arg::new("async").action(ArgAction::SetTrue).long("async").help("Serve connections on an async runtime instead of the worker pool (needs the async feature)"))
+        .arg(Arg::new("level").default_value("Info").short('l').long("log-level").value_parser(PossibleValuesParser::new(["Trace", "Debug", "Info", "Warn", "Error"])))
+        .arg(Arg::new("verbose").action(ArgAction::Count).short('v').long("verbose").help("Log one level more than --log-level, may be repeated"))
+        .arg(Arg::new("quiet").action(ArgAction::Count).short('q').long("quiet").help("Log one level less than --log-level, may be repeated"))
         .get_matches();
 
     let port = *matches.get_one::<u16>("port").unwrap();
@@ -22,42 +82,362 @@ fn main() {
     let bind = matches.get_one::<String>("bind").unwrap().to_string();
     let directory = matches.get_one::<String>("directory").unwrap().to_string();
     let poolsize = *matches.get_one::<usize>("poolsize").unwrap();
-    let auth = match matches.get_one::<String>("auth") {
-        Some(auth_str) => {
-            let (username, password) = auth_str.split_once(':').expect("Invalid auth string");
-            Some(Auth {
-                username: username.to_string(),
-                password: password.to_string(),
-            })
+    let auth = matches
+        .get_one::<String>("auth")
+        .map(|auth_str| Auth::try_from(auth_str.as_str()).unwrap_or_else(|err| fail(&err)));
+    let level = log_level(
+        matches.get_one::<String>("level").unwrap(),
+        matches.get_count("verbose"),
+        matches.get_count("quiet"),
+    );
+    let lifo = matches.get_flag("lifo");
+    let queue_timeout = matches
+        .get_one::<u64>("queue-timeout")
+        .map(|secs| Duration::from_secs(*secs));
+    let trusted_proxies = matches
+        .get_many::<IpAddr>("trusted-proxy")
+        .map(|proxies| proxies.copied().collect())
+        .unwrap_or_default();
+    let verbose_errors = matches.get_flag("verbose-errors");
+    let hide_server = matches.get_flag("hide-server");
+    let max_headers = *matches.get_one::<usize>("max-headers").unwrap();
+    let max_decoded_body = *matches.get_one::<usize>("max-decoded-body").unwrap();
+    let max_requests_per_connection = *matches.get_one::<usize>("max-requests").unwrap();
+    let mut compression_policy = Opts::default().compression_policy;
+    for (arg, compress) in [("compress-type", true), ("skip-compress-type", false)] {
+        if let Some(mimes) = matches.get_many::<String>(arg) {
+            for mime in mimes {
+                compression_policy.insert(mime.to_ascii_lowercase(), compress);
+            }
         }
-        None => None,
-    };
-    let level = match matches.get_one::<String>("level").unwrap().as_str() {
-        "Debug" => log::Level::Debug,
-        "Info" => log::Level::Info,
-        "Warn" => log::Level::Warn,
-        _ => log::Level::Info,
+    }
+    let mime_overrides = matches
+        .get_many::<String>("mime")
+        .map(|mimes| {
+            mimes
+                .map(|mime| match mime.split_once('=') {
+                    Some((ext, mime_type)) => (
+                        ext.trim_start_matches('.').to_string(),
+                        mime_type.to_string(),
+                    ),
+                    None => fail(&format!("Invalid mime override {}", mime)),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let cache_control = matches
+        .get_many::<String>("cache-control")
+        .map(|rules| {
+            rules
+                .map(|rule| match rule.split_once('=') {
+                    Some((key, directive)) if key.contains('/') => {
+                        (key.to_ascii_lowercase(), directive.to_string())
+                    }
+                    Some((ext, directive)) => (
+                        ext.trim_start_matches('.').to_string(),
+                        directive.to_string(),
+                    ),
+                    None => fail(&format!("Invalid Cache-Control rule {}", rule)),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let early_hints = matches
+        .get_many::<String>("early-hint")
+        .map(|hints| {
+            hints
+                .map(|hint| match hint.split_once('=') {
+                    Some((prefix, link)) => (prefix.to_string(), link.to_string()),
+                    None => fail(&format!("Invalid early hint {}", hint)),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let charset = match matches.get_flag("no-charset") {
+        true => None,
+        false => matches.get_one::<String>("charset").cloned(),
     };
+    let strict_accept = matches.get_flag("strict-accept");
+    let write_buffer = *matches.get_one::<usize>("write-buffer").unwrap();
+    let recv_buffer = matches.get_one::<usize>("recv-buffer").copied();
+    let send_buffer = matches.get_one::<usize>("send-buffer").copied();
+    let tcp_keepalive = matches
+        .get_one::<u64>("keepalive-idle")
+        .map(|idle| TcpKeepalive {
+            idle: Duration::from_secs(*idle),
+            interval: Duration::from_secs(*matches.get_one::<u64>("keepalive-interval").unwrap()),
+        });
+    let auth_rules = matches
+        .get_many::<String>("auth-rule")
+        .map(|rules| {
+            rules
+                .map(|rule| {
+                    let Some((prefix, auth_str)) = rule.split_once('=') else {
+                        fail(&format!("Invalid auth rule {}", rule));
+                    };
+                    let auth = Auth::try_from(auth_str).unwrap_or_else(|err| fail(&err));
+                    (prefix.to_string(), auth)
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let max_connections = *matches.get_one::<usize>("max-connections").unwrap();
+    let accept_rate = matches.get_one::<u32>("accept-rate").copied();
+    let max_connections_per_ip = *matches.get_one::<usize>("max-connections-per-ip").unwrap();
+    let cors_origin = matches.get_one::<String>("cors-origin").cloned();
+    let cors_max_age = matches
+        .get_one::<u64>("cors-max-age")
+        .map(|secs| Duration::from_secs(*secs));
+    let directory_listing = !matches.get_flag("no-listing");
+    let vhosts = matches
+        .get_many::<String>("vhost")
+        .map(|vhosts| {
+            vhosts
+                .map(|vhost| match vhost.split_once('=') {
+                    Some((host, dir)) => (host.to_string(), dir.to_string()),
+                    None => fail(&format!("Invalid vhost {}, expected host=directory", vhost)),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let reuse_port = matches.get_flag("reuse-port");
+    let compression = !matches.get_flag("no-compression");
+    let precompress = matches.get_flag("precompress");
+    let precompress_budget = *matches.get_one::<usize>("precompress-budget").unwrap();
+    let min_compress_size = *matches.get_one::<usize>("min-compress-size").unwrap();
+    let listen_fd = matches
+        .get_one::<i32>("listen-fd")
+        .copied()
+        .or_else(socket_activation_fd);
+    let writable_paths = matches
+        .get_many::<String>("writable")
+        .map(|prefixes| prefixes.cloned().collect())
+        .unwrap_or_default();
+    let empty_as_no_content = matches.get_flag("empty-as-no-content");
+    let min_compression_gain = *matches.get_one::<u8>("min-compression-gain").unwrap();
+    let trace = matches.get_flag("trace");
+    let disabled_methods = matches
+        .get_many::<String>("disable-method")
+        .map(|methods| {
+            methods
+                .map(|method| {
+                    Method::try_from(method.as_str())
+                        .unwrap_or_else(|_| fail(&format!("Invalid method {:?}", method)))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let connection_deadline = matches
+        .get_one::<u64>("connection-deadline")
+        .map(|secs| Duration::from_secs(*secs));
+    let url_prefix = matches.get_one::<String>("url-prefix").cloned();
+    let default_mime = matches
+        .get_one::<String>("default-mime")
+        .unwrap()
+        .to_string();
+    let shutdown_grace = matches
+        .get_one::<u64>("shutdown-grace")
+        .map(|secs| Duration::from_secs(*secs));
+    let worker_stack_size = matches.get_one::<usize>("stack-size").copied();
+    let case_insensitive = matches.get_flag("case-insensitive");
+    let method_override = matches.get_flag("method-override");
+    let deny_dotfiles = !matches.get_flag("allow-dotfiles");
     let ratio = *matches.get_one::<u32>("ratio").unwrap();
-    if ratio > 9 {
-        panic!("Compression ratio must be between 0-9");
-    }
     let args = Opts {
         port,
         bind,
         protocol,
         directory,
+        vhosts,
+        directory_listing,
+        case_insensitive,
+        method_override,
+        deny_dotfiles,
         auth,
+        auth_rules,
         ratio,
+        lifo,
+        queue_timeout,
+        connection_deadline,
+        shutdown_grace,
+        worker_stack_size,
+        max_connections,
+        max_connections_per_ip,
+        accept_rate,
+        trusted_proxies,
+        verbose_errors,
+        hide_server,
+        max_headers,
+        max_decoded_body,
+        max_requests_per_connection,
+        compression_policy,
+        compression,
+        precompress,
+        precompress_budget,
+        min_compress_size,
+        min_compression_gain,
+        mime_overrides,
+        cache_control,
+        early_hints,
+        charset,
+        strict_accept,
+        tcp_keepalive,
+        write_buffer,
+        recv_buffer,
+        send_buffer,
+        reuse_port,
+        listen_fd,
+        writable_paths,
+        empty_as_no_content,
+        trace,
+        disabled_methods,
+        default_mime,
+        url_prefix,
+        cors_origin,
+        cors_max_age,
     };
 
+    if let Err(err) = args.validate() {
+        fail(&err);
+    }
+    if matches.get_flag("check") {
+        println!("Configuration OK");
+        println!(
+            "  listen:    {}:{} ({})",
+            args.bind, args.port, args.protocol
+        );
+        println!("  directory: {}", args.directory);
+        println!("  pool size: {}", poolsize);
+        println!(
+            "  auth:      {}{}",
+            if args.auth.is_some() { "on" } else { "off" },
+            match args.auth_rules.len() {
+                0 => String::new(),
+                n => format!(", {} prefix rule(s)", n),
+            }
+        );
+        return;
+    }
+
     // Initialize a new logger
     simple_logger::init_with_level(level).unwrap();
     log::info!("Logging started...");
 
-    //let http_server = HTTPServer::new(HTTPServerClass::Simple, args, None);
-    //let http_server = HTTPServer::new(HTTPServerClass::Threaded, args, None);
-    let http_server = HTTPServer::new(HTTPServerClass::ThreadPooled(poolsize), args, None);
+    //let http_server = HTTPServer::new(HTTPServerClass::Simple, args, Arc::new(()), None);
+    //let http_server = HTTPServer::new(HTTPServerClass::Threaded, args, Arc::new(()), None);
+    let http_server = HTTPServer::new(
+        HTTPServerClass::ThreadPooled(poolsize),
+        args,
+        Arc::new(()),
+        None,
+    );
+    let http_server = if matches.get_flag("stdin") {
+        let mut body = Vec::new();
+        if let Err(err) = std::io::stdin().read_to_end(&mut body) {
+            fail(&format!("Unable to read stdin: {}", err));
+        }
+        log::info!("Serving {} bytes read from stdin", body.len());
+        http_server.with_body(body, matches.get_one::<String>("stdin-type").unwrap())
+    } else {
+        http_server
+    };
+
+    let http_server = if matches.get_flag("drain") {
+        http_server.with_drain_flag(drain_on_sigterm())
+    } else {
+        http_server
+    };
+
+    let stats = http_server.stats();
+    if matches.get_flag("async") {
+        #[cfg(feature = "async")]
+        http_server.serve_async();
+        #[cfg(not(feature = "async"))]
+        fail("--async needs the server built with the async feature");
+    } else {
+        http_server.serve_forever();
+    }
+    log::info!(
+        "Served {} requests, {} bytes",
+        stats.requests(),
+        stats.bytes_written()
+    );
+}
+
+// Raised by SIGTERM, the handler only stores to it
+static DRAIN: OnceLock<Arc<AtomicBool>> = OnceLock::new();
 
-    http_server.serve_forever();
+#[cfg(unix)]
+fn drain_on_sigterm() -> Arc<AtomicBool> {
+    const SIGTERM: i32 = 15;
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    }
+    extern "C" fn raise_drain(_: i32) {
+        if let Some(flag) = DRAIN.get() {
+            flag.store(true, Ordering::SeqCst);
+        }
+    }
+    let flag = Arc::clone(DRAIN.get_or_init(|| Arc::new(AtomicBool::new(false))));
+    // Safety: the handler only does an atomic load and store
+    unsafe {
+        signal(SIGTERM, raise_drain);
+    }
+    flag
+}
+
+#[cfg(not(unix))]
+fn drain_on_sigterm() -> Arc<AtomicBool> {
+    log::warn!("Ignoring --drain, SIGTERM handling needs unix");
+    Arc::clone(DRAIN.get_or_init(|| Arc::new(AtomicBool::new(false))))
+}
+
+// Steps the named level up for every -v and down for every -q, staying within
+// Error and Trace
+fn log_level(name: &str, verbose: u8, quiet: u8) -> log::Level {
+    let levels = [
+        log::Level::Error,
+        log::Level::Warn,
+        log::Level::Info,
+        log::Level::Debug,
+        log::Level::Trace,
+    ];
+    let named = match name {
+        "Trace" => 4,
+        "Debug" => 3,
+        "Warn" => 1,
+        "Error" => 0,
+        _ => 2,
+    };
+    let stepped = (named + verbose as usize).saturating_sub(quiet as usize);
+    levels[stepped.min(levels.len() - 1)]
+}
+
+// Reports a configuration error and exits without serving
+fn fail(err: &str) -> ! {
+    eprintln!("Invalid configuration: {}", err);
+    std::process::exit(1);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_maps_log_levels() {
+        assert_eq!(log_level("Trace", 0, 0), log::Level::Trace);
+        assert_eq!(log_level("Debug", 0, 0), log::Level::Debug);
+        assert_eq!(log_level("Info", 0, 0), log::Level::Info);
+        assert_eq!(log_level("Warn", 0, 0), log::Level::Warn);
+        assert_eq!(log_level("Error", 0, 0), log::Level::Error);
+    }
+
+    #[test]
+    fn test_steps_log_level() {
+        assert_eq!(log_level("Info", 1, 0), log::Level::Debug);
+        assert_eq!(log_level("Info", 5, 0), log::Level::Trace);
+        assert_eq!(log_level("Info", 0, 1), log::Level::Warn);
+        assert_eq!(log_level("Info", 0, 5), log::Level::Error);
+        assert_eq!(log_level("Warn", 2, 1), log::Level::Info);
+    }
 }
@@ -0,0 +1,214 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use crate::http10::headers::HeaderVariant;
+use crate::http10::methods::Method;
+use crate::http10::request::HTTPRequest;
+use crate::http10::response::HTTPResponse;
+use crate::http10::result_codes::ResultCode;
+
+// Request fields the server's own responses vary on, every entry is keyed by them
+const KEYED: [&str; 2] = ["Accept-Encoding", "Origin"];
+
+/// Disk-backed cache of handler responses. Only GET and HEAD requests under one of
+/// the cached routes are looked up, and entries expire once older than the TTL.
+/// Requests with credentials or under basic auth bypass it altogether, and responses
+/// meant for one client (Set-Cookie, private, no-store) are never stored.
+#[derive(Debug)]
+pub struct ResponseCache {
+    dir: PathBuf,
+    ttl: Duration,
+    routes: Vec<String>,
+}
+
+impl ResponseCache {
+    /// Cache storing its entries in dir, created when missing
+    pub fn new(dir: impl Into<PathBuf>, ttl: Duration) -> ResponseCache {
+        ResponseCache {
+            dir: dir.into(),
+            ttl,
+            routes: Vec::new(),
+        }
+    }
+
+    /// Caches responses for paths under this prefix, matching whole segments
+    pub fn route(mut self, prefix: &str) -> ResponseCache {
+        self.routes.push(prefix.to_string());
+        self
+    }
+
+    // Where the response to this request is kept, None if it isn't cached
+    pub(crate) fn entry(&self, req: &HTTPRequest) -> Option<PathBuf> {
+        if req.method != Method::GET && req.method != Method::HEAD {
+            return None;
+        }
+        let path = req.uri.split(['?', '#']).next().unwrap_or("");
+        let cached = self.routes.iter().any(|prefix| {
+            match path.strip_prefix(prefix.trim_end_matches('/')) {
+                Some(rest) => rest.is_empty() || rest.starts_with('/'),
+                None => false,
+            }
+        });
+        if !cached {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        Into::<String>::into(req.method.clone()).hash(&mut hasher);
+        req.uri.hash(&mut hasher);
+        // Virtual hosts answer the same path differently
+        req.headers
+            .get(HeaderVariant::Host)
+            .map(|host| host.to_string())
+            .hash(&mut hasher);
+        // A gzipped body mustn't be replayed to a client that can't read it
+        req.headers
+            .get(HeaderVariant::AcceptEncoding)
+            .map(|accepted| accepted.to_string())
+            .hash(&mut hasher);
+        req.headers.get_generic("Origin").hash(&mut hasher);
+        Some(self.dir.join(format!("{:016x}", hasher.finish())))
+    }
+
+    // The stored response, as long as it's younger than the TTL
+    pub(crate) fn get(&self, entry: &PathBuf) -> Option<HTTPResponse> {
+        let modified = fs::metadata(entry).and_then(|meta| meta.modified()).ok()?;
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+        if age >= self.ttl {
+            return None;
+        }
+        match HTTPResponse::try_from(fs::read(entry).ok()?.as_slice()) {
+            Ok(resp) => Some(resp),
+            Err(_) => {
+                log::warn!("Ignoring unreadable cache entry {}", entry.display());
+                None
+            }
+        }
+    }
+
+    // Stores successful responses, failing to do so only costs a later miss
+    pub(crate) fn put(&self, entry: &PathBuf, resp: &HTTPResponse) {
+        if resp.status != ResultCode::OK || resp.trailers.is_some() || !shareable(resp) {
+            return;
+        }
+        let bytes = resp.clone().as_bytes();
+        if let Err(err) = fs::create_dir_all(&self.dir).and_then(|_| fs::write(entry, bytes)) {
+            log::warn!("Unable to cache response in {}: {}", entry.display(), err);
+        }
+    }
+}
+
+// Whether a response may go to other clients: nothing in it is theirs alone, and it
+// varies on nothing the entry's key leaves out
+fn shareable(resp: &HTTPResponse) -> bool {
+    if resp.headers.get_generic("Set-Cookie").is_some() {
+        return false;
+    }
+    let control = resp
+        .headers
+        .get_generic("Cache-Control")
+        .unwrap_or_default();
+    let personal = control.split(',').any(|directive| {
+        let name = directive.split('=').next().unwrap_or("").trim();
+        name.eq_ignore_ascii_case("private") || name.eq_ignore_ascii_case("no-store")
+    });
+    let vary = resp.headers.get_generic("Vary").unwrap_or_default();
+    let keyed = vary
+        .split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .all(|field| KEYED.iter().any(|key| key.eq_ignore_ascii_case(field)));
+    !personal && keyed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http10::content_codings::ContentEncoding;
+    use crate::http10::headers::{Header, Headers};
+    use crate::test_util::test_dir;
+
+    fn request(method: Method, uri: &str) -> HTTPRequest {
+        HTTPRequest {
+            method,
+            uri: uri.to_string(),
+            version: "HTTP/1.0".to_string(),
+            headers: Headers::new(),
+            body: None,
+            trailers: None,
+            peer: None,
+        }
+    }
+
+    #[test]
+    fn test_only_caches_reads_under_routes() {
+        let cache = ResponseCache::new("/tmp/cache", Duration::from_secs(60)).route("/api/");
+        assert!(cache.entry(&request(Method::GET, "/api/report")).is_some());
+        assert!(cache.entry(&request(Method::HEAD, "/api")).is_some());
+        assert!(cache.entry(&request(Method::POST, "/api/report")).is_none());
+        assert!(cache.entry(&request(Method::GET, "/apix")).is_none());
+        assert_ne!(
+            cache.entry(&request(Method::GET, "/api/report?a=1")),
+            cache.entry(&request(Method::GET, "/api/report?a=2"))
+        );
+    }
+
+    #[test]
+    fn test_entries_expire() {
+        let dir = test_dir("cache", &[]);
+        let fresh = ResponseCache::new(dir.to_path_buf(), Duration::from_secs(60)).route("/");
+        let expired = ResponseCache::new(dir.to_path_buf(), Duration::ZERO).route("/");
+        let entry = fresh.entry(&request(Method::GET, "/report")).unwrap();
+        let mut headers = Headers::new();
+        headers.set(Header::ContentLength(2));
+        let resp = HTTPResponse::new("HTTP/1.0", ResultCode::OK, headers, Some(b"hi".to_vec()));
+        fresh.put(&entry, &resp);
+        assert_eq!(fresh.get(&entry).unwrap().body, Some(b"hi".to_vec()));
+        assert!(expired.get(&entry).is_none());
+    }
+
+    #[test]
+    fn test_personal_responses_are_not_stored() {
+        let dir = test_dir("cache-personal", &[]);
+        let cache = ResponseCache::new(dir.to_path_buf(), Duration::from_secs(60)).route("/");
+        let entry = cache.entry(&request(Method::GET, "/report")).unwrap();
+        for (field, value) in [
+            ("Set-Cookie", "session=1"),
+            ("Cache-Control", "max-age=60, private"),
+            ("Cache-Control", "no-store"),
+            ("Vary", "User-Agent"),
+        ] {
+            let mut headers = Headers::new();
+            headers.set(Header::Generic((field.to_string(), value.to_string())));
+            let resp = HTTPResponse::new("HTTP/1.0", ResultCode::OK, headers, None);
+            cache.put(&entry, &resp);
+            assert!(cache.get(&entry).is_none(), "{}: {}", field, value);
+        }
+        let mut headers = Headers::new();
+        headers.set(Header::Generic((
+            "Vary".to_string(),
+            "Accept-Encoding, Origin".to_string(),
+        )));
+        cache.put(
+            &entry,
+            &HTTPResponse::new("HTTP/1.0", ResultCode::OK, headers, None),
+        );
+        assert!(cache.get(&entry).is_some());
+    }
+
+    #[test]
+    fn test_entries_depend_on_accepted_encoding() {
+        let cache = ResponseCache::new("/tmp/cache", Duration::from_secs(60)).route("/");
+        let mut gzip = request(Method::GET, "/report");
+        gzip.headers
+            .set(Header::AcceptEncoding(vec![ContentEncoding::GZIP.into()]));
+        assert_ne!(
+            cache.entry(&gzip),
+            cache.entry(&request(Method::GET, "/report"))
+        );
+    }
+}
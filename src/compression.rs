@@ -0,0 +1,122 @@
+use std::io::{self, Read, Write};
+
+use flate2::read::{DeflateDecoder, GzDecoder};
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Compression;
+
+pub use crate::http10::content_codings::ContentEncoding;
+
+// Compresses a body with the given content coding, used for static files and
+// available to custom handlers for dynamic bodies. Brotli is only ever served
+// precompressed, other codings without a compressor leave the data untouched.
+pub fn compress_bytes(data: &[u8], encoding: &ContentEncoding, ratio: u32) -> io::Result<Vec<u8>> {
+    match encoding {
+        ContentEncoding::GZIP => {
+            let mut enc = GzEncoder::new(Vec::new(), Compression::new(ratio));
+            enc.write_all(data)?;
+            enc.finish()
+        }
+        ContentEncoding::DEFLATE => {
+            let mut enc = DeflateEncoder::new(Vec::new(), Compression::new(ratio));
+            enc.write_all(data)?;
+            enc.finish()
+        }
+        ContentEncoding::BR => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "No brotli compressor available",
+        )),
+        _ => Ok(data.to_vec()),
+    }
+}
+
+// Undoes a content coding applied by the client to a request body. Decoding stops
+// once the output passes limit bytes, a few kilobytes of gzip can expand to gigabytes.
+pub fn decompress_bytes(
+    data: &[u8],
+    encoding: &ContentEncoding,
+    limit: usize,
+) -> io::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let max = limit as u64 + 1;
+    match encoding {
+        ContentEncoding::GZIP => GzDecoder::new(data).take(max).read_to_end(&mut decoded)?,
+        ContentEncoding::DEFLATE => DeflateDecoder::new(data)
+            .take(max)
+            .read_to_end(&mut decoded)?,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("No decompressor for {}", encoding),
+            ))
+        }
+    };
+    if decoded.len() > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::FileTooLarge,
+            format!("Decoded body exceeds {} bytes", limit),
+        ));
+    }
+    Ok(decoded)
+}
+
+// Whether compress_bytes can produce the coding at runtime
+pub fn supports(encoding: &ContentEncoding) -> bool {
+    matches!(encoding, ContentEncoding::GZIP | ContentEncoding::DEFLATE)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use flate2::read::{DeflateDecoder, GzDecoder};
+
+    use super::*;
+
+    const BODY: &[u8] = b"{\"message\": \"hello hello hello hello\"}";
+
+    #[test]
+    fn test_gzip_round_trip() {
+        let compressed = compress_bytes(BODY, &ContentEncoding::GZIP, 6).unwrap();
+        let mut decoded = Vec::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, BODY);
+    }
+
+    #[test]
+    fn test_decompresses_request_codings() {
+        for encoding in [ContentEncoding::GZIP, ContentEncoding::DEFLATE] {
+            let compressed = compress_bytes(BODY, &encoding, 6).unwrap();
+            assert_eq!(
+                decompress_bytes(&compressed, &encoding, 1024).unwrap(),
+                BODY
+            );
+        }
+        let err = decompress_bytes(BODY, &ContentEncoding::BR, 1024).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_decompression_stops_at_limit() {
+        let bomb = compress_bytes(&vec![0; 1 << 20], &ContentEncoding::GZIP, 9).unwrap();
+        let err = decompress_bytes(&bomb, &ContentEncoding::GZIP, 4096).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+        assert_eq!(
+            decompress_bytes(&bomb, &ContentEncoding::GZIP, 1 << 20)
+                .unwrap()
+                .len(),
+            1 << 20
+        );
+    }
+
+    #[test]
+    fn test_deflate_round_trip() {
+        let compressed = compress_bytes(BODY, &ContentEncoding::DEFLATE, 6).unwrap();
+        let mut decoded = Vec::new();
+        DeflateDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, BODY);
+    }
+}
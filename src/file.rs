@@ -1,24 +1,37 @@
-use core::str;
+use std::collections::HashMap;
 use std::ffi::OsStr;
-use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::{fs, io};
 
 use chrono::{DateTime, FixedOffset, Utc};
-use flate2::write::{DeflateEncoder, GzEncoder};
-use flate2::Compression;
 
 use crate::http10::content_codings::ContentEncoding;
 use crate::http10::content_types::get_mime;
+use crate::Opts;
 
-const TRYFILES: [&'static str; 2] = ["/index.html", "/index.htm"];
+const TRYFILES: [&str; 2] = ["/index.html", "/index.htm"];
+
+// A gzipped copy of a file and the modification time of the content it was made from
+type Gzipped = (DateTime<Utc>, Vec<u8>);
 
 #[derive(Debug)]
 pub enum FileError {
+    NotFound,
+    PermissionDenied,
     ReadError(io::Error),
     IsADirectory,
 }
 
+impl From<io::Error> for FileError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => FileError::NotFound,
+            io::ErrorKind::PermissionDenied => FileError::PermissionDenied,
+            _ => FileError::ReadError(err),
+        }
+    }
+}
+
 #[derive(Debug)]
 #[allow(dead_code)]
 pub struct File {
@@ -32,24 +45,28 @@ pub struct File {
 
 impl std::fmt::Display for File {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(str::from_utf8(&self.content).map_err(|_| std::fmt::Error)?)
+        // Binary content is shown with replacement characters rather than failing
+        f.write_str(&String::from_utf8_lossy(&self.content))
     }
 }
 
 impl File {
-    pub fn try_load(uri: &str, base_dir: &str) -> Result<Self, FileError> {
-        let path = Path::new(base_dir).join(&uri[1..]);
-        if let Ok(exists) = path.try_exists() {
-            if !exists {
-                return Err(FileError::ReadError(io::ErrorKind::NotFound.into()));
+    pub fn try_load(uri: &str, opts: &Opts) -> Result<Self, FileError> {
+        // Hidden whether or not they exist
+        if opts.deny_dotfiles && Self::is_dotfile(uri) {
+            return Err(FileError::NotFound);
+        }
+        let mut path = Path::new(&opts.directory).join(&uri[1..]);
+        if !path.try_exists()? {
+            match Self::case_insensitive_match(&path, opts) {
+                Some(found) => path = found,
+                None => return Err(FileError::NotFound),
             }
-        } else {
-            return Err(FileError::ReadError(io::ErrorKind::NotFound.into()));
         }
         if path.is_dir() {
             let try_files: Vec<Result<Self, FileError>> = TRYFILES
                 .iter()
-                .map(|file| Self::try_load(Path::new(&uri).join(file).to_str().unwrap(), base_dir))
+                .map(|file| Self::try_load(Path::new(&uri).join(file).to_str().unwrap(), opts))
                 .collect();
             if let Some(file) = try_files.into_iter().find_map(Result::ok) {
                 return Ok(file);
@@ -61,28 +78,69 @@ impl File {
             .extension()
             .and_then(OsStr::to_str)
             .map(|ext| ext.to_string());
-        let mime_type = get_mime(extension.clone().unwrap_or("".to_string())).to_string();
-        let content: Result<Vec<u8>, std::io::Error> = fs::read(&path);
-
-        if content.is_ok() {
-            let size = content.as_ref().unwrap().len();
-            Ok(File {
-                path: path.to_str().unwrap().to_string(),
-                extension,
-                content: content.unwrap(),
-                mime_type,
-                size,
-                modified: fs::metadata(path).unwrap().modified().unwrap().into(),
-            })
-        } else {
-            Err(FileError::ReadError(content.unwrap_err()))
+        // Configured overrides win over the built in table
+        let mime_type = match extension
+            .as_ref()
+            .and_then(|ext| opts.mime_overrides.get(ext))
+        {
+            Some(mime) => mime.clone(),
+            None => get_mime(extension.as_deref().unwrap_or(""))
+                .map_or_else(|| opts.default_mime.clone(), str::to_string),
+        };
+        let content = fs::read(&path)?;
+        let modified = fs::metadata(&path)?.modified()?.into();
+
+        Ok(File {
+            path: path.to_str().unwrap().to_string(),
+            extension,
+            size: content.len(),
+            content,
+            mime_type,
+            modified,
+        })
+    }
+
+    // Whether any component of the path is hidden, e.g. `.git/config`
+    pub fn is_dotfile(path: &str) -> bool {
+        path.split(['/', '\\'])
+            .any(|segment| segment.starts_with('.'))
+    }
+
+    // A directory with none of the index files, which only a listing could show
+    pub fn is_unindexed_dir(uri: &str, opts: &Opts) -> bool {
+        let path = Path::new(&opts.directory).join(&uri[1..]);
+        path.is_dir() && TRYFILES.iter().all(|file| !path.join(&file[1..]).is_file())
+    }
+
+    // The only entry of the parent directory whose name matches the path's but for
+    // case, if case_insensitive is set. Several case variants are as good as none.
+    fn case_insensitive_match(path: &Path, opts: &Opts) -> Option<PathBuf> {
+        if !opts.case_insensitive {
+            return None;
         }
+        let name = path.file_name()?.to_str()?.to_lowercase();
+        let mut matches = fs::read_dir(path.parent()?)
+            .ok()?
+            .filter_map(Result::ok)
+            .filter(|entry| {
+                entry.file_name().to_str().map(str::to_lowercase) == Some(name.clone())
+            });
+        let found = matches.next()?;
+        if matches.next().is_some() {
+            log::debug!("{} matches several files but for case", path.display());
+            return None;
+        }
+        Some(found.path())
     }
 
     pub fn get_content(&self) -> Vec<u8> {
         self.content.clone()
     }
 
+    pub fn get_extension(&self) -> Option<&str> {
+        self.extension.as_deref()
+    }
+
     pub fn get_mime(&self) -> String {
         self.mime_type.to_string()
     }
@@ -105,34 +163,274 @@ impl File {
             .collect())
     }
 
-    pub fn compress(self, compression: &ContentEncoding, ratio: u32) -> io::Result<Self> {
-        log::debug!("Encoding {} as {}", self.path, compression);
-        match compression {
-            ContentEncoding::GZIP => {
-                let mut enc = GzEncoder::new(Vec::new(), Compression::new(ratio));
-                enc.write_all(&self.content)?;
+    // When entries were last added to or removed from a directory
+    pub fn listing_modified(uri: &str, base_dir: &str) -> io::Result<DateTime<FixedOffset>> {
+        let path = Path::new(base_dir).join(&uri[1..]);
+        let modified: DateTime<Utc> = fs::metadata(path)?.modified()?.into();
+        Ok(modified.into())
+    }
+
+    // Stores a file at the URI, returning whether it was newly created. Writes never
+    // leave the served directory or replace a directory.
+    pub fn store(uri: &str, content: &[u8], opts: &Opts) -> Result<bool, FileError> {
+        let path = Self::writable_path(uri, opts)?;
+        let created = !path.try_exists()?;
+        fs::write(&path, content)?;
+        Ok(created)
+    }
+
+    pub fn remove(uri: &str, opts: &Opts) -> Result<(), FileError> {
+        let path = Self::writable_path(uri, opts)?;
+        Ok(fs::remove_file(&path)?)
+    }
 
-                let comp = enc.finish()?;
+    fn writable_path(uri: &str, opts: &Opts) -> Result<PathBuf, FileError> {
+        if uri.split('/').any(|segment| segment == "..") {
+            return Err(FileError::PermissionDenied);
+        }
+        if opts.deny_dotfiles && Self::is_dotfile(uri) {
+            return Err(FileError::NotFound);
+        }
+        let path = Path::new(&opts.directory).join(&uri[1..]);
+        if path.is_dir() {
+            return Err(FileError::IsADirectory);
+        }
+        Ok(path)
+    }
 
-                Ok(File {
-                    size: comp.len(),
-                    content: comp,
-                    ..self
-                })
+    // Looks for a copy of this file compressed ahead of time (`<path>.br`, `<path>.gz`),
+    // trying the codings in order of preference. Siblings older than the file are stale
+    // and skipped. The MIME type stays that of the original file.
+    pub fn precompressed(&self, encodings: &[ContentEncoding]) -> Option<(Self, ContentEncoding)> {
+        for encoding in encodings {
+            let ext = match encoding {
+                ContentEncoding::BR => "br",
+                ContentEncoding::GZIP => "gz",
+                _ => continue,
+            };
+            let path = format!("{}.{}", self.path, ext);
+            let Ok(metadata) = fs::metadata(&path) else {
+                continue;
+            };
+            let fresh = match metadata.modified() {
+                Ok(modified) => DateTime::<Utc>::from(modified) >= self.modified,
+                Err(_) => false,
+            };
+            if !metadata.is_file() || !fresh {
+                continue;
+            }
+            match fs::read(&path) {
+                Ok(content) => {
+                    log::debug!("Serving {} for {}", path, self.path);
+                    let file = File {
+                        path,
+                        extension: self.extension.clone(),
+                        mime_type: self.mime_type.clone(),
+                        size: content.len(),
+                        content,
+                        modified: self.modified,
+                    };
+                    return Some((file, encoding.clone()));
+                }
+                Err(err) => log::warn!("Unable to read {}: {}", path, err),
             }
-            ContentEncoding::DEFLATE => {
-                let mut enc = DeflateEncoder::new(Vec::new(), Compression::new(ratio));
-                enc.write_all(&self.content)?;
+        }
+        None
+    }
+}
 
-                let comp = enc.finish()?;
+// Gzipped copies of files kept in memory by path, filled before the server starts
+// and only read from after. Copies that would take it past budget bytes aren't kept.
+#[derive(Debug)]
+pub struct GzipStore {
+    files: HashMap<String, Gzipped>,
+    budget: usize,
+    used: usize,
+}
 
-                Ok(File {
-                    size: comp.len(),
-                    content: comp,
-                    ..self
-                })
-            }
-            _ => Ok(self),
+impl GzipStore {
+    pub fn new(budget: usize) -> GzipStore {
+        GzipStore {
+            files: HashMap::new(),
+            budget,
+            used: 0,
+        }
+    }
+
+    // Keeps a gzipped copy of the file, returning whether it fit in the budget
+    pub fn keep(&mut self, file: &File, content: Vec<u8>) -> bool {
+        let replaced = self.files.get(&file.path).map_or(0, |(_, old)| old.len());
+        let used = self.used - replaced + content.len();
+        if used > self.budget {
+            return false;
+        }
+        self.used = used;
+        self.files
+            .insert(file.path.clone(), (file.modified, content));
+        true
+    }
+
+    // The gzipped copy of the file, unless the file changed since it was made
+    pub fn get(&self, file: &File) -> Option<File> {
+        let (modified, content) = self.files.get(&file.path)?;
+        if *modified != file.modified {
+            return None;
+        }
+        Some(File {
+            path: file.path.clone(),
+            extension: file.extension.clone(),
+            mime_type: file.mime_type.clone(),
+            size: content.len(),
+            content: content.clone(),
+            modified: file.modified,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{test_dir, TempDir};
+
+    #[test]
+    fn test_classifies_not_found() {
+        let err: FileError = io::Error::from(io::ErrorKind::NotFound).into();
+        assert!(matches!(err, FileError::NotFound));
+    }
+
+    #[test]
+    fn test_classifies_permission_denied() {
+        let err: FileError = io::Error::from(io::ErrorKind::PermissionDenied).into();
+        assert!(matches!(err, FileError::PermissionDenied));
+    }
+
+    #[test]
+    fn test_classifies_other_errors() {
+        let err: FileError = io::Error::from(io::ErrorKind::InvalidData).into();
+        assert!(
+            matches!(err, FileError::ReadError(inner) if inner.kind() == io::ErrorKind::InvalidData)
+        );
+    }
+
+    fn dir_opts(dir: &Path) -> Opts {
+        Opts {
+            directory: dir.to_str().unwrap().to_string(),
+            ..Opts::default()
+        }
+    }
+
+    #[test]
+    fn test_missing_file_is_not_found() {
+        let dir = test_dir("missing-file", &[]);
+        let err = File::try_load("/missing-file", &dir_opts(&dir));
+        assert!(matches!(err, Err(FileError::NotFound)));
+    }
+
+    #[test]
+    fn test_displays_binary_file() {
+        let dir = test_dir("binary", &[("image.bin", &[0x89, b'P', b'N', b'G', 0xff])]);
+        let file = File::try_load("/image.bin", &dir_opts(&dir)).unwrap();
+        assert_eq!(file.to_string(), "\u{fffd}PNG\u{fffd}");
+    }
+
+    #[test]
+    fn test_mime_override() {
+        let dir = test_dir("mime-override", &[("app.map", b"{}"), ("app.css", b"a {}")]);
+        let mut opts = dir_opts(&dir);
+        opts.mime_overrides
+            .insert("map".to_string(), "application/json".to_string());
+        let file = File::try_load("/app.map", &opts).unwrap();
+        assert_eq!(file.get_mime(), "application/json");
+        let file = File::try_load("/app.css", &opts).unwrap();
+        assert_eq!(file.get_mime(), "text/css");
+    }
+
+    #[test]
+    fn test_default_mime_for_unknown_extension() {
+        let dir = test_dir("default-mime", &[("server.weird", b"log line")]);
+        let file = File::try_load("/server.weird", &dir_opts(&dir)).unwrap();
+        assert_eq!(file.get_mime(), "application/octet-stream");
+        let opts = Opts {
+            default_mime: "text/plain".to_string(),
+            ..dir_opts(&dir)
+        };
+        let file = File::try_load("/server.weird", &opts).unwrap();
+        assert_eq!(file.get_mime(), "text/plain");
+    }
+
+    #[test]
+    fn test_dotfiles_are_not_found() {
+        let dir = test_dir(
+            "dotfiles",
+            &[
+                (".env", b"SECRET=1"),
+                ("normal.txt", b"normal"),
+                (".git/config", b"[core]"),
+            ],
+        );
+        let opts = dir_opts(&dir);
+        assert!(File::try_load("/normal.txt", &opts).is_ok());
+        for uri in ["/.env", "/.git/config", "/.missing"] {
+            assert!(matches!(
+                File::try_load(uri, &opts),
+                Err(FileError::NotFound)
+            ));
         }
+        let opts = Opts {
+            deny_dotfiles: false,
+            ..opts
+        };
+        assert_eq!(
+            File::try_load("/.env", &opts).unwrap().to_string(),
+            "SECRET=1"
+        );
+    }
+
+    // Each file holds its own name, which tells apart the one that was matched
+    fn case_dir(name: &str, files: &[&str]) -> (TempDir, Opts) {
+        let files: Vec<_> = files.iter().map(|file| (*file, file.as_bytes())).collect();
+        let dir = test_dir(name, &files);
+        let opts = Opts {
+            case_insensitive: true,
+            ..dir_opts(&dir)
+        };
+        (dir, opts)
+    }
+
+    #[test]
+    fn test_case_insensitive_match() {
+        let (_dir, opts) = case_dir("case-single", &["index.html"]);
+        let file = File::try_load("/INDEX.HTML", &opts).unwrap();
+        assert_eq!(file.to_string(), "index.html");
+
+        let opts = Opts {
+            case_insensitive: false,
+            ..opts
+        };
+        let err = File::try_load("/INDEX.HTML", &opts);
+        assert!(matches!(err, Err(FileError::NotFound)));
+    }
+
+    #[test]
+    fn test_ambiguous_case_is_not_found() {
+        let (_dir, opts) = case_dir("case-ambiguous", &["Notes.txt", "NOTES.txt"]);
+        let err = File::try_load("/notes.txt", &opts);
+        assert!(matches!(err, Err(FileError::NotFound)));
+        let file = File::try_load("/Notes.txt", &opts).unwrap();
+        assert_eq!(file.to_string(), "Notes.txt");
+    }
+
+    #[test]
+    fn test_gzip_store_stays_within_budget() {
+        let opts = Opts::default();
+        let manifest = File::try_load("/Cargo.toml", &opts).unwrap();
+        let lib = File::try_load("/src/lib.rs", &opts).unwrap();
+        let mut store = GzipStore::new(10);
+        assert!(store.keep(&manifest, vec![1; 8]));
+        assert!(!store.keep(&lib, vec![2; 8]));
+        assert!(store.get(&lib).is_none());
+        // Replacing a copy only counts the difference
+        assert!(store.keep(&manifest, vec![3; 10]));
+        assert_eq!(store.get(&manifest).unwrap().get_content(), vec![3; 10]);
     }
 }
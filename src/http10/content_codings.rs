@@ -5,6 +5,11 @@ pub struct InvalidContentEncodingErr;
 pub enum ContentEncoding {
     GZIP,
     DEFLATE,
+    BR,
+    // No coding at all
+    IDENTITY,
+    // `*` in Accept-Encoding, any coding not listed otherwise
+    ANY,
     TOKEN,
 }
 
@@ -14,6 +19,9 @@ impl TryFrom<String> for ContentEncoding {
         match value.as_str() {
             "gzip" | "x-gzip" => Ok(Self::GZIP),
             "deflate" => Ok(Self::DEFLATE),
+            "br" => Ok(Self::BR),
+            "identity" => Ok(Self::IDENTITY),
+            "*" => Ok(Self::ANY),
             "token" => Ok(Self::TOKEN),
             _ => Err(InvalidContentEncodingErr),
         }
@@ -26,6 +34,9 @@ impl TryFrom<&str> for ContentEncoding {
         match value {
             "gzip" | "x-gzip" => Ok(Self::GZIP),
             "deflate" => Ok(Self::DEFLATE),
+            "br" => Ok(Self::BR),
+            "identity" => Ok(Self::IDENTITY),
+            "*" => Ok(Self::ANY),
             "token" => Ok(Self::TOKEN),
             _ => Err(InvalidContentEncodingErr),
         }
@@ -37,7 +48,167 @@ impl std::fmt::Display for ContentEncoding {
         match *self {
             ContentEncoding::GZIP => f.write_str("gzip"),
             ContentEncoding::DEFLATE => f.write_str("deflate"),
+            ContentEncoding::BR => f.write_str("br"),
+            ContentEncoding::IDENTITY => f.write_str("identity"),
+            ContentEncoding::ANY => f.write_str("*"),
             ContentEncoding::TOKEN => f.write_str("token"),
         }
     }
 }
+
+// An Accept-Encoding entry with its weight in thousandths, q=0.5 being 500
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct AcceptedCoding {
+    pub encoding: ContentEncoding,
+    pub q: u16,
+}
+
+impl From<ContentEncoding> for AcceptedCoding {
+    fn from(encoding: ContentEncoding) -> Self {
+        AcceptedCoding { encoding, q: 1000 }
+    }
+}
+
+// A qvalue is 0 or 1 with at most three decimals (RFC 7231 5.3.1)
+fn parse_qvalue(value: &str) -> Option<u16> {
+    let (int, frac) = value.split_once('.').unwrap_or((value, ""));
+    if frac.len() > 3 || !frac.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let thousandths = format!("{:0<3}", frac).parse::<u16>().ok()?;
+    match int {
+        "0" => Some(thousandths),
+        "1" if thousandths == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+impl TryFrom<&str> for AcceptedCoding {
+    type Error = InvalidContentEncodingErr;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut parts = value.split(';').map(str::trim);
+        let encoding =
+            ContentEncoding::try_from(parts.next().unwrap_or("").to_ascii_lowercase().as_str())?;
+        let mut q = 1000;
+        for param in parts {
+            if let Some((name, value)) = param.split_once('=') {
+                if name.trim().eq_ignore_ascii_case("q") {
+                    q = parse_qvalue(value.trim()).ok_or(InvalidContentEncodingErr)?;
+                }
+            }
+        }
+        Ok(AcceptedCoding { encoding, q })
+    }
+}
+
+impl std::fmt::Display for AcceptedCoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.q {
+            1000 => self.encoding.fmt(f),
+            q => f.write_fmt(format_args!("{};q={}", self.encoding, q as f32 / 1000.0)),
+        }
+    }
+}
+
+// The weight a client gives a coding, falling back to `*`. Identity is acceptable
+// unless excluded explicitly or by `*;q=0` (RFC 7231 5.3.4).
+pub fn quality(accepted: &[AcceptedCoding], encoding: &ContentEncoding) -> u16 {
+    let weight = |encoding: &ContentEncoding| {
+        accepted
+            .iter()
+            .find(|coding| coding.encoding == *encoding)
+            .map(|coding| coding.q)
+    };
+    match (weight(encoding), weight(&ContentEncoding::ANY)) {
+        (Some(q), _) | (None, Some(q)) => q,
+        (None, None) if *encoding == ContentEncoding::IDENTITY => 1000,
+        (None, None) => 0,
+    }
+}
+
+// Orders the candidates the client accepts by weight, ties going to the one it
+// listed first and then to the order of the candidates
+pub fn preference(
+    accepted: &[AcceptedCoding],
+    candidates: &[ContentEncoding],
+) -> Vec<ContentEncoding> {
+    let mut ranked: Vec<(u16, usize, &ContentEncoding)> = candidates
+        .iter()
+        .map(|candidate| {
+            let listed = accepted
+                .iter()
+                .position(|coding| coding.encoding == *candidate)
+                .unwrap_or(accepted.len());
+            (quality(accepted, candidate), listed, candidate)
+        })
+        .filter(|(q, _, _)| *q > 0)
+        .collect();
+    ranked.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    ranked
+        .into_iter()
+        .map(|(_, _, candidate)| candidate.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(value: &str) -> Vec<AcceptedCoding> {
+        value
+            .split(',')
+            .filter_map(|coding| AcceptedCoding::try_from(coding).ok())
+            .collect()
+    }
+
+    #[test]
+    fn parses_weighted_codings() {
+        assert_eq!(
+            parse("gzip;q=0.5, identity; q=0, *, zstd"),
+            vec![
+                AcceptedCoding {
+                    encoding: ContentEncoding::GZIP,
+                    q: 500
+                },
+                AcceptedCoding {
+                    encoding: ContentEncoding::IDENTITY,
+                    q: 0
+                },
+                AcceptedCoding {
+                    encoding: ContentEncoding::ANY,
+                    q: 1000
+                },
+            ]
+        );
+        assert!(AcceptedCoding::try_from("gzip;q=1.5").is_err());
+        assert_eq!(parse("gzip;q=0.25")[0].to_string(), "gzip;q=0.25");
+    }
+
+    #[test]
+    fn weighs_identity_and_any() {
+        let candidates = [
+            ContentEncoding::GZIP,
+            ContentEncoding::DEFLATE,
+            ContentEncoding::IDENTITY,
+        ];
+        assert_eq!(
+            preference(&parse("deflate"), &candidates),
+            vec![ContentEncoding::DEFLATE, ContentEncoding::IDENTITY]
+        );
+        assert_eq!(preference(&parse("*"), &candidates), candidates.to_vec());
+        assert_eq!(
+            preference(&parse("*, gzip;q=0"), &candidates),
+            vec![ContentEncoding::DEFLATE, ContentEncoding::IDENTITY]
+        );
+        assert_eq!(
+            quality(&parse("gzip, identity;q=0"), &ContentEncoding::IDENTITY),
+            0
+        );
+        assert_eq!(quality(&parse("*;q=0"), &ContentEncoding::IDENTITY), 0);
+        assert_eq!(
+            quality(&parse("*;q=0, identity"), &ContentEncoding::IDENTITY),
+            1000
+        );
+        assert_eq!(quality(&[], &ContentEncoding::IDENTITY), 1000);
+    }
+}
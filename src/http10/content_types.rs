@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 #[derive(Debug)]
 pub struct InvalidContentTypeErr;
 
@@ -7,21 +9,22 @@ pub struct MimeType {
     pub content_subtype: Vec<ContentSubtype>,
 }
 
-pub fn get_mime(value: String) -> &'static str {
-    match value.as_str() {
-        "html" | "htm" => "text/html",
-        "css" => "text/css",
-        "gz" => "application/gzip",
-        "gif" => "image/gif",
-        "ico" => "image/vnd.microsoft.icon",
-        "jpg" | "jpeg" => "image/jpeg",
-        "js" => "text/javascript",
-        "json" => "applicaton/json",
-        "png" => "image/png",
-        "pdf" => "applicaton/pdf",
-        "txt" => "text/plain",
-        "xml" => "applicaton/xhtml+xml",
-        _ => "application/octet-stream"
+// None for extensions we don't know, left for the caller to default
+pub fn get_mime(value: &str) -> Option<&'static str> {
+    match value {
+        "html" | "htm" => Some("text/html"),
+        "css" => Some("text/css"),
+        "gz" => Some("application/gzip"),
+        "gif" => Some("image/gif"),
+        "ico" => Some("image/vnd.microsoft.icon"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "js" => Some("text/javascript"),
+        "json" => Some("application/json"),
+        "png" => Some("image/png"),
+        "pdf" => Some("application/pdf"),
+        "txt" => Some("text/plain"),
+        "xml" => Some("application/xml"),
+        _ => None
     }
 }
 
@@ -131,3 +134,108 @@ impl std::fmt::Display for ContentType {
         }
     }
 }
+
+// A parsed Content-Type value: the media type plus its parameters. The raw
+// value is kept so types and parameters we don't model round-trip untouched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaType {
+    pub content_type: Option<ContentType>,
+    pub essence: String,
+    pub params: HashMap<String, String>,
+    pub raw: String,
+}
+
+impl MediaType {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(&name.to_ascii_lowercase()).map(String::as_str)
+    }
+
+    pub fn charset(&self) -> Option<&str> {
+        self.param("charset")
+    }
+
+    pub fn boundary(&self) -> Option<&str> {
+        self.param("boundary")
+    }
+}
+
+// Strips the quotes (and backslash escapes) from a quoted-string parameter value
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|value| value.strip_suffix('"')) {
+        Some(quoted) => {
+            let mut unquoted = String::new();
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => unquoted.extend(chars.next()),
+                    c => unquoted.push(c),
+                }
+            }
+            unquoted
+        }
+        None => value.to_string(),
+    }
+}
+
+impl TryFrom<&str> for MediaType {
+    type Error = InvalidContentTypeErr;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut parts = value.split(';');
+        let essence = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let Some((typ, subtype)) = essence.split_once('/') else {
+            return Err(InvalidContentTypeErr);
+        };
+        if typ.is_empty() || subtype.is_empty() {
+            return Err(InvalidContentTypeErr);
+        }
+        let content_type = ContentType::try_from(typ).ok();
+        let mut params = HashMap::new();
+        for param in parts {
+            let param = param.trim();
+            if param.is_empty() {
+                continue;
+            }
+            let Some((name, value)) = param.split_once('=') else {
+                return Err(InvalidContentTypeErr);
+            };
+            params.insert(name.trim().to_ascii_lowercase(), unquote(value.trim()));
+        }
+        Ok(MediaType { content_type, essence, params, raw: value.to_string() })
+    }
+}
+
+impl std::fmt::Display for MediaType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_charset_parameter() {
+        let media = MediaType::try_from("text/html; charset=UTF-8").unwrap();
+        assert_eq!(media.content_type, Some(ContentType::Text));
+        assert_eq!(media.essence, "text/html");
+        assert_eq!(media.charset(), Some("UTF-8"));
+        assert_eq!(media.to_string(), "text/html; charset=UTF-8");
+    }
+
+    #[test]
+    fn parses_multipart_boundary() {
+        let media = MediaType::try_from("multipart/form-data; Boundary=\"----form \\\"42\\\"\"").unwrap();
+        assert_eq!(media.content_type, Some(ContentType::Multipart));
+        assert_eq!(media.essence, "multipart/form-data");
+        assert_eq!(media.boundary(), Some("----form \"42\""));
+    }
+
+    #[test]
+    fn keeps_unknown_types() {
+        let media = MediaType::try_from("x-custom/thing; v=1").unwrap();
+        assert_eq!(media.content_type, None);
+        assert_eq!(media.param("v"), Some("1"));
+        assert!(MediaType::try_from("nonsense").is_err());
+    }
+}
@@ -3,20 +3,94 @@ use std::collections::{HashMap, HashSet};
 use chrono::{DateTime, FixedOffset, Utc};
 
 use super::{
-    content_codings::ContentEncoding,
-    methods::{InvalidMethodErr, Method},
+    content_codings::{AcceptedCoding, ContentEncoding},
+    content_types::MediaType,
+    methods::{is_token, InvalidMethodErr, Method},
 };
 
-#[derive(Debug)]
+use crate::util::http_date;
+
+// Whether a value can go out as a field value as is. Control characters, CR and
+// LF above all, would let it end the field early and inject others (RFC 7230 3.2).
+pub fn is_field_value(value: &str) -> bool {
+    !value.chars().any(|c| c.is_ascii_control() && c != '\t')
+}
+
+// Drops whatever would keep a value from being a single field value
+pub fn sanitize_field_value(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| !c.is_ascii_control() || *c == '\t')
+        .collect()
+}
+
+// Header lines accepted per request unless configured otherwise
+pub const DEFAULT_MAX_HEADERS: usize = 100;
+
+#[derive(Debug, PartialEq, Eq)]
 pub enum HeaderErr {
-    InvalidField(String),
+    // A line that isn't `field: value`
+    Malformed(String),
+    // A known field whose value doesn't parse
+    UnparseableValue { field: String, value: String },
+    // A field sent more than once that can't be combined into one
+    DuplicateUnmergeable(String),
+    // Content-Length sent more than once with different values
+    ConflictingContentLength(usize, usize),
+    TooManyFields(usize),
 }
 
 impl std::fmt::Display for HeaderErr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::InvalidField(err) => f.write_fmt(format_args!("Invalid Field: {}", err)),
+            Self::Malformed(line) => f.write_fmt(format_args!("Malformed header line {}", line)),
+            Self::UnparseableValue { field, value } => {
+                f.write_fmt(format_args!("Unable to parse {} value {}", field, value))
+            }
+            Self::DuplicateUnmergeable(field) => {
+                f.write_fmt(format_args!("Field {} may only be sent once", field))
+            }
+            Self::ConflictingContentLength(first, second) => f.write_fmt(format_args!(
+                "Conflicting Content-Length values {} and {}",
+                first, second
+            )),
+            Self::TooManyFields(max) => {
+                f.write_fmt(format_args!("Too many header fields (limit {})", max))
+            }
+        }
+    }
+}
+
+// An Authorization header split into its auth scheme and the credentials that
+// follow, a token68 or auth-params depending on the scheme
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Credentials {
+    pub scheme: String,
+    pub credentials: String,
+}
+
+impl Credentials {
+    // Scheme names are case-insensitive
+    pub fn is_scheme(&self, scheme: &str) -> bool {
+        self.scheme.eq_ignore_ascii_case(scheme)
+    }
+}
+
+impl TryFrom<&str> for Credentials {
+    type Error = HeaderErr;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let value = value.trim();
+        let (scheme, credentials) = value.split_once([' ', '\t']).unwrap_or((value, ""));
+        if !is_token(scheme) {
+            return Err(HeaderErr::UnparseableValue {
+                field: "Authorization".to_string(),
+                value: value.to_string(),
+            });
         }
+        Ok(Credentials {
+            scheme: scheme.to_string(),
+            credentials: credentials.trim().to_string(),
+        })
     }
 }
 
@@ -45,12 +119,18 @@ impl Headers {
             Header::AcceptEncoding(_) => {
                 self.headers.insert(HeaderVariant::AcceptEncoding, header);
             }
+            Header::AcceptRanges(_) => {
+                self.headers.insert(HeaderVariant::AcceptRanges, header);
+            }
             Header::Allow(_) => {
                 self.headers.insert(HeaderVariant::Allow, header);
             }
             Header::Authorization(_) => {
                 self.headers.insert(HeaderVariant::Authorization, header);
             }
+            Header::Connection(_) => {
+                self.headers.insert(HeaderVariant::Connection, header);
+            }
             Header::ContentEncoding(_) => {
                 self.headers.insert(HeaderVariant::ContentEncoding, header);
             }
@@ -63,6 +143,9 @@ impl Headers {
             Header::Date(_) => {
                 self.headers.insert(HeaderVariant::Date, header);
             }
+            Header::ETag(_) => {
+                self.headers.insert(HeaderVariant::ETag, header);
+            }
             Header::Expires(_) => {
                 self.headers.insert(HeaderVariant::Expires, header);
             }
@@ -78,12 +161,18 @@ impl Headers {
             Header::IfModifiedSince(_) => {
                 self.headers.insert(HeaderVariant::IfModifiedSince, header);
             }
+            Header::IfNoneMatch(_) => {
+                self.headers.insert(HeaderVariant::IfNoneMatch, header);
+            }
             Header::LastModified(_) => {
                 self.headers.insert(HeaderVariant::LastModified, header);
             }
             Header::Location(_) => {
                 self.headers.insert(HeaderVariant::Location, header);
             }
+            Header::MaxForwards(_) => {
+                self.headers.insert(HeaderVariant::MaxForwards, header);
+            }
             Header::Pragma(_) => {
                 self.headers.insert(HeaderVariant::Pragma, header);
             }
@@ -93,6 +182,9 @@ impl Headers {
             Header::Server(_) => {
                 self.headers.insert(HeaderVariant::Server, header);
             }
+            Header::TransferEncoding(_) => {
+                self.headers.insert(HeaderVariant::TransferEncoding, header);
+            }
             Header::UserAgent(_) => {
                 self.headers.insert(HeaderVariant::UserAgent, header);
             }
@@ -102,8 +194,67 @@ impl Headers {
         }
     }
 
-    pub fn get_generic(&self, _header: &str) -> Option<String> {
-        unimplemented!();
+    // Names of the fields present, typed and generic
+    pub fn field_names(&self) -> Vec<String> {
+        self.headers
+            .values()
+            .chain(&self.extra)
+            .map(Header::field_name)
+            .collect()
+    }
+
+    // Removes every field with the given name, typed or generic
+    pub fn remove_named(&mut self, name: &str) {
+        self.headers.retain(|_, header| !header.is_named(name));
+        self.extra.retain(|header| !header.is_named(name));
+    }
+
+    // Removes the hop-by-hop fields listed in the Connection header so they
+    // aren't passed on beyond this connection
+    pub fn strip_hop_by_hop(&mut self) {
+        if let Some(Header::Connection(tokens)) = self.get(HeaderVariant::Connection) {
+            for token in tokens {
+                if token != "close" && token != "keep-alive" {
+                    self.remove_named(&token);
+                }
+            }
+        }
+        self.remove_named("Keep-Alive");
+    }
+
+    // Content-Type parsed into its media type and parameters
+    pub fn content_type(&self) -> Option<MediaType> {
+        match self.get(HeaderVariant::ContentType) {
+            Some(Header::ContentType(value)) => MediaType::try_from(value.as_str()).ok(),
+            _ => None,
+        }
+    }
+
+    // Authorization parsed into its scheme and credentials
+    pub fn authorization(&self) -> Option<Credentials> {
+        match self.get(HeaderVariant::Authorization) {
+            Some(Header::Authorization(value)) => Credentials::try_from(value.as_str()).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn get_generic(&self, header: &str) -> Option<String> {
+        // Repeated fields are combined into a single comma separated value
+        let values: Vec<String> = self
+            .extra
+            .iter()
+            .filter_map(|extra| match extra {
+                Header::Generic((field, value)) if field.eq_ignore_ascii_case(header) => {
+                    Some(value.clone())
+                }
+                _ => None,
+            })
+            .collect();
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.join(", "))
+        }
     }
 }
 
@@ -131,23 +282,37 @@ impl std::fmt::Display for Headers {
     }
 }
 
-impl TryFrom<&str> for Headers {
-    type Error = HeaderErr;
-
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
+impl Headers {
+    // Parses header lines, rejecting blocks with more than max_fields lines
+    pub fn try_from_with_limit(value: &str, max_fields: usize) -> Result<Self, HeaderErr> {
         let lines = value.trim_end().split("\r\n");
         let mut hm: HashMap<HeaderVariant, Header> = HashMap::new();
         let mut ex = Vec::new();
-        for line in lines {
-            let (k, mut v) = match Header::try_from(line)? {
+        for (count, line) in lines.enumerate() {
+            if count >= max_fields {
+                return Err(HeaderErr::TooManyFields(max_fields));
+            }
+            let header = match Header::try_from(line) {
+                // A bad date only means the condition can't be evaluated (RFC 7232 3.3)
+                Err(HeaderErr::UnparseableValue { field, .. }) if field == "If-Modified-Since" => {
+                    log::debug!("Ignoring invalid {}", line);
+                    continue;
+                }
+                header => header?,
+            };
+            let (k, mut v) = match header {
                 Header::Accept(val) => (HeaderVariant::Accept, Header::Accept(val)),
                 Header::AcceptEncoding(val) => {
                     (HeaderVariant::AcceptEncoding, Header::AcceptEncoding(val))
                 }
+                Header::AcceptRanges(val) => {
+                    (HeaderVariant::AcceptRanges, Header::AcceptRanges(val))
+                }
                 Header::Allow(val) => (HeaderVariant::Allow, Header::Allow(val)),
                 Header::Authorization(val) => {
                     (HeaderVariant::Authorization, Header::Authorization(val))
                 }
+                Header::Connection(val) => (HeaderVariant::Connection, Header::Connection(val)),
                 Header::ContentEncoding(val) => {
                     (HeaderVariant::ContentEncoding, Header::ContentEncoding(val))
                 }
@@ -156,6 +321,7 @@ impl TryFrom<&str> for Headers {
                 }
                 Header::ContentType(val) => (HeaderVariant::ContentType, Header::ContentType(val)),
                 Header::Date(val) => (HeaderVariant::Date, Header::Date(val)),
+                Header::ETag(val) => (HeaderVariant::ETag, Header::ETag(val)),
                 Header::Expires(val) => (HeaderVariant::Expires, Header::Expires(val)),
                 Header::From(val) => (HeaderVariant::From, Header::From(val)),
                 Header::Generic(val) => (HeaderVariant::Generic, Header::Generic(val)),
@@ -163,13 +329,19 @@ impl TryFrom<&str> for Headers {
                 Header::IfModifiedSince(val) => {
                     (HeaderVariant::IfModifiedSince, Header::IfModifiedSince(val))
                 }
+                Header::IfNoneMatch(val) => (HeaderVariant::IfNoneMatch, Header::IfNoneMatch(val)),
                 Header::LastModified(val) => {
                     (HeaderVariant::LastModified, Header::LastModified(val))
                 }
                 Header::Location(val) => (HeaderVariant::Location, Header::Location(val)),
+                Header::MaxForwards(val) => (HeaderVariant::MaxForwards, Header::MaxForwards(val)),
                 Header::Pragma(val) => (HeaderVariant::Pragma, Header::Pragma(val)),
                 Header::Referer(val) => (HeaderVariant::Referer, Header::Referer(val)),
                 Header::Server(val) => (HeaderVariant::Server, Header::Server(val)),
+                Header::TransferEncoding(val) => (
+                    HeaderVariant::TransferEncoding,
+                    Header::TransferEncoding(val),
+                ),
                 Header::UserAgent(val) => (HeaderVariant::UserAgent, Header::UserAgent(val)),
                 Header::WWWAuthenticate(val) => {
                     (HeaderVariant::WWWAuthenticate, Header::WWWAuthenticate(val))
@@ -184,40 +356,54 @@ impl TryFrom<&str> for Headers {
                 match value {
                     Header::Accept(inner) => {
                         let Header::Accept(inner_v) = v else {
-                            return Err(HeaderErr::InvalidField(
-                                "Error merging Accept header".to_string(),
-                            ));
+                            return Err(HeaderErr::DuplicateUnmergeable(v.field_name()));
                         };
-                        v = Header::Accept(inner_v + inner);
+                        v = Header::Accept(format!("{}, {}", inner_v, inner));
                     }
                     Header::AcceptEncoding(encodings) => {
                         let Header::AcceptEncoding(ex_enc) = v else {
-                            return Err(HeaderErr::InvalidField(
-                                "Error merging Accept header".to_string(),
-                            ));
+                            return Err(HeaderErr::DuplicateUnmergeable(v.field_name()));
                         };
+                        // Order matters for preference, the earlier field comes first
                         let mut encs = encodings.clone();
-                        encs.append(&mut ex_enc.clone());
-                        let hs: HashSet<ContentEncoding> = HashSet::from_iter(encs.iter().cloned());
-                        v = Header::AcceptEncoding(hs.into_iter().collect());
+                        encs.extend(ex_enc);
+                        v = Header::AcceptEncoding(encs);
                     }
                     Header::Allow(methods) => {
                         let Header::Allow(ex_met) = v else {
-                            return Err(HeaderErr::InvalidField(
-                                "Error merging Accept header".to_string(),
-                            ));
+                            return Err(HeaderErr::DuplicateUnmergeable(v.field_name()));
                         };
                         let mut mets = methods.clone();
                         mets.append(&mut ex_met.clone());
                         let hs: HashSet<Method> = HashSet::from_iter(mets.iter().cloned());
                         v = Header::Allow(hs.into_iter().collect());
                     }
-                    _ => {
-                        return Err(HeaderErr::InvalidField(format!(
-                            "Cannot merge multiple of field {}, {}",
-                            v, value
-                        )))
+                    Header::Connection(tokens) => {
+                        let Header::Connection(ex_tokens) = v else {
+                            return Err(HeaderErr::DuplicateUnmergeable(v.field_name()));
+                        };
+                        let mut all = tokens.clone();
+                        all.append(&mut ex_tokens.clone());
+                        v = Header::Connection(all);
+                    }
+                    // Repeats of the same length are harmless and accepted (RFC 7230 3.3.2),
+                    // differing ones leave the body length ambiguous
+                    Header::ContentLength(len) => match v {
+                        Header::ContentLength(ex_len) if ex_len == *len => {}
+                        Header::ContentLength(ex_len) => {
+                            return Err(HeaderErr::ConflictingContentLength(*len, ex_len))
+                        }
+                        _ => return Err(HeaderErr::DuplicateUnmergeable(v.field_name())),
+                    },
+                    Header::IfNoneMatch(tags) => {
+                        let Header::IfNoneMatch(ex_tags) = v else {
+                            return Err(HeaderErr::DuplicateUnmergeable(v.field_name()));
+                        };
+                        let mut all = tags.clone();
+                        all.append(&mut ex_tags.clone());
+                        v = Header::IfNoneMatch(all);
                     }
+                    _ => return Err(HeaderErr::DuplicateUnmergeable(v.field_name())),
                 }
             }
             hm.insert(k, v);
@@ -229,6 +415,14 @@ impl TryFrom<&str> for Headers {
     }
 }
 
+impl TryFrom<&str> for Headers {
+    type Error = HeaderErr;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Headers::try_from_with_limit(value, DEFAULT_MAX_HEADERS)
+    }
+}
+
 impl TryFrom<String> for Headers {
     type Error = HeaderErr;
 
@@ -241,22 +435,28 @@ impl TryFrom<String> for Headers {
 pub enum HeaderVariant {
     Accept,
     AcceptEncoding,
+    AcceptRanges,
     Allow,
     Authorization,
+    Connection,
     ContentEncoding,
     ContentLength,
     ContentType,
     Date,
+    ETag,
     Expires,
     From,
     Generic,
     Host,
     IfModifiedSince,
+    IfNoneMatch,
     LastModified,
     Location,
+    MaxForwards,
     Pragma,
     Referer,
     Server,
+    TransferEncoding,
     UserAgent,
     WWWAuthenticate,
 }
@@ -265,23 +465,30 @@ pub enum HeaderVariant {
 #[allow(dead_code)]
 pub enum Header {
     Accept(String),
-    AcceptEncoding(Vec<ContentEncoding>),
+    AcceptEncoding(Vec<AcceptedCoding>),
+    // Range units the server takes for the resource, "none" if it takes no ranges
+    AcceptRanges(String),
     Allow(Vec<Method>),
     Authorization(String),
+    Connection(Vec<String>),
     ContentEncoding(ContentEncoding),
     ContentLength(usize),
     ContentType(String),
     Date(DateTime<FixedOffset>),
+    ETag(String),
     Expires(DateTime<FixedOffset>),
     From(String),
     Generic((String, String)),
     Host(String),
     IfModifiedSince(DateTime<FixedOffset>),
+    IfNoneMatch(Vec<String>),
     LastModified(DateTime<FixedOffset>),
     Location(String),
+    MaxForwards(usize),
     Pragma(String),
     Referer(String),
     Server(String),
+    TransferEncoding(String),
     UserAgent(String),
     WWWAuthenticate(String),
 }
@@ -298,21 +505,26 @@ impl std::fmt::Display for Header {
                     .collect::<Vec<String>>()
                     .join(", ")
             )),
+            Header::AcceptRanges(units) => f.write_fmt(format_args!("Accept-Ranges: {}", units)),
             Header::Allow(methods) => f.write_fmt(format_args!(
                 "Allow: {}",
                 methods
                     .iter()
-                    .map(|method| Into::<String>::into(*method))
+                    .map(|method| Into::<String>::into(method.clone()))
                     .collect::<Vec<String>>()
                     .join(",")
             )),
             Header::Authorization(suf) => f.write_fmt(format_args!("Authorization: {}", suf)),
+            Header::Connection(tokens) => {
+                f.write_fmt(format_args!("Connection: {}", tokens.join(", ")))
+            }
             Header::ContentEncoding(encoding) => {
                 f.write_fmt(format_args!("Content-Encoding: {}", encoding))
             }
             Header::ContentLength(len) => f.write_fmt(format_args!("Content-Length: {}", len)),
             Header::ContentType(mime) => f.write_fmt(format_args!("Content-Type: {}", mime)),
-            Header::Date(date) => f.write_fmt(format_args!("Date: {}", date.to_rfc2822())),
+            Header::Date(date) => f.write_fmt(format_args!("Date: {}", http_date::format(date))),
+            Header::ETag(tag) => f.write_fmt(format_args!("ETag: {}", tag)),
             Header::Expires(date) => f.write_fmt(format_args!("Expires: {}", date.to_rfc2822())),
             Header::From(suf) => f.write_fmt(format_args!("From: {}", suf)),
             Header::Generic((pref, suf)) => f.write_fmt(format_args!("{}: {}", pref, suf)),
@@ -320,19 +532,56 @@ impl std::fmt::Display for Header {
             Header::IfModifiedSince(date) => {
                 f.write_fmt(format_args!("If-Modified-Since: {}", date.to_rfc2822()))
             }
+            Header::IfNoneMatch(tags) => {
+                f.write_fmt(format_args!("If-None-Match: {}", tags.join(", ")))
+            }
             Header::LastModified(date) => {
                 f.write_fmt(format_args!("Last-Modified: {}", date.to_rfc2822()))
             }
             Header::Location(suf) => f.write_fmt(format_args!("Location: {}", suf)),
+            Header::MaxForwards(hops) => f.write_fmt(format_args!("Max-Forwards: {}", hops)),
             Header::Pragma(suf) => f.write_fmt(format_args!("Pragma: {}", suf)),
             Header::Referer(suf) => f.write_fmt(format_args!("Referer: {}", suf)),
             Header::Server(suf) => f.write_fmt(format_args!("Server: {}", suf)),
+            Header::TransferEncoding(suf) => {
+                f.write_fmt(format_args!("Transfer-Encoding: {}", suf))
+            }
             Header::UserAgent(suf) => f.write_fmt(format_args!("User-Agent: {}", suf)),
             Header::WWWAuthenticate(suf) => f.write_fmt(format_args!("WWW-Authenticate: {}", suf)),
         }
     }
 }
 
+impl Header {
+    // Location pointing at a target that may echo client controlled path data.
+    // Bytes that can't appear in a URI reference, CR and LF included, are
+    // percent-encoded so the value can't end the field early.
+    pub fn location(target: &str) -> Header {
+        let encoded = target
+            .bytes()
+            .map(|byte| match byte {
+                b'"' | b'<' | b'>' | b'\\' | b'^' | b'`' | b'{' | b'|' | b'}' => {
+                    format!("%{:02X}", byte)
+                }
+                byte if byte.is_ascii_graphic() => (byte as char).to_string(),
+                byte => format!("%{:02X}", byte),
+            })
+            .collect();
+        Header::Location(encoded)
+    }
+
+    pub fn field_name(&self) -> String {
+        let line = self.to_string();
+        line.split_once(':')
+            .map_or(line.clone(), |(field, _)| field.to_string())
+    }
+
+    // Whether this header's field name matches, ignoring case
+    pub fn is_named(&self, name: &str) -> bool {
+        self.field_name().eq_ignore_ascii_case(name)
+    }
+}
+
 impl TryFrom<&str> for Header {
     type Error = HeaderErr;
     fn try_from(value: &str) -> Result<Self, Self::Error> {
@@ -346,76 +595,83 @@ impl TryFrom<String> for Header {
     fn try_from(value: String) -> Result<Self, Self::Error> {
         if let Some((field, suffix)) = value.split_once(':') {
             let suf = suffix.trim();
+            let unparseable = || HeaderErr::UnparseableValue {
+                field: field.to_string(),
+                value: suf.to_string(),
+            };
             match field {
                 "Accept" => Ok(Self::Accept(suf.to_string())),
                 "Accept-Encoding" => {
+                    // Codings we don't know can't be produced anyway and are left out,
+                    // an empty list still means identity is welcome
                     let codings = suf
                         .split(',')
-                        .map(|coding| ContentEncoding::try_from(coding.trim()))
-                        .filter_map(|coding| coding.ok())
-                        .collect::<Vec<ContentEncoding>>();
-                    if codings.is_empty() {
-                        Err(Self::Error::InvalidField(format!(
-                            "No supported formats in: {}",
-                            suf
-                        )))
-                    } else {
-                        dbg!(Ok(Self::AcceptEncoding(codings)))
-                    }
+                        .filter_map(|coding| AcceptedCoding::try_from(coding).ok())
+                        .collect::<Vec<AcceptedCoding>>();
+                    Ok(Self::AcceptEncoding(codings))
                 }
+                "Accept-Ranges" => Ok(Self::AcceptRanges(suf.to_string())),
                 "Allow" => {
                     let methods = suf
                         .split(',')
-                        .map(|method| Method::try_from(method))
+                        .map(Method::try_from)
                         .collect::<Result<Vec<Method>, InvalidMethodErr>>()
-                        .map_err(|_| {
-                            Self::Error::InvalidField(format!("Unable to parse suffix {}", suf))
-                        });
+                        .map_err(|_| unparseable());
                     Ok(Self::Allow(methods?))
                 }
                 "Authorization" => Ok(Self::Authorization(suf.to_string())),
-                "Content-Encoding" => Ok(Self::ContentEncoding(
-                    ContentEncoding::try_from(suf).map_err(|_| {
-                        Self::Error::InvalidField(format!("Unable to parse suffix {}", suf))
-                    })?,
+                // Connection options are case-insensitive tokens
+                "Connection" => Ok(Self::Connection(
+                    suf.split(',')
+                        .map(|token| token.trim().to_ascii_lowercase())
+                        .filter(|token| !token.is_empty())
+                        .collect(),
+                )),
+                // Unknown codings are kept as is so a request body using one can be refused
+                // with 415 rather than failing the whole request
+                "Content-Encoding" => match ContentEncoding::try_from(suf) {
+                    Ok(encoding) => Ok(Self::ContentEncoding(encoding)),
+                    Err(_) => Ok(Self::Generic((field.to_string(), suf.to_string()))),
+                },
+                "Content-Length" => Ok(Self::ContentLength(
+                    suf.parse::<usize>().map_err(|_| unparseable())?,
                 )),
-                "Content-Length" => {
-                    Ok(Self::ContentLength(suf.parse::<usize>().map_err(|_| {
-                        Self::Error::InvalidField(format!("Unable to parse suffix {}", suf))
-                    })?))
-                }
                 "Content-Type" => Ok(Self::ContentType(suf.to_string())),
-                "Date" => Ok(Self::Date(DateTime::parse_from_rfc2822(suf).map_err(
-                    |_| Self::Error::InvalidField(format!("Unable to parse suffix {}", suf)),
-                )?)),
-                "Expires" => Ok(Self::Expires(DateTime::parse_from_rfc2822(suf).map_err(
-                    |_| Self::Error::InvalidField(format!("Unable to parse suffix {}", suf)),
-                )?)),
+                "Date" => Ok(Self::Date(
+                    DateTime::parse_from_rfc2822(suf).map_err(|_| unparseable())?,
+                )),
+                "ETag" => Ok(Self::ETag(suf.to_string())),
+                "Expires" => Ok(Self::Expires(
+                    DateTime::parse_from_rfc2822(suf).map_err(|_| unparseable())?,
+                )),
                 "From" => Ok(Self::From(suf.to_string())),
                 "Host" => Ok(Self::Host(suf.to_string())),
                 "If-Modified-Since" => Ok(Self::IfModifiedSince(
-                    DateTime::parse_from_rfc2822(suf).map_err(|_| {
-                        Self::Error::InvalidField(format!("Unable to parse suffix {}", suf))
-                    })?,
+                    DateTime::parse_from_rfc2822(suf).map_err(|_| unparseable())?,
+                )),
+                "If-None-Match" => Ok(Self::IfNoneMatch(
+                    suf.split(',')
+                        .map(|tag| tag.trim().to_string())
+                        .filter(|tag| !tag.is_empty())
+                        .collect(),
                 )),
                 "Last-Modified" => Ok(Self::LastModified(
-                    DateTime::parse_from_rfc2822(suf).map_err(|_| {
-                        Self::Error::InvalidField(format!("Unable to parse suffix {}", suf))
-                    })?,
+                    DateTime::parse_from_rfc2822(suf).map_err(|_| unparseable())?,
                 )),
                 "Location" => Ok(Self::Location(suf.to_string())),
+                "Max-Forwards" => Ok(Self::MaxForwards(
+                    suf.parse::<usize>().map_err(|_| unparseable())?,
+                )),
                 "Pragma" => Ok(Self::Pragma(suf.to_string())),
                 "Referer" => Ok(Self::Referer(suf.to_string())),
                 "Server" => Ok(Self::Server(suf.to_string())),
+                "Transfer-Encoding" => Ok(Self::TransferEncoding(suf.to_string())),
                 "User-Agent" => Ok(Self::UserAgent(suf.to_string())),
                 "WWW-Authenticate" => Ok(Self::WWWAuthenticate(suf.to_string())),
                 _ => Ok(Self::Generic((field.to_string(), suf.to_string()))),
             }
         } else {
-            Err(HeaderErr::InvalidField(format!(
-                "Unable to parse field {}",
-                value
-            )))
+            Err(HeaderErr::Malformed(value))
         }
     }
 }
@@ -424,6 +680,13 @@ impl TryFrom<String> for Header {
 mod tests {
     use super::*;
 
+    #[test]
+    fn converts_accept_ranges_from_string() {
+        let header = Header::try_from("Accept-Ranges: bytes").unwrap();
+        assert_eq!(header, Header::AcceptRanges("bytes".to_string()));
+        assert_eq!(header.to_string(), "Accept-Ranges: bytes");
+    }
+
     #[test]
     fn converts_authorization_from_string() {
         assert_eq!(
@@ -443,6 +706,174 @@ mod tests {
         );
     }
 
+    #[test]
+    fn converts_if_none_match_from_string() {
+        assert_eq!(
+            Header::try_from("If-None-Match: \"abc\", W/\"def\"").unwrap(),
+            Header::IfNoneMatch(vec!["\"abc\"".to_string(), "W/\"def\"".to_string()])
+        );
+    }
+
+    #[test]
+    fn converts_connection_from_string() {
+        assert_eq!(
+            Header::try_from("Connection: Keep-Alive, X-Trace").unwrap(),
+            Header::Connection(vec!["keep-alive".to_string(), "x-trace".to_string()])
+        );
+    }
+
+    #[test]
+    fn strips_hop_by_hop_headers() {
+        let mut headers = Headers::try_from(
+            "Connection: keep-alive, X-Trace, Pragma\r\n\
+            X-Trace: abc\r\n\
+            Keep-Alive: timeout=5\r\n\
+            Pragma: no-cache\r\n\
+            Host: www.mywebserver.com\r\n\r\n",
+        )
+        .unwrap();
+        headers.strip_hop_by_hop();
+        assert_eq!(headers.get_generic("X-Trace"), None);
+        assert_eq!(headers.get_generic("Keep-Alive"), None);
+        assert_eq!(headers.get(HeaderVariant::Pragma), None);
+        assert!(headers.get(HeaderVariant::Connection).is_some());
+        assert!(headers.get(HeaderVariant::Host).is_some());
+    }
+
+    #[test]
+    fn gets_generic_header_case_insensitively() {
+        let headers = Headers::try_from(
+            "X-Forwarded-For: 10.0.0.1\r\n\
+            x-forwarded-for: 10.0.0.2, 10.0.0.3\r\n\
+            Host: www.mywebserver.com\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            headers.get_generic("X-Forwarded-For"),
+            Some("10.0.0.1, 10.0.0.2, 10.0.0.3".to_string())
+        );
+        assert_eq!(headers.get_generic("X-Real-Ip"), None);
+    }
+
+    #[test]
+    fn parses_content_type_parameters() {
+        let headers =
+            Headers::try_from("Content-Type: multipart/form-data; boundary=xyz\r\n\r\n").unwrap();
+        let media = headers.content_type().unwrap();
+        assert_eq!(media.essence, "multipart/form-data");
+        assert_eq!(media.boundary(), Some("xyz"));
+    }
+
+    #[test]
+    fn parses_authorization_schemes() {
+        let headers = Headers::try_from("Authorization: Bearer abc.def.ghi\r\n\r\n").unwrap();
+        let credentials = headers.authorization().unwrap();
+        assert!(credentials.is_scheme("bearer"));
+        assert_eq!(credentials.credentials, "abc.def.ghi");
+
+        let credentials = Credentials::try_from("Basic dXNlcjpwYXNz").unwrap();
+        assert_eq!(credentials.scheme, "Basic");
+        assert_eq!(credentials.credentials, "dXNlcjpwYXNz");
+        assert!(Credentials::try_from("Negotiate").is_ok());
+        assert!(Credentials::try_from("").is_err());
+    }
+
+    #[test]
+    fn screens_field_values() {
+        assert!(is_field_value("text/plain;\tcharset=utf-8"));
+        assert!(!is_field_value("text/plain\r\nSet-Cookie: a=b"));
+        assert_eq!(
+            sanitize_field_value("text/plain\r\nSet-Cookie: a=b"),
+            "text/plainSet-Cookie: a=b"
+        );
+    }
+
+    #[test]
+    fn location_is_percent_encoded() {
+        let location = Header::location("/docs\r\nSet-Cookie: a=b/");
+        assert_eq!(
+            location.to_string(),
+            "Location: /docs%0D%0ASet-Cookie:%20a=b/"
+        );
+        assert_eq!(
+            Header::location("/a/b.html?x=1#top"),
+            Header::Location("/a/b.html?x=1#top".to_string())
+        );
+        assert_eq!(
+            Header::location("/caf\u{e9}/"),
+            Header::Location("/caf%C3%A9/".to_string())
+        );
+    }
+
+    #[test]
+    fn parses_max_forwards() {
+        assert_eq!(
+            Header::try_from("Max-Forwards: 0").unwrap(),
+            Header::MaxForwards(0)
+        );
+        assert_eq!(Header::MaxForwards(10).to_string(), "Max-Forwards: 10");
+        assert!(Header::try_from("Max-Forwards: -1").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert_eq!(
+            Header::try_from("Host webserver.christianbingman.com").unwrap_err(),
+            HeaderErr::Malformed("Host webserver.christianbingman.com".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_unparseable_values() {
+        assert_eq!(
+            Headers::try_from("Content-Length: ten\r\n\r\n").unwrap_err(),
+            HeaderErr::UnparseableValue {
+                field: "Content-Length".to_string(),
+                value: "ten".to_string()
+            }
+        );
+        // Except for a conditional date, which is ignored instead
+        let headers = Headers::try_from("If-Modified-Since: yesterday\r\nHost: a\r\n\r\n").unwrap();
+        assert_eq!(headers.get(HeaderVariant::IfModifiedSince), None);
+        assert_eq!(
+            headers.get(HeaderVariant::Host),
+            Some(Header::Host("a".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_unmergeable_fields() {
+        assert_eq!(
+            Headers::try_from("Host: a\r\nHost: b\r\n\r\n").unwrap_err(),
+            HeaderErr::DuplicateUnmergeable("Host".to_string())
+        );
+    }
+
+    #[test]
+    fn repeated_content_length_must_agree() {
+        assert_eq!(
+            Headers::try_from("Content-Length: 4\r\nContent-Length: 5\r\n\r\n").unwrap_err(),
+            HeaderErr::ConflictingContentLength(4, 5)
+        );
+        let headers = Headers::try_from("Content-Length: 4\r\nContent-Length: 4\r\n\r\n").unwrap();
+        assert_eq!(
+            headers.get(HeaderVariant::ContentLength),
+            Some(Header::ContentLength(4))
+        );
+    }
+
+    #[test]
+    fn rejects_too_many_fields() {
+        let headers_str = (0..5)
+            .map(|i| format!("X-Field-{}: {}\r\n", i, i))
+            .collect::<String>();
+        assert!(Headers::try_from_with_limit(&headers_str, 5).is_ok());
+        assert!(matches!(
+            Headers::try_from_with_limit(&headers_str, 4),
+            Err(HeaderErr::TooManyFields(4))
+        ));
+    }
+
     #[test]
     fn builds_header_list_from_string() {
         let headers_str = "Content-Type: text/html\r\n\
@@ -528,7 +959,10 @@ mod tests {
                 ),
                 (
                     HeaderVariant::AcceptEncoding,
-                    Header::AcceptEncoding(vec![ContentEncoding::DEFLATE, ContentEncoding::GZIP]),
+                    Header::AcceptEncoding(vec![
+                        ContentEncoding::DEFLATE.into(),
+                        ContentEncoding::GZIP.into(),
+                    ]),
                 ),
             ]),
             extra: vec![],
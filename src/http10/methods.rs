@@ -1,23 +1,33 @@
 #[derive(Debug)]
 pub struct InvalidMethodErr;
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub enum Method {
     GET,
     POST,
     HEAD,
+    OPTIONS,
+    PUT,
+    DELETE,
+    TRACE,
+    CONNECT,
+    // Any other well formed method token, left for handlers to support or refuse
+    Other(String),
+}
+
+// Method names are tokens (RFC 7230 3.1.1, 3.2.6), as are other protocol names
+pub fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
 }
 
 impl TryFrom<String> for Method {
     type Error = InvalidMethodErr;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        match value.as_str() {
-            "GET" => Ok(Method::GET),
-            "POST" => Ok(Method::POST),
-            "HEAD" => Ok(Method::HEAD),
-            _ => Err(InvalidMethodErr),
-        }
+        Method::try_from(value.as_str())
     }
 }
 
@@ -29,6 +39,12 @@ impl TryFrom<&str> for Method {
             "GET" => Ok(Method::GET),
             "POST" => Ok(Method::POST),
             "HEAD" => Ok(Method::HEAD),
+            "OPTIONS" => Ok(Method::OPTIONS),
+            "PUT" => Ok(Method::PUT),
+            "DELETE" => Ok(Method::DELETE),
+            "TRACE" => Ok(Method::TRACE),
+            "CONNECT" => Ok(Method::CONNECT),
+            other if is_token(other) => Ok(Method::Other(other.to_string())),
             _ => Err(InvalidMethodErr),
         }
     }
@@ -40,7 +56,29 @@ impl From<Method> for String {
             Method::GET => "GET",
             Method::POST => "POST",
             Method::HEAD => "HEAD",
+            Method::OPTIONS => "OPTIONS",
+            Method::PUT => "PUT",
+            Method::DELETE => "DELETE",
+            Method::TRACE => "TRACE",
+            Method::CONNECT => "CONNECT",
+            Method::Other(method) => return method,
         }
         .to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_unknown_token_methods() {
+        assert_eq!(Method::try_from("GET").unwrap(), Method::GET);
+        assert_eq!(
+            Method::try_from("PROPFIND").unwrap(),
+            Method::Other("PROPFIND".to_string())
+        );
+        assert!(Method::try_from("GE(T").is_err());
+        assert!(Method::try_from("").is_err());
+    }
+}
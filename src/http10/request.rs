@@ -1,15 +1,80 @@
-use super::headers::{Header, HeaderVariant, Headers};
+use std::io::{self, Read};
+use std::net::SocketAddr;
+
+use crate::compression;
+use crate::http10::content_codings::ContentEncoding;
+
+use super::headers::{Header, HeaderErr, HeaderVariant, Headers, DEFAULT_MAX_HEADERS};
 use super::methods::Method;
 
+// Bytes a request body may decode to unless configured otherwise
+pub const DEFAULT_MAX_BODY: usize = 16 * 1024 * 1024;
+
 #[derive(Debug, PartialEq, Eq)]
 #[allow(dead_code)]
 pub enum ReqError {
-    ParseError(String),
+    // The request line isn't `method SP target SP version`
+    BadRequestLine,
+    BadHeader(HeaderErr),
+    // The head isn't valid UTF-8
+    BadEncoding,
+    // More header fields than the configured limit
+    TooManyHeaders(usize),
+    BadChunk(String),
+    // The body doesn't decode with its Content-Encoding
+    BadContent(String),
     ContentLenError,
+    // Content-Length alongside Transfer-Encoding, framed differently depending on
+    // who reads it, a request smuggling vector (RFC 7230 3.3.3)
+    ConflictingLength,
     InvalidMethodErr,
     InvalidHTTPVerError,
+    UnsupportedEncodingErr(String),
+    // The body decodes to more than the limit in bytes
+    BodyTooLarge(usize),
+}
+
+impl std::fmt::Display for ReqError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadRequestLine => f.write_str("Malformed request line"),
+            Self::BadHeader(err) => err.fmt(f),
+            Self::BadEncoding => f.write_str("Invalid header encoding"),
+            Self::TooManyHeaders(max) => {
+                f.write_fmt(format_args!("Too many header fields (limit {})", max))
+            }
+            Self::BadChunk(err) => f.write_fmt(format_args!("Invalid chunked body: {}", err)),
+            Self::BadContent(err) => f.write_str(err),
+            Self::ContentLenError => f.write_str("Incomplete request or Content-Length mismatch"),
+            Self::ConflictingLength => {
+                f.write_str("Both Content-Length and Transfer-Encoding are present")
+            }
+            Self::InvalidMethodErr => f.write_str("Invalid request method"),
+            Self::InvalidHTTPVerError => f.write_str("Unsupported HTTP version"),
+            Self::UnsupportedEncodingErr(encoding) => {
+                f.write_fmt(format_args!("Unsupported content coding {}", encoding))
+            }
+            Self::BodyTooLarge(max) => {
+                f.write_fmt(format_args!("Decoded body larger than {} bytes", max))
+            }
+        }
+    }
 }
 
+/// A parsed request, as handlers receive it. Parsing needs no server:
+///
+/// ```
+/// use simple_webserver::{HTTPRequest, Header, HeaderVariant, Method};
+///
+/// let raw = b"GET /index.html HTTP/1.0\r\nHost: example.com\r\n\r\n".to_vec();
+/// let req = HTTPRequest::try_from(&raw).unwrap();
+/// assert_eq!(req.method, Method::GET);
+/// assert_eq!(req.uri, "/index.html");
+/// assert_eq!(
+///     req.headers.get(HeaderVariant::Host),
+///     Some(Header::Host("example.com".to_string()))
+/// );
+/// ```
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub struct HTTPRequest {
@@ -18,10 +83,19 @@ pub struct HTTPRequest {
     pub version: String,
     pub headers: Headers,
     pub body: Option<Vec<u8>>,
+    /// Trailer fields sent after a chunked body
+    pub trailers: Option<Headers>,
+    /// Address of the connected client, filled in by the server
+    pub peer: Option<SocketAddr>,
 }
 
 fn parse_request_line(line: impl Into<String>) -> Result<(Method, String, String), ReqError> {
     let line_s: String = line.into();
+    // Exactly three tokens with single spaces between, an empty token would shift
+    // the rest into the wrong place
+    if line_s.split(' ').count() != 3 || line_s.split(' ').any(str::is_empty) {
+        return Err(ReqError::BadRequestLine);
+    }
     let mut spl = line_s.split(" ");
     let method: Method;
     let uri: String;
@@ -30,78 +104,377 @@ fn parse_request_line(line: impl Into<String>) -> Result<(Method, String, String
     if let Some(m) = spl.next() {
         method = Method::try_from(m).map_err(|_| ReqError::InvalidMethodErr)?;
     } else {
-        return Err(ReqError::ParseError("Invalid header line".to_string()));
+        return Err(ReqError::BadRequestLine);
     }
 
     if let Some(u) = spl.next() {
         uri = u.to_string();
     } else {
-        return Err(ReqError::ParseError("Invalid header line".to_string()));
+        return Err(ReqError::BadRequestLine);
+    }
+    // The asterisk-form only names the server as a whole, for OPTIONS
+    if uri == "*" && method != Method::OPTIONS {
+        return Err(ReqError::BadRequestLine);
     }
 
     if let Some(v) = spl.next() {
         version = v.to_string();
     } else {
-        return Err(ReqError::ParseError("Invalid header line".to_string()));
+        return Err(ReqError::BadRequestLine);
     }
     Ok((method, uri, version))
 }
 
-// Convert from a string of bytes
-impl TryFrom<&Vec<u8>> for HTTPRequest {
-    type Error = ReqError;
-    fn try_from(req: &Vec<u8>) -> Result<Self, Self::Error> {
-        let spl_ind = &req.windows(4).position(|bytes| bytes == &[13, 10, 13, 10]);
-        if spl_ind.is_none() {
-            // Fail if we can't find \r\n\r\n
-            return Err(Self::Error::ContentLenError);
+// Reads a CRLF terminated line starting at pos, returning it and the position after it
+fn read_line(buf: &[u8], pos: usize) -> Result<(&[u8], usize), ReqError> {
+    match buf[pos..].windows(2).position(|bytes| bytes == b"\r\n") {
+        Some(len) => Ok((&buf[pos..pos + len], pos + len + 2)),
+        None => Err(ReqError::ContentLenError),
+    }
+}
+
+// The size at the start of a chunk, extensions after it ignored
+fn chunk_size(line: &[u8]) -> Result<usize, ReqError> {
+    let line = std::str::from_utf8(line)
+        .map_err(|_| ReqError::BadChunk("Invalid chunk size encoding".to_string()))?;
+    let size = line.split(';').next().unwrap_or("").trim();
+    usize::from_str_radix(size, 16)
+        .map_err(|_| ReqError::BadChunk(format!("Invalid chunk size {}", size)))
+}
+
+// Decodes a chunked body, returning it with its trailer fields, if any, and the
+// number of bytes it took up. Trailers are kept apart so they are never mistaken
+// for body data or the next request.
+fn decode_chunked(
+    body: &[u8],
+    max_headers: usize,
+) -> Result<(Vec<u8>, Option<Headers>, usize), ReqError> {
+    let mut decoded = Vec::new();
+    let mut pos = 0;
+    loop {
+        let (line, next) = read_line(body, pos)?;
+        let size = chunk_size(line)?;
+        pos = next;
+        if size == 0 {
+            break;
+        }
+        if body.len() < pos + size + 2 {
+            return Err(ReqError::ContentLenError);
+        }
+        if &body[pos + size..pos + size + 2] != b"\r\n" {
+            return Err(ReqError::BadChunk("Chunk missing terminator".to_string()));
         }
-        let (header_lines, body) = &req.split_at(spl_ind.unwrap() + 4);
-        let header_lines = header_lines.to_vec();
-        let header_lines_str = match std::str::from_utf8(&header_lines) {
-            Ok(lines) => lines,
-            Err(err) => {
-                log::debug!("Received invalid bytes {}", err);
-                return Err(Self::Error::ParseError("Invalid header encoding".into()));
+        decoded.extend_from_slice(&body[pos..pos + size]);
+        pos += size + 2;
+    }
+    // Trailer fields up to the terminating empty line
+    let trailers_start = pos;
+    loop {
+        let (line, next) = read_line(body, pos)?;
+        pos = next;
+        if line.is_empty() {
+            break;
+        }
+    }
+    if pos == trailers_start + 2 {
+        return Ok((decoded, None, pos));
+    }
+    let trailers =
+        std::str::from_utf8(&body[trailers_start..pos]).map_err(|_| ReqError::BadEncoding)?;
+    let trailers =
+        Headers::try_from_with_limit(trailers, max_headers).map_err(|err| match err {
+            HeaderErr::TooManyFields(max) => ReqError::TooManyHeaders(max),
+            err => ReqError::BadHeader(err),
+        })?;
+    Ok((decoded, Some(trailers), pos))
+}
+
+// Finds where a chunked body ends while it's still arriving. Each call carries on
+// from the last chunk it got past, so a body read in many pieces is walked once
+// rather than once per read.
+#[derive(Debug, Default)]
+pub struct ChunkedFraming {
+    // Start of the next chunk size or trailer line
+    pos: usize,
+    // Past the last chunk, among the trailer fields
+    trailers: bool,
+}
+
+impl ChunkedFraming {
+    // Where the body ends, just past its final trailer line, once it has all arrived.
+    // A malformed body ends where it is, leaving the error to the parser.
+    pub fn end(&mut self, body: &[u8]) -> Option<usize> {
+        loop {
+            let (line, next) = read_line(body, self.pos).ok()?;
+            if self.trailers {
+                self.pos = next;
+                if line.is_empty() {
+                    return Some(next);
+                }
+                continue;
             }
-        };
-        let headers = header_lines_str.split_once("\r\n");
-        if headers.is_none() {
-            return Err(Self::Error::ParseError(
-                "Unable to split header line".to_string(),
-            ));
+            let Ok(size) = chunk_size(line) else {
+                return Some(body.len());
+            };
+            if size == 0 {
+                self.trailers = true;
+                self.pos = next;
+                continue;
+            }
+            if body.len() < next + size + 2 {
+                return None;
+            }
+            if &body[next + size..next + size + 2] != b"\r\n" {
+                return Some(body.len());
+            }
+            self.pos = next + size + 2;
         }
-        let headers = headers.unwrap();
-        let (method, uri, version) = parse_request_line(headers.0)?;
+    }
+}
+
+// How the end of a request body is found once the head has been parsed
+#[derive(Debug, PartialEq, Eq)]
+pub enum BodyLength {
+    Exact(usize),
+    Chunked,
+}
 
-        // We are only supporting 1.0, but 1.1 should be compatible for the most part
-        if version != "HTTP/1.0" && version != "HTTP/1.1" {
-            return Err(Self::Error::InvalidHTTPVerError);
+// Length of the request line and headers including the blank line, once they have all arrived
+pub fn head_length(req: &[u8]) -> Option<usize> {
+    req.windows(4)
+        .position(|bytes| bytes == b"\r\n\r\n")
+        .map(|pos| pos + 4)
+}
+
+fn parse_head(
+    head: &[u8],
+    max_headers: usize,
+) -> Result<(Method, String, String, Headers), ReqError> {
+    let header_lines_str = match std::str::from_utf8(head) {
+        Ok(lines) => lines,
+        Err(err) => {
+            log::debug!("Received invalid bytes {}", err);
+            return Err(ReqError::BadEncoding);
         }
+    };
+    let Some(headers) = header_lines_str.split_once("\r\n") else {
+        return Err(ReqError::BadRequestLine);
+    };
+    let (method, uri, version) = parse_request_line(headers.0)?;
 
-        let headers: Headers = Headers::try_from(headers.1).map_err(|err| {
-            Self::Error::ParseError(format!("Unable to parse request line: {}", err))
+    // We are only supporting 1.0, but 1.1 should be compatible for the most part
+    if version != "HTTP/1.0" && version != "HTTP/1.1" {
+        return Err(ReqError::InvalidHTTPVerError);
+    }
+
+    let headers: Headers =
+        Headers::try_from_with_limit(headers.1, max_headers).map_err(|err| match err {
+            HeaderErr::TooManyFields(max) => ReqError::TooManyHeaders(max),
+            err => ReqError::BadHeader(err),
         })?;
+    if headers.get(HeaderVariant::ContentLength).is_some()
+        && headers.get(HeaderVariant::TransferEncoding).is_some()
+    {
+        return Err(ReqError::ConflictingLength);
+    }
+    Ok((method, uri, version, headers))
+}
 
-        if let Some(len) = headers.get(HeaderVariant::ContentLength) {
-            let Header::ContentLength(len) = len else {
-                return Err(Self::Error::ContentLenError);
-            };
-            if len != body.len() {
-                return Err(Self::Error::ContentLenError);
+fn body_length(headers: &Headers) -> BodyLength {
+    let chunked = match headers.get(HeaderVariant::TransferEncoding) {
+        Some(Header::TransferEncoding(coding)) => coding
+            .split(',')
+            .any(|coding| coding.trim().eq_ignore_ascii_case("chunked")),
+        _ => false,
+    };
+    match headers.get(HeaderVariant::ContentLength) {
+        _ if chunked => BodyLength::Chunked,
+        Some(Header::ContentLength(len)) => BodyLength::Exact(len),
+        _ => BodyLength::Exact(0),
+    }
+}
+
+// Parses only the head of a request so a reader can tell how many body bytes to expect
+pub fn expected_body(head: &[u8], max_headers: usize) -> Result<BodyLength, ReqError> {
+    let (_, _, _, headers) = parse_head(head, max_headers)?;
+    Ok(body_length(&headers))
+}
+
+// Undoes the Content-Encoding of a request body so handlers see the real payload.
+// The header is dropped and Content-Length updated to match the decoded body.
+fn decode_content(
+    headers: &mut Headers,
+    body: Vec<u8>,
+    max_body: usize,
+) -> Result<Vec<u8>, ReqError> {
+    let encoding = match headers.get(HeaderVariant::ContentEncoding) {
+        Some(Header::ContentEncoding(encoding)) => encoding,
+        _ => match headers.get_generic("Content-Encoding") {
+            Some(encoding) => return Err(ReqError::UnsupportedEncodingErr(encoding)),
+            None => return Ok(body),
+        },
+    };
+    if encoding == ContentEncoding::IDENTITY {
+        headers.remove_named("Content-Encoding");
+        return Ok(body);
+    }
+    if !compression::supports(&encoding) {
+        return Err(ReqError::UnsupportedEncodingErr(encoding.to_string()));
+    }
+    let decoded = compression::decompress_bytes(&body, &encoding, max_body).map_err(|err| {
+        if err.kind() == io::ErrorKind::FileTooLarge {
+            return ReqError::BodyTooLarge(max_body);
+        }
+        ReqError::BadContent(format!("Unable to decode {} body: {}", encoding, err))
+    })?;
+    headers.remove_named("Content-Encoding");
+    headers.set(Header::ContentLength(decoded.len()));
+    Ok(decoded)
+}
+
+// Reads a request body on demand, starting with any bytes that arrived with the head
+// and pulling the rest from the connection as the handler asks for it.
+pub struct BodyReader<'a> {
+    buffered: Vec<u8>,
+    pos: usize,
+    source: Option<&'a mut dyn Read>,
+    remaining: usize,
+}
+
+impl<'a> BodyReader<'a> {
+    pub fn new(mut buffered: Vec<u8>, source: &'a mut dyn Read, len: usize) -> Self {
+        buffered.truncate(len);
+        let remaining = len - buffered.len();
+        BodyReader {
+            buffered,
+            pos: 0,
+            source: Some(source),
+            remaining,
+        }
+    }
+
+    // A reader over a body that has already been read in full
+    pub fn from_bytes(body: Vec<u8>) -> BodyReader<'static> {
+        BodyReader {
+            buffered: body,
+            pos: 0,
+            source: None,
+            remaining: 0,
+        }
+    }
+
+    // Whether the whole body has been consumed
+    pub fn is_done(&self) -> bool {
+        self.pos == self.buffered.len() && self.remaining == 0
+    }
+}
+
+impl Read for BodyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.buffered.len() {
+            let n = buf.len().min(self.buffered.len() - self.pos);
+            buf[..n].copy_from_slice(&self.buffered[self.pos..self.pos + n]);
+            self.pos += n;
+            return Ok(n);
+        }
+        let Some(source) = self.source.as_mut() else {
+            return Ok(0);
+        };
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let max = buf.len().min(self.remaining);
+        let n = source.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed before the end of the body",
+            ));
+        }
+        self.remaining -= n;
+        Ok(n)
+    }
+}
+
+// Convert from a string of bytes
+impl TryFrom<&Vec<u8>> for HTTPRequest {
+    type Error = ReqError;
+    fn try_from(req: &Vec<u8>) -> Result<Self, Self::Error> {
+        HTTPRequest::try_from_with_limit(req, DEFAULT_MAX_HEADERS, DEFAULT_MAX_BODY)
+    }
+}
+
+impl HTTPRequest {
+    // Whether the client expects the connection to stay open after this request
+    pub fn keep_alive(&self) -> bool {
+        match self.headers.get(HeaderVariant::Connection) {
+            Some(Header::Connection(tokens)) if tokens.iter().any(|token| token == "close") => {
+                false
+            }
+            Some(Header::Connection(tokens))
+                if tokens.iter().any(|token| token == "keep-alive") =>
+            {
+                true
             }
+            _ => self.version == "HTTP/1.1",
         }
+    }
+
+    // Parses only the request line and headers, leaving the body to be read separately
+    pub fn from_head(head: &[u8], max_headers: usize) -> Result<Self, ReqError> {
+        let (method, uri, version, headers) = parse_head(head, max_headers)?;
+        Ok(HTTPRequest {
+            method,
+            uri,
+            version,
+            headers,
+            body: None,
+            trailers: None,
+            peer: None,
+        })
+    }
+
+    // Parses a request, rejecting it if it carries more than max_headers header lines
+    // or its body decodes to more than max_body bytes
+    pub fn try_from_with_limit(
+        req: &[u8],
+        max_headers: usize,
+        max_body: usize,
+    ) -> Result<Self, ReqError> {
+        let head_len = head_length(req).ok_or(ReqError::ContentLenError)?;
+        let (header_lines, body) = req.split_at(head_len);
+        let (method, uri, version, mut headers) = parse_head(header_lines, max_headers)?;
+
+        let mut trailers = None;
+        let body = if let BodyLength::Chunked = body_length(&headers) {
+            let (body, sent, _) = decode_chunked(body, max_headers)?;
+            trailers = sent;
+            body
+        } else {
+            if let Some(len) = headers.get(HeaderVariant::ContentLength) {
+                let Header::ContentLength(len) = len else {
+                    return Err(ReqError::ContentLenError);
+                };
+                if len != body.len() {
+                    return Err(ReqError::ContentLenError);
+                }
+            }
+            body.to_vec()
+        };
+        let body = if body.is_empty() {
+            body
+        } else {
+            decode_content(&mut headers, body, max_body)?
+        };
 
         Ok(HTTPRequest {
             method,
             uri,
             version,
             headers,
-            body: if body.len() != 0 {
-                Some(body.to_vec())
-            } else {
-                None
-            },
+            body: if !body.is_empty() { Some(body) } else { None },
+            trailers,
+            peer: None,
         })
     }
 }
@@ -142,8 +515,39 @@ mod test {
 
         assert_eq!(
             HTTPRequest::try_from(&request_buf).unwrap_err(),
-            ReqError::ParseError("Invalid header line".into())
+            ReqError::BadRequestLine
+        );
+    }
+
+    #[test]
+    fn test_request_line_needs_three_tokens() {
+        for line in [
+            "GET  HTTP/1.0",
+            "GET  / HTTP/1.0",
+            "GET / HTTP/1.0 ",
+            " GET / HTTP/1.0",
+            "GET /",
+            "GET / HTTP/1.0 extra",
+        ] {
+            let request = format!("{}\r\nHost: localhost\r\n\r\n", line);
+            assert_eq!(
+                HTTPRequest::try_from(&request.into_bytes()).unwrap_err(),
+                ReqError::BadRequestLine,
+                "{:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_asterisk_form_only_for_options() {
+        let request = |method: &str| format!("{} * HTTP/1.1\r\nHost: localhost\r\n\r\n", method);
+        assert_eq!(
+            HTTPRequest::try_from(&request("GET").into_bytes()).unwrap_err(),
+            ReqError::BadRequestLine
         );
+        let req = HTTPRequest::try_from(&request("OPTIONS").into_bytes()).unwrap();
+        assert_eq!(req.uri, "*");
     }
 
     #[test]
@@ -186,7 +590,235 @@ mod test {
 
         assert_eq!(
             HTTPRequest::try_from(&request_buf).unwrap_err(),
-            ReqError::ParseError("Invalid header encoding".into())
+            ReqError::BadEncoding
+        );
+    }
+
+    #[test]
+    fn test_chunked_body_keeps_trailers_apart() {
+        let request_buf = "POST /upload HTTP/1.1\r\n\
+        Host: webserver.christianbingman.com\r\n\
+        Transfer-Encoding: chunked\r\n\r\n\
+        4\r\nWiki\r\n\
+        5\r\npedia\r\n\
+        0\r\n\
+        X-Checksum: 5f2b\r\n\r\n"
+            .as_bytes()
+            .to_vec();
+
+        let req = HTTPRequest::try_from(&request_buf).unwrap();
+        assert_eq!(req.body, Some(b"Wikipedia".to_vec()));
+        assert_eq!(
+            req.trailers
+                .and_then(|trailers| trailers.get_generic("X-Checksum")),
+            Some("5f2b".to_string())
+        );
+    }
+
+    #[test]
+    fn test_decodes_gzipped_body() {
+        let body = compression::compress_bytes(b"{\"id\": 1}", &ContentEncoding::GZIP, 6).unwrap();
+        let mut request_buf = format!(
+            "POST /upload HTTP/1.1\r\n\
+            Host: webserver.christianbingman.com\r\n\
+            Content-Encoding: gzip\r\n\
+            Content-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        request_buf.extend_from_slice(&body);
+
+        let req = HTTPRequest::try_from(&request_buf).unwrap();
+        assert_eq!(req.body, Some(b"{\"id\": 1}".to_vec()));
+        assert_eq!(req.headers.get(HeaderVariant::ContentEncoding), None);
+        assert_eq!(
+            req.headers.get(HeaderVariant::ContentLength),
+            Some(Header::ContentLength(9))
+        );
+    }
+
+    #[test]
+    fn test_identity_body_encoding_is_a_no_op() {
+        let request_buf = "POST /upload HTTP/1.1\r\n\
+        Host: webserver.christianbingman.com\r\n\
+        Content-Encoding: identity\r\n\
+        Content-Length: 4\r\n\r\n\
+        Wiki"
+            .as_bytes()
+            .to_vec();
+
+        let req = HTTPRequest::try_from(&request_buf).unwrap();
+        assert_eq!(req.body, Some(b"Wiki".to_vec()));
+        assert_eq!(req.headers.get(HeaderVariant::ContentEncoding), None);
+    }
+
+    #[test]
+    fn test_decoded_body_limit() {
+        let body = compression::compress_bytes(&[b'a'; 4096], &ContentEncoding::GZIP, 9).unwrap();
+        let mut request_buf = format!(
+            "POST /upload HTTP/1.1\r\n\
+            Host: webserver.christianbingman.com\r\n\
+            Content-Encoding: gzip\r\n\
+            Content-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        request_buf.extend_from_slice(&body);
+
+        assert_eq!(
+            HTTPRequest::try_from_with_limit(&request_buf, DEFAULT_MAX_HEADERS, 1024).unwrap_err(),
+            ReqError::BodyTooLarge(1024)
+        );
+        let req =
+            HTTPRequest::try_from_with_limit(&request_buf, DEFAULT_MAX_HEADERS, 4096).unwrap();
+        assert_eq!(req.body.map(|body| body.len()), Some(4096));
+    }
+
+    #[test]
+    fn test_unsupported_body_encoding() {
+        let request_buf = "POST /upload HTTP/1.1\r\n\
+        Host: webserver.christianbingman.com\r\n\
+        Content-Encoding: compress\r\n\
+        Content-Length: 4\r\n\r\n\
+        Wiki"
+            .as_bytes()
+            .to_vec();
+
+        assert_eq!(
+            HTTPRequest::try_from(&request_buf).unwrap_err(),
+            ReqError::UnsupportedEncodingErr("compress".into())
+        );
+    }
+
+    #[test]
+    fn test_incomplete_chunked_body() {
+        let request_buf = "POST /upload HTTP/1.1\r\n\
+        Host: webserver.christianbingman.com\r\n\
+        Transfer-Encoding: chunked\r\n\r\n\
+        4\r\nWiki\r\n\
+        0\r\n\
+        X-Checksum: 5f2b\r\n"
+            .as_bytes()
+            .to_vec();
+
+        assert_eq!(
+            HTTPRequest::try_from(&request_buf).unwrap_err(),
+            ReqError::ContentLenError
+        );
+    }
+
+    #[test]
+    fn test_rejects_content_length_with_transfer_encoding() {
+        let request_buf = "POST /upload HTTP/1.1\r\n\
+        Host: webserver.christianbingman.com\r\n\
+        Content-Length: 4\r\n\
+        Transfer-Encoding: chunked\r\n\r\n\
+        4\r\nWiki\r\n\
+        0\r\n\r\n"
+            .as_bytes()
+            .to_vec();
+
+        assert_eq!(
+            HTTPRequest::try_from(&request_buf).unwrap_err(),
+            ReqError::ConflictingLength
+        );
+        let head_len = head_length(&request_buf).unwrap();
+        assert_eq!(
+            expected_body(&request_buf[..head_len], DEFAULT_MAX_HEADERS),
+            Err(ReqError::ConflictingLength)
+        );
+    }
+
+    #[test]
+    fn test_repeated_content_length() {
+        let request = |lengths: &str| {
+            format!(
+                "POST /upload HTTP/1.0\r\nHost: localhost\r\n{}\r\nWiki",
+                lengths
+            )
+            .into_bytes()
+        };
+
+        assert_eq!(
+            HTTPRequest::try_from(&request("Content-Length: 4\r\nContent-Length: 40\r\n"))
+                .unwrap_err(),
+            ReqError::BadHeader(HeaderErr::ConflictingContentLength(4, 40))
+        );
+        let req =
+            HTTPRequest::try_from(&request("Content-Length: 4\r\nContent-Length: 4\r\n")).unwrap();
+        assert_eq!(req.body, Some(b"Wiki".to_vec()));
+    }
+
+    #[test]
+    fn test_expected_body_from_head() {
+        let request_buf = "POST /upload HTTP/1.0\r\n\
+        Host: webserver.christianbingman.com\r\n\
+        Content-Length: 9\r\n\r\n\
+        Wiki"
+            .as_bytes()
+            .to_vec();
+
+        let head_len = head_length(&request_buf).unwrap();
+        assert_eq!(&request_buf[head_len..], b"Wiki");
+        assert_eq!(
+            expected_body(&request_buf[..head_len], DEFAULT_MAX_HEADERS),
+            Ok(BodyLength::Exact(9))
+        );
+
+        let chunked = b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(
+            expected_body(chunked, DEFAULT_MAX_HEADERS),
+            Ok(BodyLength::Chunked)
+        );
+        let mut framing = ChunkedFraming::default();
+        assert_eq!(framing.end(b"4\r\nWi"), None);
+        assert_eq!(framing.end(b"4\r\nWiki\r\n"), None);
+        assert_eq!(framing.end(b"4\r\nWiki\r\n0\r\nX-Sum: 1\r\n"), None);
+        assert_eq!(
+            framing.end(b"4\r\nWiki\r\n0\r\nX-Sum: 1\r\n\r\nGET /"),
+            Some(24)
+        );
+        let mut framing = ChunkedFraming::default();
+        assert_eq!(framing.end(b"zz\r\n"), Some(4));
+    }
+
+    #[test]
+    fn test_body_reader_pulls_remaining_bytes() {
+        let mut rest: &[u8] = b"pedia and more";
+        let mut reader = BodyReader::new(b"Wiki".to_vec(), &mut rest, 9);
+        let mut body = String::new();
+        reader.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "Wikipedia");
+        assert!(reader.is_done());
+    }
+
+    #[test]
+    fn test_body_reader_fails_on_short_body() {
+        let mut rest: &[u8] = b"pe";
+        let mut reader = BodyReader::new(b"Wiki".to_vec(), &mut rest, 9);
+        let mut body = Vec::new();
+        assert_eq!(
+            reader.read_to_end(&mut body).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert!(!reader.is_done());
+    }
+
+    #[test]
+    fn test_too_many_headers() {
+        let request_buf = format!(
+            "GET / HTTP/1.0\r\n{}\r\n",
+            (0..20)
+                .map(|i| format!("X-Field-{}: {}\r\n", i, i))
+                .collect::<String>()
+        )
+        .as_bytes()
+        .to_vec();
+
+        assert!(HTTPRequest::try_from_with_limit(&request_buf, 20, DEFAULT_MAX_BODY).is_ok());
+        assert_eq!(
+            HTTPRequest::try_from_with_limit(&request_buf, 10, DEFAULT_MAX_BODY).unwrap_err(),
+            ReqError::TooManyHeaders(10)
         );
     }
 }
@@ -1,6 +1,13 @@
-use core::str;
+use std::io::{self, Write};
 
-use super::{headers::Headers, result_codes::ResultCode};
+use super::{
+    headers::{Header, HeaderVariant, Headers},
+    request::head_length,
+    result_codes::ResultCode,
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidResponseErr;
 
 #[derive(Debug, Clone)]
 pub struct HTTPResponse {
@@ -8,6 +15,7 @@ pub struct HTTPResponse {
     pub status: ResultCode,
     pub headers: Headers,
     pub body: Option<Vec<u8>>,
+    pub trailers: Option<Headers>,
 }
 
 impl HTTPResponse {
@@ -22,22 +30,108 @@ impl HTTPResponse {
             status,
             headers,
             body,
+            trailers: None,
         }
     }
 
-    pub fn as_bytes(&mut self) -> Vec<u8> {
-        let mut bytes: Vec<u8> = Vec::new();
-        let mut response: String =
+    // Adds fields sent after the body, e.g. a checksum only known once it's built.
+    // Only a chunked HTTP/1.1 body can carry them, HTTP/1.0 responses leave them out.
+    pub fn with_trailers(mut self, trailers: Headers) -> Self {
+        self.trailers = Some(trailers);
+        self
+    }
+
+    // Whether the body goes out chunked, which is only done to carry trailers
+    pub fn is_chunked(&self) -> bool {
+        self.trailers.is_some() && self.body.is_some() && self.version != "HTTP/1.0"
+    }
+
+    // Writes the head, then the body, each with its own write. Whether they reach
+    // the client together or one by one is down to how out is buffered.
+    pub fn write_to(&mut self, out: &mut impl Write) -> io::Result<usize> {
+        let chunked = self.is_chunked();
+        if chunked {
+            let names = self.trailers.as_ref().map(Headers::field_names);
+            self.headers.remove_named("Content-Length");
+            self.headers.remove_named("Trailer");
+            self.headers
+                .set(Header::TransferEncoding("chunked".to_string()));
+            self.headers.set(Header::Generic((
+                "Trailer".to_string(),
+                names.unwrap_or_default().join(", "),
+            )));
+        }
+        let mut head: String =
             format!("{} {}\r\n", self.version, Into::<String>::into(self.status));
-        response += &self.headers.to_string();
-        bytes.append(&mut response.as_bytes().to_vec());
-        if let Some(body) = &mut self.body {
-            bytes.append(body);
+        head += &self.headers.to_string();
+        out.write_all(head.as_bytes())?;
+        let mut written = head.len();
+        if let Some(body) = &self.body {
+            if chunked {
+                // The whole body as one chunk, then the last chunk and the trailers
+                let mut framed = Vec::with_capacity(body.len() + 32);
+                if !body.is_empty() {
+                    framed.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+                    framed.extend_from_slice(body);
+                    framed.extend_from_slice(b"\r\n");
+                }
+                framed.extend_from_slice(b"0\r\n");
+                let trailers = self.trailers.as_ref().map(Headers::to_string);
+                framed.extend_from_slice(trailers.unwrap_or_default().as_bytes());
+                out.write_all(&framed)?;
+                written += framed.len();
+            } else {
+                out.write_all(body)?;
+                written += body.len();
+            }
         }
+        Ok(written)
+    }
+
+    pub fn as_bytes(&mut self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        // Writing into memory can't fail
+        let _ = self.write_to(&mut bytes);
         bytes
     }
 }
 
+// Reads back a response as written by as_bytes, e.g. one stored in a cache.
+// Chunked responses aren't supported.
+impl TryFrom<&[u8]> for HTTPResponse {
+    type Error = InvalidResponseErr;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let head_len = head_length(value).ok_or(InvalidResponseErr)?;
+        let (head, body) = value.split_at(head_len);
+        let head = std::str::from_utf8(head).map_err(|_| InvalidResponseErr)?;
+        let (status_line, fields) = head.split_once("\r\n").ok_or(InvalidResponseErr)?;
+        let mut parts = status_line.splitn(3, ' ');
+        let version = parts.next().ok_or(InvalidResponseErr)?;
+        let status = parts
+            .next()
+            .and_then(|code| code.parse::<usize>().ok())
+            .and_then(|code| ResultCode::try_from(code).ok())
+            .ok_or(InvalidResponseErr)?;
+        let headers = Headers::try_from(fields).map_err(|_| InvalidResponseErr)?;
+        if let Some(Header::ContentLength(len)) = headers.get(HeaderVariant::ContentLength) {
+            if len != body.len() {
+                return Err(InvalidResponseErr);
+            }
+        }
+        Ok(HTTPResponse::new(
+            version,
+            status,
+            headers,
+            if body.is_empty() {
+                None
+            } else {
+                Some(body.to_vec())
+            },
+        ))
+    }
+}
+
 impl std::fmt::Display for HTTPResponse {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!(
@@ -47,8 +141,46 @@ impl std::fmt::Display for HTTPResponse {
         ))?;
         f.write_str(&self.headers.to_string())?;
         if let Some(body) = &self.body {
-            f.write_str(str::from_utf8(body).map_err(|_| std::fmt::Error)?)?;
+            f.write_str(&String::from_utf8_lossy(body))?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_back_written_response() {
+        let mut headers = Headers::new();
+        headers.set(Header::ContentType("text/plain".to_string()));
+        headers.set(Header::ContentLength(5));
+        let mut resp =
+            HTTPResponse::new("HTTP/1.0", ResultCode::OK, headers, Some(b"hello".to_vec()));
+        let bytes = resp.as_bytes();
+
+        let read = HTTPResponse::try_from(bytes.as_slice()).unwrap();
+        assert_eq!(read.version, "HTTP/1.0");
+        assert_eq!(read.status, ResultCode::OK);
+        assert_eq!(
+            read.headers.get(HeaderVariant::ContentType),
+            Some(Header::ContentType("text/plain".to_string()))
+        );
+        assert_eq!(read.body, Some(b"hello".to_vec()));
+        assert!(HTTPResponse::try_from(&bytes[..bytes.len() - 1]).is_err());
+        assert!(HTTPResponse::try_from(&b"HTTP/1.0 299 Odd\r\n\r\n"[..]).is_err());
+    }
+
+    #[test]
+    fn writes_custom_status_line() {
+        let mut resp = HTTPResponse::new(
+            "HTTP/1.0",
+            ResultCode::Custom(207, "Multi-Status"),
+            Headers::new(),
+            None,
+        );
+        let bytes = resp.as_bytes();
+        assert!(bytes.starts_with(b"HTTP/1.0 207 Multi-Status\r\n"));
+    }
+}
@@ -2,10 +2,14 @@ pub struct ResultCodeConversionError;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum ResultCode {
+    EarlyHints,
     OK,
     Created,
     Accepted,
+    NonAuthoritativeInformation,
     NoContent,
+    ResetContent,
+    PartialContent,
     MultipleChoices,
     MovedPermanently,
     MovedTemporarily,
@@ -14,42 +18,65 @@ pub enum ResultCode {
     Unauthorized,
     Forbidden,
     NotFound,
+    MethodNotAllowed,
+    NotAcceptable,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    RequestHeaderFieldsTooLarge,
     InternalServerError,
     NotImplemented,
     BadGateway,
-    ServiceUnavailable
+    ServiceUnavailable,
+    HTTPVersionNotSupported,
+    // Any other status, with its reason phrase
+    Custom(u16, &'static str)
 }
 
-impl Into<String> for ResultCode {
-    fn into(self) -> String {
+impl ResultCode {
+    pub fn reason_phrase(&self) -> &'static str {
         match self {
-            Self::OK => "200 OK",
-            Self::Created => "201 Created",
-            Self::Accepted => "202 Accepted",
-            Self::NoContent => "204 No Content",
-            Self::MultipleChoices => "300 Multiple Choices",
-            Self::MovedPermanently => "301 Moved Permanently",
-            Self::MovedTemporarily => "302 Moved Temporarily",
-            Self::NotModified => "304 Not Modified",
-            Self::BadRequest => "400 Bad Request",
-            Self::Unauthorized => "401 Unauthorized",
-            Self::Forbidden => "403 Forbidden",
-            Self::NotFound => "404 Not Found",
-            Self::InternalServerError => "500 Internal Server Error",
-            Self::NotImplemented => "501 Not Implemented",
-            Self::BadGateway => "502 Bad Gateway",
-            Self::ServiceUnavailable => "503 Service Unavailable"
-        }.to_string()
+            Self::EarlyHints => "Early Hints",
+            Self::OK => "OK",
+            Self::Created => "Created",
+            Self::Accepted => "Accepted",
+            Self::NonAuthoritativeInformation => "Non-Authoritative Information",
+            Self::NoContent => "No Content",
+            Self::ResetContent => "Reset Content",
+            Self::PartialContent => "Partial Content",
+            Self::MultipleChoices => "Multiple Choices",
+            Self::MovedPermanently => "Moved Permanently",
+            Self::MovedTemporarily => "Moved Temporarily",
+            Self::NotModified => "Not Modified",
+            Self::BadRequest => "Bad Request",
+            Self::Unauthorized => "Unauthorized",
+            Self::Forbidden => "Forbidden",
+            Self::NotFound => "Not Found",
+            Self::MethodNotAllowed => "Method Not Allowed",
+            Self::NotAcceptable => "Not Acceptable",
+            Self::PayloadTooLarge => "Payload Too Large",
+            Self::UnsupportedMediaType => "Unsupported Media Type",
+            Self::RangeNotSatisfiable => "Range Not Satisfiable",
+            Self::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            Self::InternalServerError => "Internal Server Error",
+            Self::NotImplemented => "Not Implemented",
+            Self::BadGateway => "Bad Gateway",
+            Self::ServiceUnavailable => "Service Unavailable",
+            Self::HTTPVersionNotSupported => "HTTP Version Not Supported",
+            Self::Custom(_, reason) => reason
+        }
     }
-}
 
-impl Into<usize> for ResultCode {
-    fn into(self) -> usize {
+    pub fn code(&self) -> u16 {
         match self {
+            Self::EarlyHints => 103,
             Self::OK => 200,
             Self::Created => 201,
             Self::Accepted => 202,
+            Self::NonAuthoritativeInformation => 203,
             Self::NoContent => 204,
+            Self::ResetContent => 205,
+            Self::PartialContent => 206,
             Self::MultipleChoices => 300,
             Self::MovedPermanently => 301,
             Self::MovedTemporarily => 302,
@@ -58,22 +85,46 @@ impl Into<usize> for ResultCode {
             Self::Unauthorized => 401,
             Self::Forbidden => 403,
             Self::NotFound => 404,
+            Self::MethodNotAllowed => 405,
+            Self::NotAcceptable => 406,
+            Self::PayloadTooLarge => 413,
+            Self::UnsupportedMediaType => 415,
+            Self::RangeNotSatisfiable => 416,
+            Self::RequestHeaderFieldsTooLarge => 431,
             Self::InternalServerError => 500,
             Self::NotImplemented => 501,
             Self::BadGateway => 502,
-            Self::ServiceUnavailable => 503
+            Self::ServiceUnavailable => 503,
+            Self::HTTPVersionNotSupported => 505,
+            Self::Custom(code, _) => *code
         }
     }
 }
 
+impl From<ResultCode> for String {
+    fn from(code: ResultCode) -> String {
+        format!("{} {}", code.code(), code.reason_phrase())
+    }
+}
+
+impl From<ResultCode> for usize {
+    fn from(code: ResultCode) -> usize {
+        code.code().into()
+    }
+}
+
 impl TryFrom<usize> for ResultCode {
     type Error = ResultCodeConversionError;
     fn try_from(value: usize) -> Result<Self, Self::Error> {
         match value {
+            103 => Ok(Self::EarlyHints),
             200 => Ok(Self::OK),
             201 => Ok(Self::Created),
             202 => Ok(Self::Accepted),
+            203 => Ok(Self::NonAuthoritativeInformation),
             204 => Ok(Self::NoContent),
+            205 => Ok(Self::ResetContent),
+            206 => Ok(Self::PartialContent),
             300 => Ok(Self::MultipleChoices),
             301 => Ok(Self::MovedPermanently),
             302 => Ok(Self::MovedTemporarily),
@@ -82,11 +133,38 @@ impl TryFrom<usize> for ResultCode {
             401 => Ok(Self::Unauthorized),
             403 => Ok(Self::Forbidden),
             404 => Ok(Self::NotFound),
+            405 => Ok(Self::MethodNotAllowed),
+            406 => Ok(Self::NotAcceptable),
+            413 => Ok(Self::PayloadTooLarge),
+            415 => Ok(Self::UnsupportedMediaType),
+            416 => Ok(Self::RangeNotSatisfiable),
+            431 => Ok(Self::RequestHeaderFieldsTooLarge),
             500 => Ok(Self::InternalServerError),
             501 => Ok(Self::NotImplemented),
             502 => Ok(Self::BadGateway),
             503 => Ok(Self::ServiceUnavailable),
+            505 => Ok(Self::HTTPVersionNotSupported),
             _ => Err(ResultCodeConversionError)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_code_and_reason_phrase() {
+        assert_eq!(ResultCode::NotFound.code(), 404);
+        assert_eq!(ResultCode::NotFound.reason_phrase(), "Not Found");
+        assert_eq!(Into::<String>::into(ResultCode::NotFound), "404 Not Found");
+    }
+
+    #[test]
+    fn serializes_custom_status() {
+        let status = ResultCode::Custom(207, "Multi-Status");
+        assert_eq!(status.code(), 207);
+        assert_eq!(Into::<String>::into(status), "207 Multi-Status");
+        assert_eq!(Into::<String>::into(ResultCode::ResetContent), "205 Reset Content");
+    }
+}
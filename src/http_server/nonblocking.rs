@@ -0,0 +1,419 @@
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use super::{listen, HTTPServer, InFlight};
+use crate::http10::request::{self, BodyLength};
+
+// Same idle timeout as the blocking server's read timeout
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How soon a raised drain flag stops the accept loop
+const DRAIN_CHECK: Duration = Duration::from_millis(50);
+
+// Stands in for the connection while a request is answered off the runtime, handing
+// every write over to be sent right away so a 103 goes out ahead of the response.
+// The whole body is read beforehand, so there is nothing left to read.
+struct Outgoing(mpsc::UnboundedSender<Vec<u8>>);
+
+impl Read for Outgoing {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Write for Outgoing {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S> HTTPServer<S>
+where
+    S: Send + Sync + 'static,
+{
+    /// Serves connections as tasks on a tokio runtime instead of a thread each, so
+    /// idle connections cost no threads. Parsing and handlers are the same as with
+    /// `serve_forever`, handlers run on tokio's blocking pool. The server class,
+    /// connection deadline and accept rate only apply to `serve_forever`, the
+    /// connection limits, keepalive probes, early hints and drain flag to both.
+    pub fn serve_async(self) {
+        let listener = listen(&self.opts).expect("Unable to bind!");
+        self.serve_async_on(listener);
+    }
+
+    fn serve_async_on(self, listener: std::net::TcpListener) {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("Unable to start the async runtime");
+        let server = Arc::new(self);
+        runtime.block_on(async move {
+            let listener = listener
+                .set_nonblocking(true)
+                .and_then(|_| TcpListener::from_std(listener))
+                .expect("Unable to register the listener");
+            match listener.local_addr() {
+                Ok(addr) => log::info!("Started async listener on {}", addr),
+                Err(_) => log::info!("Started async listener"),
+            }
+            while !server.draining() {
+                // Wakes up now and then to notice the drain flag
+                let accepted = match tokio::time::timeout(DRAIN_CHECK, listener.accept()).await {
+                    Ok(accepted) => accepted,
+                    Err(_) => continue,
+                };
+                match accepted {
+                    Ok((stream, peer)) if server.admit() => {
+                        // Counted before the task starts so draining can't miss it
+                        let in_flight = InFlight::start(&server.in_flight);
+                        let server = Arc::clone(&server);
+                        tokio::spawn(async move {
+                            Arc::clone(&server).handle_async(stream, peer).await;
+                            server.connections.fetch_sub(1, Ordering::SeqCst);
+                            drop(in_flight);
+                        });
+                    }
+                    Ok((stream, _)) => {
+                        let server = Arc::clone(&server);
+                        tokio::spawn(async move { server.turn_away(stream).await });
+                    }
+                    Err(e) => log::error!("Failed to establish a connection: {}", e),
+                }
+            }
+
+            log::info!("Draining, no longer accepting connections");
+            drop(listener);
+            while server.in_flight.load(Ordering::SeqCst) > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            log::info!("Drained, no connections left in flight");
+        });
+    }
+
+    // Mirrors handle_stream, with the reads and writes on the runtime
+    async fn handle_async(self: Arc<Self>, mut stream: TcpStream, peer: SocketAddr) {
+        let _slot = match self.ip_slot(peer.ip()) {
+            Ok(slot) => slot,
+            Err(()) => return self.turn_away(stream).await,
+        };
+        self.set_keepalive(socket2::SockRef::from(&stream));
+        if let Err(err) = stream.set_nodelay(self.opts.write_buffer == 0) {
+            log::warn!("Unable to set TCP_NODELAY: {}", err);
+        }
+        self.set_buffers(socket2::SockRef::from(&stream));
+        let remote = peer.to_string();
+        let max_requests = self.opts.max_requests_per_connection;
+        let mut served = 0;
+        // Bytes of pipelined requests that arrived along with the one before
+        let mut pending = Vec::new();
+        loop {
+            let request = self.read_request_async(&mut stream, &mut pending).await;
+            if request.is_empty() {
+                // Client closed the connection or sat idle between requests
+                return;
+            }
+            served += 1;
+            let last = max_requests != 0 && served >= max_requests;
+            let server = Arc::clone(&self);
+            let client = remote.clone();
+            let (sent, mut outgoing) = mpsc::unbounded_channel();
+            let answered = tokio::task::spawn_blocking(move || {
+                server.handle_request(
+                    &mut Outgoing(sent),
+                    Some(peer),
+                    &request,
+                    None,
+                    &client,
+                    !last,
+                )
+            });
+            // Ends once the handler is done and has dropped its end
+            while let Some(bytes) = outgoing.recv().await {
+                // Clients aborting a download is routine
+                if let Err(err) = stream.write_all(&bytes).await {
+                    log::debug!("Client {} went away mid-response: {}", remote, err);
+                    return;
+                }
+            }
+            match answered.await {
+                Ok(true) => (),
+                Ok(false) => return,
+                Err(err) => {
+                    log::error!("Handler for {} failed: {}", remote, err);
+                    return;
+                }
+            }
+        }
+    }
+
+    // Tells a client turned away at the door to come back later, like service_unavailable
+    async fn turn_away(&self, mut stream: TcpStream) {
+        let _ = stream.write_all(&self.unavailable_response()).await;
+        let _ = stream.shutdown().await;
+    }
+
+    // Reads one request, body included however large, since streaming handlers get
+    // theirs from memory here. Like read_request, bytes past it are left in pending.
+    async fn read_request_async(&self, stream: &mut TcpStream, pending: &mut Vec<u8>) -> Vec<u8> {
+        let mut request = std::mem::take(pending);
+        let mut buf = [0u8; 4096];
+        let mut body: Option<(usize, BodyLength)> = None;
+        let mut chunked = request::ChunkedFraming::default();
+        loop {
+            if body.is_none() {
+                if let Some(head_len) = request::head_length(&request) {
+                    match request::expected_body(&request[..head_len], self.opts.max_headers) {
+                        Ok(length) => body = Some((head_len, length)),
+                        // Leave reporting the error to the parser
+                        Err(_) => break,
+                    }
+                }
+            }
+            let end = match &body {
+                Some((head_len, BodyLength::Exact(len))) if request.len() >= head_len + len => {
+                    Some(head_len + len)
+                }
+                Some((head_len, BodyLength::Chunked)) => {
+                    chunked.end(&request[*head_len..]).map(|len| head_len + len)
+                }
+                _ => None,
+            };
+            if let Some(end) = end {
+                *pending = request.split_off(end);
+                break;
+            }
+            match tokio::time::timeout(READ_TIMEOUT, stream.read(&mut buf)).await {
+                Ok(Ok(0)) | Ok(Err(_)) | Err(_) => break,
+                Ok(Ok(n)) => request.extend_from_slice(&buf[..n]),
+            }
+        }
+        request
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::http10::headers::Headers;
+    use crate::http10::response::HTTPResponse;
+    use crate::http10::result_codes::ResultCode;
+    use crate::http_server::HTTPServerClass;
+    use crate::Opts;
+
+    fn read_response(client: &mut std::net::TcpStream) -> String {
+        let mut resp = Vec::new();
+        let mut buf = [0u8; 4096];
+        while let Ok(n) = client.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            resp.extend_from_slice(&buf[..n]);
+            if let Some(head_len) = request::head_length(&resp) {
+                let head = String::from_utf8_lossy(&resp[..head_len]).to_lowercase();
+                let len = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .map_or(0, |len| len.trim().parse().unwrap());
+                if resp.len() >= head_len + len {
+                    break;
+                }
+            }
+        }
+        String::from_utf8_lossy(&resp).to_string()
+    }
+
+    #[test]
+    fn serves_kept_alive_connection() {
+        let server = HTTPServer::new(HTTPServerClass::Simple, Opts::default(), Arc::new(()), None);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || server.serve_async_on(listener));
+
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        for _ in 0..2 {
+            client
+                .write_all(b"GET /Cargo.toml HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+                .unwrap();
+            let resp = read_response(&mut client);
+            assert!(resp.starts_with("HTTP/1.0 200 OK\r\n"));
+            assert!(resp.contains("Connection: keep-alive\r\n"));
+            assert!(resp.contains("[package]"));
+        }
+        client
+            .write_all(b"GET /missing HTTP/1.0\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        assert!(read_response(&mut client).starts_with("HTTP/1.0 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn serves_pipelined_requests() {
+        let server = HTTPServer::new(HTTPServerClass::Simple, Opts::default(), Arc::new(()), None);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || server.serve_async_on(listener));
+
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+            .write_all(
+                b"GET /Cargo.toml HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                GET /missing HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).unwrap();
+        assert!(resp.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(resp.contains("HTTP/1.0 404 Not Found\r\n"));
+    }
+
+    // Serves on a fresh listener in the background, returning its address
+    fn serve<S: Send + Sync + 'static>(server: HTTPServer<S>) -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || server.serve_async_on(listener));
+        addr
+    }
+
+    #[test]
+    fn limits_connections_per_client() {
+        let opts = Opts {
+            max_connections_per_ip: 1,
+            ..Opts::default()
+        };
+        let addr = serve(HTTPServer::new(
+            HTTPServerClass::Simple,
+            opts,
+            Arc::new(()),
+            None,
+        ));
+        let _first = std::net::TcpStream::connect(addr).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        let mut second = std::net::TcpStream::connect(addr).unwrap();
+        second
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert!(read_response(&mut second).starts_with("HTTP/1.0 503 Service Unavailable\r\n"));
+    }
+
+    #[test]
+    fn limits_outstanding_connections() {
+        let opts = Opts {
+            max_connections: 1,
+            ..Opts::default()
+        };
+        let addr = serve(HTTPServer::new(
+            HTTPServerClass::Simple,
+            opts,
+            Arc::new(()),
+            None,
+        ));
+        let _first = std::net::TcpStream::connect(addr).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        let mut second = std::net::TcpStream::connect(addr).unwrap();
+        second
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert!(read_response(&mut second).starts_with("HTTP/1.0 503 Service Unavailable\r\n"));
+    }
+
+    #[test]
+    fn sends_early_hints_before_the_handler_is_done() {
+        let opts = Opts {
+            protocol: "HTTP/1.1".to_string(),
+            early_hints: vec![("/".to_string(), "</style.css>; rel=preload".to_string())],
+            ..Opts::default()
+        };
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let server = HTTPServer::new(
+            HTTPServerClass::Simple,
+            opts,
+            Arc::new(Mutex::new(released)),
+            Some(Box::new(
+                |_, opts, released: &Arc<Mutex<std::sync::mpsc::Receiver<()>>>| {
+                    // Only released once the client has the 103
+                    let waited = released
+                        .lock()
+                        .unwrap()
+                        .recv_timeout(Duration::from_secs(5));
+                    let body = if waited.is_ok() {
+                        "released"
+                    } else {
+                        "timed out"
+                    };
+                    HTTPResponse::new(
+                        opts.protocol.clone(),
+                        ResultCode::OK,
+                        Headers::new(),
+                        Some(body.as_bytes().to_vec()),
+                    )
+                },
+            )),
+        );
+        let mut client = std::net::TcpStream::connect(serve(server)).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut hints = Vec::new();
+        let mut byte = [0u8];
+        while !hints.ends_with(b"\r\n\r\n") {
+            client.read_exact(&mut byte).unwrap();
+            hints.push(byte[0]);
+        }
+        assert!(hints.starts_with(b"HTTP/1.1 103 Early Hints\r\n"));
+        release.send(()).unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(resp.ends_with("released"));
+    }
+
+    #[test]
+    fn drain_flag_stops_serving() {
+        let drain = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let server = HTTPServer::new(HTTPServerClass::Simple, Opts::default(), Arc::new(()), None)
+            .with_drain_flag(Arc::clone(&drain));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = std::thread::spawn(move || server.serve_async_on(listener));
+
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+            .write_all(b"GET /Cargo.toml HTTP/1.0\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        assert!(read_response(&mut client).starts_with("HTTP/1.0 200 OK\r\n"));
+        drain.store(true, std::sync::atomic::Ordering::SeqCst);
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !serving.is_finished() && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(serving.is_finished());
+        assert!(std::net::TcpStream::connect(addr).is_err());
+    }
+}
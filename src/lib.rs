@@ -1,10 +1,29 @@
+mod cache;
+pub mod compression;
 mod file;
-mod http10;
+pub mod http10;
 mod middleware;
-mod threadpool;
+mod reaper;
+#[cfg(test)]
+mod test_util;
+// Public for the benches only
+#[doc(hidden)]
+pub mod threadpool;
+mod throttle;
 mod util;
 
-#[derive(Debug, PartialEq)]
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+// The HTTP types work without a server, e.g. to parse captured traffic
+pub use crate::http10::headers::{Header, HeaderVariant, Headers};
+pub use crate::http10::methods::Method;
+pub use crate::http10::request::HTTPRequest;
+pub use crate::http10::response::HTTPResponse;
+pub use crate::http10::result_codes::ResultCode;
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct Opts {
     /// port to bind to
     pub port: u16,
@@ -15,53 +34,370 @@ pub struct Opts {
     /// directory to serve
     pub directory: String,
 
+    /// directories served instead of `directory` by Host, the port left off when it's the one bound to
+    pub vhosts: HashMap<String, String>,
+
+    /// list the contents of directories without an index file, otherwise answer 403
+    pub directory_listing: bool,
+
+    /// answer 404 for paths with a component starting with `.` (`.git/config`, `.env`) and leave them out of listings
+    pub deny_dotfiles: bool,
+
+    /// fall back to the one file whose name differs from the request only in case
+    pub case_insensitive: bool,
+
     /// protocol to use (supports http 1.0)
     pub protocol: String,
 
     /// Auth for basic authentication
     pub auth: Option<Auth>,
 
+    /// basic auth per path prefix, the longest matching prefix wins over `auth`
+    pub auth_rules: Vec<(String, Auth)>,
+
     /// compression ratio (0-9, default 6)
     pub ratio: u32,
+
+    /// serve queued connections newest-first instead of oldest-first
+    pub lifo: bool,
+
+    /// drop pooled connections that waited in the queue longer than this
+    pub queue_timeout: Option<Duration>,
+
+    /// stack size in bytes of the threads serving connections, None for the platform default
+    pub worker_stack_size: Option<usize>,
+
+    /// how long a stopping pooled server waits for busy workers before leaving them behind
+    pub shutdown_grace: Option<Duration>,
+
+    /// shut down connections still being served after this long, however busy the handler is
+    pub connection_deadline: Option<Duration>,
+
+    /// pooled connections queued or in flight before new ones get a 503 (0 = unlimited)
+    pub max_connections: usize,
+
+    /// connections accepted per second at most, bursts beyond it wait to be accepted rather than being refused
+    pub accept_rate: Option<u32>,
+
+    /// connections one peer address may have open at once before new ones get a 503 (0 = unlimited).
+    /// Counted when the connection is accepted, before X-Forwarded-For is read, so clients
+    /// behind one of `trusted_proxies` all share the proxy's allowance.
+    pub max_connections_per_ip: usize,
+
+    /// proxies whose X-Forwarded-For header is trusted for the client address
+    pub trusted_proxies: Vec<IpAddr>,
+
+    /// leave the Server header off every response, whoever set it
+    pub hide_server: bool,
+
+    /// include the reason a request failed to parse in 400 responses
+    pub verbose_errors: bool,
+
+    /// maximum number of header lines accepted per request
+    pub max_headers: usize,
+
+    /// bytes a request body with a Content-Encoding may decode to, larger ones get a 413
+    pub max_decoded_body: usize,
+
+    /// requests served on one kept-alive connection before closing it (0 = unlimited)
+    pub max_requests_per_connection: usize,
+
+    /// compress responses at all, off leaves every body unencoded whatever the client accepts
+    pub compression: bool,
+
+    /// gzip the files compression applies to into memory at startup and serve them from there
+    pub precompress: bool,
+
+    /// bytes of gzipped copies precompress keeps in memory at most, files past it are compressed per request
+    pub precompress_budget: usize,
+
+    /// whether responses of a MIME type (or `type/*`, `*/*` wildcard) get compressed
+    pub compression_policy: HashMap<String, bool>,
+
+    /// bodies smaller than this many bytes are sent uncompressed
+    pub min_compress_size: usize,
+
+    /// percentage of bytes compression has to save, below it the body goes out uncompressed
+    pub min_compression_gain: u8,
+
+    /// MIME types by file extension, consulted before the built in table
+    pub mime_overrides: HashMap<String, String>,
+
+    /// Cache-Control directives by file extension, MIME type or `type/*`, `*/*` for everything else
+    pub cache_control: HashMap<String, String>,
+
+    /// Link values sent in a 103 Early Hints ahead of GET and HEAD responses under a path prefix ("/" for all), to HTTP/1.1 clients only
+    pub early_hints: Vec<(String, String)>,
+
+    /// charset appended to text Content-Types, None to leave them bare
+    pub charset: Option<String>,
+
+    /// answer 406 when the file's MIME type matches none of the Accept ranges
+    pub strict_accept: bool,
+
+    /// TCP keepalive probes on accepted connections
+    pub tcp_keepalive: Option<TcpKeepalive>,
+
+    /// SO_RCVBUF of accepted connections in bytes, None leaves the OS default
+    pub recv_buffer: Option<usize>,
+
+    /// SO_SNDBUF of accepted connections in bytes, None leaves the OS default
+    pub send_buffer: Option<usize>,
+
+    /// bytes of a response gathered before they're written to the connection; 0 writes the head
+    /// and body out as soon as each is ready, with TCP_NODELAY so the kernel doesn't hold them back
+    pub write_buffer: usize,
+
+    /// let several server instances share the port (SO_REUSEPORT, unix only)
+    pub reuse_port: bool,
+
+    /// URI prefixes whose files may be replaced with PUT and removed with DELETE, everything else is read-only
+    pub writable_paths: Vec<String>,
+
+    /// answer empty files with 204 No Content instead of an empty 200
+    pub empty_as_no_content: bool,
+
+    /// answer TRACE by echoing the request back, credentials left out
+    pub trace: bool,
+
+    /// path the site is mounted at behind a proxy, stripped from request URIs and added to listing links
+    pub url_prefix: Option<String>,
+
+    /// content type of files whose extension isn't known or overridden
+    pub default_mime: String,
+
+    /// take the method of POST requests from X-HTTP-Method-Override, for clients that can only send GET and POST
+    pub method_override: bool,
+
+    /// methods refused with 405 whatever the path, OPTIONS and HEAD included
+    pub disabled_methods: Vec<http10::methods::Method>,
+
+    /// adopt this already listening socket instead of binding (socket activation, unix only)
+    pub listen_fd: Option<i32>,
+
+    /// origin allowed to make cross-origin requests ("*" for any), None disables CORS
+    pub cors_origin: Option<String>,
+
+    /// how long browsers may cache a preflight response
+    pub cors_max_age: Option<Duration>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Auth {
     pub username: String,
     pub password: String,
 }
 
+// Parses credentials in the form of username:password
+impl TryFrom<&str> for Auth {
+    type Error = String;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let Some((username, password)) = value.split_once(':') else {
+            return Err(format!(
+                "Invalid auth string {:?}, expected username:password",
+                value
+            ));
+        };
+        let auth = Auth {
+            username: username.to_string(),
+            password: password.to_string(),
+        };
+        auth.validate()?;
+        Ok(auth)
+    }
+}
+
+impl Auth {
+    fn validate(&self) -> Result<(), String> {
+        // Basic auth splits on the first colon, so it can't be part of the username
+        if self.username.is_empty() || self.username.contains(':') {
+            return Err(format!("Invalid auth username {:?}", self.username));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct TcpKeepalive {
+    /// idle time before the first probe is sent
+    pub idle: Duration,
+    /// time between unanswered probes
+    pub interval: Duration,
+}
+
 impl Default for Opts {
     fn default() -> Self {
         Opts {
             port: 8080,
             bind: "127.0.0.1".to_string(),
             directory: "./".to_string(),
+            vhosts: HashMap::new(),
+            directory_listing: true,
+            case_insensitive: false,
+            deny_dotfiles: true,
             protocol: "HTTP/1.0".to_string(),
             auth: None,
+            auth_rules: Vec::new(),
             ratio: 6,
+            lifo: false,
+            queue_timeout: None,
+            connection_deadline: None,
+            shutdown_grace: None,
+            worker_stack_size: None,
+            max_connections: 0,
+            max_connections_per_ip: 0,
+            accept_rate: None,
+            trusted_proxies: Vec::new(),
+            verbose_errors: false,
+            hide_server: false,
+            max_headers: 100,
+            max_decoded_body: http10::request::DEFAULT_MAX_BODY,
+            max_requests_per_connection: 100,
+            compression: true,
+            precompress: false,
+            precompress_budget: 64 * 1024 * 1024,
+            compression_policy: HashMap::from(
+                [
+                    ("text/*", true),
+                    ("application/json", true),
+                    ("application/javascript", true),
+                    ("application/xml", true),
+                    ("application/xhtml+xml", true),
+                    ("image/svg+xml", true),
+                    ("image/*", false),
+                ]
+                .map(|(mime, compress)| (mime.to_string(), compress)),
+            ),
+            min_compress_size: 0,
+            min_compression_gain: 0,
+            mime_overrides: HashMap::new(),
+            cache_control: HashMap::new(),
+            early_hints: Vec::new(),
+            charset: Some("utf-8".to_string()),
+            strict_accept: false,
+            tcp_keepalive: None,
+            write_buffer: 8 * 1024,
+            recv_buffer: None,
+            send_buffer: None,
+            reuse_port: false,
+            listen_fd: None,
+            trace: false,
+            method_override: false,
+            disabled_methods: Vec::new(),
+            default_mime: "application/octet-stream".to_string(),
+            url_prefix: None,
+            empty_as_no_content: false,
+            writable_paths: Vec::new(),
+            cors_origin: None,
+            cors_max_age: None,
+        }
+    }
+}
+
+impl Opts {
+    /// Checks the options for mistakes that would only surface once serving
+    pub fn validate(&self) -> Result<(), String> {
+        let directory = std::path::Path::new(&self.directory);
+        if !directory.is_dir() {
+            return Err(format!("{} is not a directory", self.directory));
+        }
+        if let Err(err) = std::fs::read_dir(directory) {
+            return Err(format!("Unable to read {}: {}", self.directory, err));
+        }
+        for (host, directory) in &self.vhosts {
+            if !std::path::Path::new(directory).is_dir() {
+                return Err(format!(
+                    "{} served for {} is not a directory",
+                    directory, host
+                ));
+            }
+        }
+        if self.ratio > 9 {
+            return Err("Compression ratio must be between 0-9".to_string());
         }
+        if self.min_compression_gain > 100 {
+            return Err("Minimum compression gain must be between 0-100".to_string());
+        }
+        if self.protocol != "HTTP/1.0" && self.protocol != "HTTP/1.1" {
+            return Err(format!("Unsupported protocol {}", self.protocol));
+        }
+        if std::net::ToSocketAddrs::to_socket_addrs(&(self.bind.as_str(), self.port)).is_err() {
+            return Err(format!("Invalid bind address {}", self.bind));
+        }
+        if let Some(auth) = &self.auth {
+            auth.validate()?;
+        }
+        for (prefix, auth) in &self.auth_rules {
+            if !prefix.starts_with('/') {
+                return Err(format!("Auth rule prefix {} must start with /", prefix));
+            }
+            auth.validate()?;
+        }
+        if let Some(prefix) = self.writable_paths.iter().find(|p| !p.starts_with('/')) {
+            return Err(format!("Writable path {} must start with /", prefix));
+        }
+        if let Some((prefix, _)) = self.early_hints.iter().find(|(p, _)| !p.starts_with('/')) {
+            return Err(format!("Early hints prefix {} must start with /", prefix));
+        }
+        if let Some(prefix) = self.url_prefix.as_ref().filter(|p| !p.starts_with('/')) {
+            return Err(format!("URL prefix {} must start with /", prefix));
+        }
+        // Configured values end up in response headers
+        let field_values = self
+            .mime_overrides
+            .values()
+            .chain(self.cache_control.values())
+            .chain(self.early_hints.iter().map(|(_, link)| link))
+            .chain(&self.charset)
+            .chain(&self.cors_origin)
+            .chain([&self.default_mime]);
+        for value in field_values {
+            if !http10::headers::is_field_value(value) {
+                return Err(format!("{:?} can't be sent in a header", value));
+            }
+        }
+        if self.connection_deadline == Some(Duration::ZERO) {
+            return Err("Connection deadline must be above 0".to_string());
+        }
+        Ok(())
     }
 }
 
 pub mod http_server {
-    use std::io::{Read, Write};
-    use std::net::{TcpListener, TcpStream};
-    use std::sync::Arc;
-    use std::time::Duration;
-
-    use crate::http10::headers::{Header, HeaderVariant, Headers};
-    use crate::http10::methods::Method;
-    use crate::http10::request::ReqError;
+    use std::collections::HashMap;
+    use std::io::{self, BufWriter, Read, Write};
+    use std::net::{
+        IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs,
+    };
+    use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use crate::file::GzipStore;
+    use crate::http10::headers::{self, Header, HeaderVariant, Headers};
+    use crate::http10::request::{self, BodyLength, ReqError};
     use crate::http10::result_codes::ResultCode;
     use crate::http10::{request::HTTPRequest, response::HTTPResponse};
     use crate::middleware;
     use crate::middleware::get_handler;
-    use crate::threadpool::ThreadPoolQ;
-    use crate::util::html::error_page;
+    use crate::reaper::Reaper;
+    use crate::threadpool::{QueueOrder, ThreadPoolQ};
+    use crate::throttle::AcceptThrottle;
+    use crate::util::html::{error_page, error_page_with_detail};
+    use crate::util::request_id;
+
+    use chrono::Utc;
+    use socket2::{Domain, Socket, Type};
 
     use super::Opts;
 
+    #[cfg(feature = "async")]
+    mod nonblocking;
+
+    pub use crate::cache::ResponseCache;
+    pub use crate::http10::methods::Method;
+    pub use crate::http10::request::BodyReader;
+
     #[derive(Debug, PartialEq)]
     pub enum HTTPServerClass {
         Simple,
@@ -69,39 +405,237 @@ pub mod http_server {
         ThreadPooled(usize),
     }
 
-    pub struct HTTPServer {
+    /// Request handler, receives the shared state passed to `HTTPServer::new`
+    pub type Handler<S> =
+        Box<dyn Fn(HTTPRequest, &Arc<Opts>, &Arc<S>) -> HTTPResponse + Send + Sync + 'static>;
+
+    /// Request handler that reads the body from the connection as it needs it
+    pub type StreamingHandler<S> = Box<
+        dyn Fn(HTTPRequest, &mut BodyReader, &Arc<Opts>, &Arc<S>) -> HTTPResponse
+            + Send
+            + Sync
+            + 'static,
+    >;
+
+    /// Observes each request just before it's handed to the handler
+    pub type RequestHook = Box<dyn Fn(&HTTPRequest) + Send + Sync + 'static>;
+
+    /// Observes each response just before it's sent
+    pub type ResponseHook = Box<dyn Fn(&HTTPResponse) + Send + Sync + 'static>;
+
+    pub struct HTTPServer<S = ()> {
         class: HTTPServerClass,
         opts: Arc<Opts>,
-        handler: Box<dyn Fn(HTTPRequest, &Arc<Opts>) -> HTTPResponse + Send + Sync + 'static>,
+        state: Arc<S>,
+        handler: Handler<S>,
+        streaming: Option<(usize, StreamingHandler<S>)>,
+        on_request: Option<RequestHook>,
+        on_response: Option<ResponseHook>,
+        reaper: Option<Arc<Reaper>>,
+        cache: Option<ResponseCache>,
+        connections: Arc<AtomicUsize>,
+        // Connections being served, whatever the server class
+        in_flight: Arc<AtomicUsize>,
+        drain: Option<Arc<AtomicBool>>,
+        stats: Arc<ServerStats>,
+        // Open connections by client address, for max_connections_per_ip
+        per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+        // Options of each virtual host by normalized host name, see middleware::vhost_name
+        vhosts: HashMap<String, Arc<Opts>>,
     }
 
-    impl HTTPServer {
-        fn default_handler(req: HTTPRequest, opts: &Arc<Opts>) -> HTTPResponse {
-            if let Some(auth) = &opts.auth {
-                match middleware::basic_auth(&req, auth) {
-                    Err(..) => {
-                        let mut headers = Headers::default();
-                        headers.set(Header::WWWAuthenticate("Basic".to_string()));
-                        headers.set(Header::ContentType("text/html".to_string()));
-                        return HTTPResponse::new(
-                            opts.protocol.clone(),
-                            ResultCode::Unauthorized,
-                            headers,
-                            Some(error_page(ResultCode::Unauthorized).as_bytes().to_vec()),
-                        );
-                    }
-                    Ok(..) => (),
+    // Holds one of a client address's connection slots, freed when dropped so a
+    // panicking handler can't leak it
+    struct IpSlot {
+        per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+        ip: IpAddr,
+    }
+
+    impl IpSlot {
+        fn acquire(
+            per_ip: &Arc<Mutex<HashMap<IpAddr, usize>>>,
+            ip: IpAddr,
+            max: usize,
+        ) -> Option<IpSlot> {
+            let mut open = per_ip
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let count = open.entry(ip).or_insert(0);
+            if *count >= max {
+                return None;
+            }
+            *count += 1;
+            Some(IpSlot {
+                per_ip: Arc::clone(per_ip),
+                ip,
+            })
+        }
+    }
+
+    impl Drop for IpSlot {
+        fn drop(&mut self) {
+            let mut open = self
+                .per_ip
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if let Some(count) = open.get_mut(&self.ip) {
+                *count -= 1;
+                if *count == 0 {
+                    open.remove(&self.ip);
+                }
+            }
+        }
+    }
+
+    /// Running totals of what the server has answered
+    #[derive(Debug, Default)]
+    pub struct ServerStats {
+        requests: AtomicU64,
+        bytes_written: AtomicU64,
+    }
+
+    impl ServerStats {
+        /// Requests answered, parse errors included
+        pub fn requests(&self) -> u64 {
+            self.requests.load(Ordering::SeqCst)
+        }
+
+        /// Response bytes written to clients, headers included
+        pub fn bytes_written(&self) -> u64 {
+            self.bytes_written.load(Ordering::SeqCst)
+        }
+
+        fn record(&self, written: usize) {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            self.bytes_written
+                .fetch_add(written as u64, Ordering::SeqCst);
+        }
+    }
+
+    // Counts a connection as in flight for as long as it's held
+    struct InFlight(Arc<AtomicUsize>);
+
+    impl InFlight {
+        fn start(counter: &Arc<AtomicUsize>) -> InFlight {
+            counter.fetch_add(1, Ordering::SeqCst);
+            InFlight(Arc::clone(counter))
+        }
+    }
+
+    impl Drop for InFlight {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    // What gets logged for each request. Fields the request doesn't carry are "-".
+    struct AccessRecord {
+        request_line: String,
+        status: usize,
+        length: usize,
+        user_agent: String,
+        referer: String,
+        remote: String,
+        request_id: String,
+    }
+
+    impl AccessRecord {
+        fn new(request: &HTTPRequest, remote: &str, request_id: &str) -> Self {
+            let user_agent = match request.headers.get(HeaderVariant::UserAgent) {
+                Some(Header::UserAgent(inner)) => inner,
+                _ => "-".to_string(),
+            };
+            let referer = match request.headers.get(HeaderVariant::Referer) {
+                Some(Header::Referer(inner)) => inner,
+                _ => "-".to_string(),
+            };
+            AccessRecord {
+                request_line: format!(
+                    "{} {} {}",
+                    Into::<String>::into(request.method.clone()),
+                    request.uri,
+                    request.version
+                ),
+                status: 0,
+                length: 0,
+                user_agent,
+                referer,
+                remote: remote.to_string(),
+                request_id: request_id.to_string(),
+            }
+        }
+    }
+
+    impl std::fmt::Display for AccessRecord {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                self.request_line,
+                self.status,
+                self.length,
+                self.user_agent,
+                self.referer,
+                self.remote,
+                self.request_id
+            )
+        }
+    }
+
+    // A Content-Length body left on the connection for a streaming handler
+    struct StreamedBody {
+        head_len: usize,
+        len: usize,
+    }
+
+    impl<S> HTTPServer<S>
+    where
+        S: Send + Sync + 'static,
+    {
+        // Serves the directory, taking gzipped copies from the store when there is one
+        fn default_handler(
+            mut req: HTTPRequest,
+            opts: &Arc<Opts>,
+            gzipped: Option<&GzipStore>,
+        ) -> HTTPResponse {
+            if opts.url_prefix.is_some() {
+                match middleware::strip_url_prefix(&req.uri, opts) {
+                    Some(uri) => req.uri = uri,
+                    None => return middleware::not_found(opts),
+                }
+            }
+            // Auth rules and files see the same path, `..` isn't resolved but refused
+            match middleware::normalize_uri(&req.uri) {
+                Some(uri) => req.uri = uri,
+                None => return middleware::bad_request(opts),
+            }
+            if opts.disabled_methods.contains(&req.method) {
+                return middleware::method_not_allowed(&req, opts);
+            }
+            // Browsers never send credentials with a preflight
+            if req.method != Method::OPTIONS {
+                if let Some(resp) = Self::authorize(&req, opts) {
+                    return resp;
                 }
             }
 
             match req.method {
-                Method::GET => get_handler(&req, opts),
+                Method::GET => middleware::allow_origin(get_handler(&req, opts, gzipped), opts),
                 Method::HEAD => {
-                    let mut resp = get_handler(&req, opts);
+                    let resp = get_handler(&req, opts, gzipped);
+                    let mut resp = middleware::allow_origin(resp, opts);
                     resp.body = None;
                     resp
                 }
-                Method::POST => {
+                Method::OPTIONS => middleware::options_handler(&req, opts),
+                Method::PUT => middleware::put_handler(&req, opts),
+                Method::DELETE => middleware::delete_handler(&req, opts),
+                Method::TRACE if opts.trace => middleware::trace_handler(&req, opts),
+                Method::TRACE => middleware::method_not_allowed(&req, opts),
+                // Not a proxy, so there's nothing to tunnel to
+                Method::CONNECT => middleware::method_not_allowed(&req, opts),
+                // Well formed but unknown to us, unlike a malformed request line (400)
+                Method::Other(_) => {
                     let mut headers = Headers::default();
                     headers.set(Header::ContentType("text/html".to_string()));
                     HTTPResponse::new(
@@ -111,14 +645,91 @@ pub mod http_server {
                         Some(error_page(ResultCode::NotImplemented).as_bytes().to_vec()),
                     )
                 }
+                // Static files can't be posted to, custom handlers get POST directly. The
+                // body has still been read in full so the connection stays usable.
+                Method::POST => {
+                    let received = req.body.as_ref().map_or(0, Vec::len);
+                    log::debug!("Refusing POST of {} bytes to {}", received, req.uri);
+                    middleware::method_not_allowed(&req, opts)
+                }
             }
         }
 
-        fn handle_stream(
-            mut stream: TcpStream,
-            handler: &Box<dyn Fn(HTTPRequest, &Arc<Opts>) -> HTTPResponse + Send + Sync + 'static>,
-            opts: &Arc<Opts>,
-        ) {
+        // Challenges requests that lack the credentials their path requires
+        fn authorize(req: &HTTPRequest, opts: &Opts) -> Option<HTTPResponse> {
+            let auth = middleware::auth_for(&req.uri, opts)?;
+            match middleware::basic_auth(req, auth) {
+                Err(..) => {
+                    let mut headers = Headers::default();
+                    headers.set(Header::WWWAuthenticate("Basic".to_string()));
+                    headers.set(Header::ContentType("text/html".to_string()));
+                    Some(HTTPResponse::new(
+                        opts.protocol.clone(),
+                        ResultCode::Unauthorized,
+                        headers,
+                        Some(error_page(ResultCode::Unauthorized).as_bytes().to_vec()),
+                    ))
+                }
+                Ok(..) => None,
+            }
+        }
+
+        fn set_keepalive(&self, socket: socket2::SockRef) {
+            if let Some(probes) = &self.opts.tcp_keepalive {
+                let params = socket2::TcpKeepalive::new()
+                    .with_time(probes.idle)
+                    .with_interval(probes.interval);
+                if let Err(err) = socket.set_tcp_keepalive(&params) {
+                    log::warn!("Unable to enable TCP keepalive: {}", err);
+                }
+            }
+        }
+
+        // Unbuffered, small writes like a 103 ahead of its response or a head ahead
+        // of its body would otherwise wait on the client's ACK of the previous one
+        fn set_nodelay(&self, stream: &TcpStream) {
+            if let Err(err) = stream.set_nodelay(self.opts.write_buffer == 0) {
+                log::warn!("Unable to set TCP_NODELAY: {}", err);
+            }
+        }
+
+        // Applied to the sockets of accepted connections, the OS may round the sizes
+        fn set_buffers(&self, socket: socket2::SockRef) {
+            if let Some(size) = self.opts.recv_buffer {
+                if let Err(err) = socket.set_recv_buffer_size(size) {
+                    log::warn!(
+                        "Unable to set the receive buffer to {} bytes: {}",
+                        size,
+                        err
+                    );
+                }
+            }
+            if let Some(size) = self.opts.send_buffer {
+                if let Err(err) = socket.set_send_buffer_size(size) {
+                    log::warn!("Unable to set the send buffer to {} bytes: {}", size, err);
+                }
+            }
+        }
+
+        fn handle_stream(&self, mut stream: TcpStream) {
+            let _in_flight = InFlight::start(&self.in_flight);
+            // By the connecting address, which is the proxy's for proxied clients. Who
+            // the client really is only comes out with its first request.
+            let _slot = match stream.peer_addr().map(|peer| self.ip_slot(peer.ip())) {
+                Ok(Err(())) => {
+                    self.service_unavailable(stream);
+                    return;
+                }
+                Ok(Ok(slot)) => slot,
+                Err(_) => None,
+            };
+            let _tracked = self
+                .reaper
+                .as_ref()
+                .and_then(|reaper| reaper.track(&stream));
+            self.set_keepalive(socket2::SockRef::from(&stream));
+            self.set_nodelay(&stream);
+            self.set_buffers(socket2::SockRef::from(&stream));
             // Only fails when duration is 0 which we explicitly do not set
             stream
                 .set_read_timeout(Some(Duration::from_secs(1)))
@@ -127,133 +738,634 @@ pub mod http_server {
                 Ok(addr) => addr.to_string(),
                 Err(_) => "Invalid Address".to_string(),
             };
-            let mut request: Vec<u8> = Vec::new();
+            let max_requests = self.opts.max_requests_per_connection;
+            let mut served = 0;
+            // Bytes of pipelined requests that arrived along with the one before
+            let mut pending = Vec::new();
+            loop {
+                let (request, streamed) = self.read_request(&mut stream, &mut pending);
+                if request.is_empty() {
+                    // Client closed the connection or sat idle between requests
+                    return;
+                }
+                served += 1;
+                let last = max_requests != 0 && served >= max_requests;
+                let peer = stream.peer_addr().ok();
+                let keep_alive =
+                    self.handle_request(&mut stream, peer, &request, streamed, &remote, !last);
+                if !keep_alive {
+                    return;
+                }
+            }
+        }
+
+        // Reads one request. The head is parsed once to learn how the body is framed,
+        // then reading continues until that many bytes have arrived. Bodies too large
+        // to buffer are left on the connection when a streaming handler is set. Reading
+        // starts with pending, and whatever follows the request is put back there.
+        fn read_request(
+            &self,
+            stream: &mut TcpStream,
+            pending: &mut Vec<u8>,
+        ) -> (Vec<u8>, Option<StreamedBody>) {
+            let mut request = std::mem::take(pending);
             let mut buf = [0u8; 4096];
+            let mut body: Option<(usize, BodyLength)> = None;
+            let mut chunked = request::ChunkedFraming::default();
             loop {
-                match HTTPRequest::try_from(&request) {
-                    Err(ReqError::ContentLenError) => match stream.read(&mut buf) {
-                        Ok(0) => break,
-                        Ok(n) => {
-                            request.append(buf[..n].to_vec().as_mut());
+                match &body {
+                    None => {
+                        if let Some(head_len) = request::head_length(&request) {
+                            let head = &request[..head_len];
+                            match request::expected_body(head, self.opts.max_headers) {
+                                Ok(length) => {
+                                    body = Some((head_len, length));
+                                    continue;
+                                }
+                                // Leave reporting the error to the parser
+                                Err(_) => break,
+                            }
+                        }
+                    }
+                    Some((head_len, BodyLength::Exact(len))) => {
+                        if request.len() >= head_len + len {
+                            *pending = request.split_off(head_len + len);
+                            break;
+                        }
+                        if let Some((max_buffered, _)) = self.streaming {
+                            if *len > max_buffered {
+                                let streamed = StreamedBody {
+                                    head_len: *head_len,
+                                    len: *len,
+                                };
+                                return (request, Some(streamed));
+                            }
+                        }
+                    }
+                    Some((head_len, BodyLength::Chunked)) => {
+                        if let Some(len) = chunked.end(&request[*head_len..]) {
+                            *pending = request.split_off(head_len + len);
+                            break;
                         }
-                        Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => (),
-                        Err(_) => break,
-                    },
-                    _ => break,
+                    }
+                }
+                match stream.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                    Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                    Err(_) => break,
                 }
             }
-            let request = match HTTPRequest::try_from(&request) {
+            (request, None)
+        }
+
+        // Answers a single request, returns whether the connection should stay open
+        fn handle_request(
+            &self,
+            stream: &mut (impl Read + Write),
+            peer: Option<SocketAddr>,
+            raw: &[u8],
+            streamed: Option<StreamedBody>,
+            remote: &str,
+            allow_keep_alive: bool,
+        ) -> bool {
+            let opts = &self.opts;
+            let parsed = match &streamed {
+                Some(body) => HTTPRequest::from_head(&raw[..body.head_len], opts.max_headers),
+                None => {
+                    HTTPRequest::try_from_with_limit(raw, opts.max_headers, opts.max_decoded_body)
+                }
+            };
+            let mut request = match parsed {
                 Ok(req) => req,
-                Err(_) => {
-                    let headers = Headers::default();
+                Err(err) => {
+                    let status = match err {
+                        ReqError::UnsupportedEncodingErr(_) => ResultCode::UnsupportedMediaType,
+                        ReqError::BodyTooLarge(_) => ResultCode::PayloadTooLarge,
+                        ReqError::TooManyHeaders(_) => ResultCode::RequestHeaderFieldsTooLarge,
+                        ReqError::InvalidHTTPVerError => ResultCode::HTTPVersionNotSupported,
+                        _ => ResultCode::BadRequest,
+                    };
+                    let mut headers = Headers::default();
+                    headers.set(Header::ContentType("text/html".to_string()));
+                    headers.set(Header::Connection(vec!["close".to_string()]));
+                    let body = if opts.verbose_errors {
+                        error_page_with_detail(status, &err.to_string())
+                    } else {
+                        error_page(status)
+                    };
                     let mut resp = HTTPResponse {
                         version: opts.protocol.clone(),
-                        status: ResultCode::BadRequest,
+                        status,
                         headers,
-                        body: Some(error_page(ResultCode::BadRequest).as_bytes().to_vec()),
+                        body: Some(body.as_bytes().to_vec()),
+                        trailers: None,
                     };
-                    let _ = stream.write_all(resp.as_bytes().as_slice());
-                    log::error!("Malformed request from: {}", remote);
-                    log::debug!("Received: {:?}", request);
-                    return;
+                    self.hide_identity(&mut resp);
+                    let bytes = resp.as_bytes();
+                    if stream.write_all(&bytes).is_ok() {
+                        self.stats.record(bytes.len());
+                    }
+                    log::error!("Malformed request from {}: {}", remote, err);
+                    log::debug!("Received: {:?}", raw);
+                    return false;
                 }
             };
 
-            // Gathering info used for logging
-            let headline = format!(
-                "{} {} {}",
-                Into::<String>::into(request.method),
-                request.uri,
-                request.version
-            );
-            let user_agent = request.headers.get(HeaderVariant::UserAgent);
-            let user_agent = match user_agent {
-                Some(Header::UserAgent(inner)) => inner,
-                _ => "-".to_string(),
+            request.peer = peer;
+            let opts = self.opts_for(&request);
+            let remote = match middleware::forwarded_client(&request, &opts.trusted_proxies) {
+                Some(client) => client.to_string(),
+                None => remote.to_string(),
             };
+            let keep_alive = allow_keep_alive && request.keep_alive();
+            let http10 = request.version == "HTTP/1.0";
+            request.headers.strip_hop_by_hop();
+            // Only POST can carry a tunnelled method, so a GET never turns into a DELETE
+            if opts.method_override && request.method == Method::POST {
+                if let Some(name) = request.headers.get_generic("X-HTTP-Method-Override") {
+                    match Method::try_from(name.trim()) {
+                        Ok(method) => request.method = method,
+                        Err(_) => log::debug!("Ignoring method override {:?}", name),
+                    }
+                }
+            }
+
+            // Reuse the caller's id for tracing, the handler sees the one in use either way
+            let request_id = match request.headers.get_generic("X-Request-Id") {
+                Some(id) if request_id::is_valid(&id) => id,
+                _ => {
+                    let id = request_id::generate();
+                    request.headers.remove_named("X-Request-Id");
+                    request
+                        .headers
+                        .set(Header::Generic(("X-Request-Id".to_string(), id.clone())));
+                    id
+                }
+            };
+
+            // Gathering info used for logging
+            let mut record = AccessRecord::new(&request, &remote, &request_id);
             let req_headers = request.headers.to_string();
 
+            if let Some(hook) = &self.on_request {
+                hook(&request);
+            }
+
+            // Lets the client start fetching what the page needs while it's prepared
+            if let Some(mut hints) = middleware::early_hints(&request, opts) {
+                self.hide_identity(&mut hints);
+                let bytes = hints.as_bytes();
+                if stream.write_all(&bytes).is_ok() {
+                    self.stats
+                        .bytes_written
+                        .fetch_add(bytes.len() as u64, Ordering::SeqCst);
+                }
+            }
+
             // Pass off the request to the handler
-            let mut resp = handler(request, opts);
+            let (mut resp, body_read) = match (&self.streaming, streamed) {
+                (Some((_, handler)), Some(body)) => {
+                    let buffered = raw[body.head_len..].to_vec();
+                    let mut reader = BodyReader::new(buffered, stream, body.len);
+                    let resp = handler(request, &mut reader, opts, &self.state);
+                    (resp, reader.is_done())
+                }
+                (Some((_, handler)), None) => {
+                    let mut reader =
+                        BodyReader::from_bytes(request.body.clone().unwrap_or_default());
+                    (handler(request, &mut reader, opts, &self.state), true)
+                }
+                (None, _) => {
+                    // Anything behind basic auth, or sent with credentials, must not be
+                    // replayed to whoever asks next, so it never touches the cache
+                    let private = request.headers.get(HeaderVariant::Authorization).is_some()
+                        || middleware::strip_url_prefix(&request.uri, opts)
+                            .is_some_and(|uri| middleware::auth_for(&uri, opts).is_some());
+                    let cached = self.cache.as_ref().filter(|_| !private).and_then(|cache| {
+                        let entry = cache.entry(&request)?;
+                        Some((cache, entry))
+                    });
+                    match cached {
+                        Some((cache, entry)) => match cache.get(&entry) {
+                            Some(mut resp) => {
+                                log::debug!("Serving {} from {}", request.uri, entry.display());
+                                resp.headers.set(Header::Date(Utc::now().into()));
+                                (resp, true)
+                            }
+                            None => {
+                                let resp = (self.handler)(request, opts, &self.state);
+                                cache.put(&entry, &resp);
+                                (resp, true)
+                            }
+                        },
+                        None => ((self.handler)(request, opts, &self.state), true),
+                    }
+                }
+            };
+
+            // HTTP/1.0 clients know nothing of chunked framing, so trailers are left out
+            // and the body is delimited by its Content-Length or the connection closing
+            if http10 {
+                resp.trailers = None;
+            }
+            // The connection can only be reused if the client can tell where the body ends,
+            // and only once any unread request body is off the connection
+            let keep_alive = keep_alive
+                && body_read
+                && (resp.body.is_none()
+                    || resp.is_chunked()
+                    || resp.headers.get(HeaderVariant::ContentLength).is_some());
+            resp.headers.remove_named("X-Request-Id");
+            resp.headers.set(Header::Generic((
+                "X-Request-Id".to_string(),
+                request_id.clone(),
+            )));
+            resp.headers.set(Header::Connection(vec![if keep_alive {
+                "keep-alive".to_string()
+            } else {
+                "close".to_string()
+            }]));
 
             //More log data gathering
-            let code = Into::<usize>::into(resp.status);
-            let content_len = match resp.headers.get(HeaderVariant::ContentLength) {
+            record.status = resp.status.into();
+            record.length = match resp.headers.get(HeaderVariant::ContentLength) {
                 Some(Header::ContentLength(len)) => len,
                 _ => 0,
             };
             let resp_headers = resp.headers.to_string();
 
-            // Send the response back
-            stream.write_all(resp.as_bytes().as_slice()).unwrap();
+            self.hide_identity(&mut resp);
+            if let Some(hook) = &self.on_response {
+                hook(&resp);
+            }
+
+            // Send the response back, clients aborting a download is routine
+            let written = match self.opts.write_buffer {
+                0 => resp
+                    .write_to(stream)
+                    .and_then(|len| stream.flush().map(|_| len)),
+                capacity => {
+                    let mut out = BufWriter::with_capacity(capacity, &mut *stream);
+                    resp.write_to(&mut out)
+                        .and_then(|len| out.flush().map(|_| len))
+                }
+            };
+            match written {
+                Ok(len) => self.stats.record(len),
+                Err(err) => {
+                    log::debug!("Client {} went away mid-response: {}", remote, err);
+                    return false;
+                }
+            }
 
-            log::info!(
-                "{} {} {} {} {}",
-                headline,
-                code,
-                content_len,
-                user_agent,
-                remote
-            );
+            log::info!("{}", record);
             log::debug!(
                 "Request headers: {}\nResponse Headers: {}",
                 req_headers,
                 resp_headers
             );
+            keep_alive
         }
 
-        pub fn new(
-            class: HTTPServerClass,
-            opts: Opts,
-            handler: Option<
-                Box<dyn Fn(HTTPRequest, &Arc<Opts>) -> HTTPResponse + Send + Sync + 'static>,
-            >,
-        ) -> HTTPServer {
-            let opts = Arc::new(opts);
-            match handler {
-                Some(handler) => HTTPServer {
-                    class,
-                    opts,
-                    handler,
-                },
-                None => HTTPServer {
-                    class,
-                    opts,
-                    handler: Box::new(HTTPServer::default_handler),
-                },
+        fn handle_queued(&self, job: (TcpStream, Instant)) {
+            let (stream, queued) = job;
+            if let Some(timeout) = self.opts.queue_timeout {
+                let waited = queued.elapsed();
+                if waited > timeout {
+                    // The client has most likely given up, don't waste work on it
+                    log::warn!("Dropping connection queued for {:?}", waited);
+                    let _ = stream.shutdown(Shutdown::Both);
+                    return;
+                }
             }
+            self.handle_stream(stream);
         }
 
-        pub fn serve_forever(self) {
-            let listener = TcpListener::bind(format!("{}:{}", self.opts.bind, self.opts.port))
-                .expect("Unable to bind!");
+        // Worker pool for the ThreadPooled class, frees a connection's slot once it's served
+        fn pool(server: &Arc<Self>, threads: usize) -> ThreadPoolQ<(TcpStream, Instant)> {
+            let order = if server.opts.lifo {
+                QueueOrder::Lifo
+            } else {
+                QueueOrder::Fifo
+            };
+            let worker = Arc::clone(server);
+            let stack_size = server.opts.worker_stack_size;
+            ThreadPoolQ::with_stack_size(threads, order, stack_size, move |job| {
+                worker.handle_queued(job);
+                worker.connections.fetch_sub(1, Ordering::SeqCst);
+            })
+            .with_shutdown_grace(server.opts.shutdown_grace)
+        }
 
-            log::info!("Started listener on {}:{}", self.opts.bind, self.opts.port);
+        // Queues a connection unless max_connections are already queued or being served,
+        // in which case the client is told to come back later
+        fn enqueue(&self, tpq: &mut ThreadPoolQ<(TcpStream, Instant)>, stream: TcpStream) {
+            if self.admit() {
+                tpq.push_job((stream, Instant::now()));
+            } else {
+                self.service_unavailable(stream);
+            }
+        }
 
-            match self.class {
-                HTTPServerClass::Simple => {
-                    let opts = Arc::clone(&self.opts);
+        // Counts a connection in unless max_connections are already outstanding. Admitted
+        // ones are counted out again once served.
+        fn admit(&self) -> bool {
+            let max = self.opts.max_connections;
+            let admitted =
+                self.connections
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |outstanding| {
+                        (max == 0 || outstanding < max).then_some(outstanding + 1)
+                    });
+            if admitted.is_err() {
+                log::warn!("Rejecting connection, {} already outstanding", max);
+            }
+            admitted.is_ok()
+        }
 
-                    for stream in listener.incoming() {
-                        match stream {
-                            Ok(stream) => HTTPServer::handle_stream(stream, &self.handler, &opts),
-                            Err(e) => {
-                                log::error!("Failed to establish a connection: {}", e);
-                            }
-                        }
-                    }
+        // One of the connection slots of a client address, Err if max_connections_per_ip
+        // of them are taken already
+        fn ip_slot(&self, ip: IpAddr) -> Result<Option<IpSlot>, ()> {
+            let max_per_ip = self.opts.max_connections_per_ip;
+            if max_per_ip == 0 {
+                return Ok(None);
+            }
+            match IpSlot::acquire(&self.per_ip, ip, max_per_ip) {
+                Some(slot) => Ok(Some(slot)),
+                None => {
+                    log::warn!(
+                        "Rejecting connection, {} already open from {}",
+                        max_per_ip,
+                        ip
+                    );
+                    Err(())
                 }
-                HTTPServerClass::Threaded => {
-                    let handler = Arc::new(self.handler);
+            }
+        }
+
+        // Tells a client turned away at the door to come back later
+        fn service_unavailable(&self, mut stream: TcpStream) {
+            let _ = stream.write_all(&self.unavailable_response());
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+
+        fn unavailable_response(&self) -> Vec<u8> {
+            let mut headers = Headers::default();
+            headers.set(Header::ContentType("text/html".to_string()));
+            headers.set(Header::Connection(vec!["close".to_string()]));
+            let mut resp = HTTPResponse::new(
+                self.opts.protocol.clone(),
+                ResultCode::ServiceUnavailable,
+                headers,
+                Some(
+                    error_page(ResultCode::ServiceUnavailable)
+                        .as_bytes()
+                        .to_vec(),
+                ),
+            );
+            self.hide_identity(&mut resp);
+            resp.as_bytes()
+        }
+
+        // Options of the virtual host the request is for, the server's own otherwise
+        fn opts_for(&self, req: &HTTPRequest) -> &Arc<Opts> {
+            if self.vhosts.is_empty() {
+                return &self.opts;
+            }
+            req.headers
+                .get(HeaderVariant::Host)
+                .and_then(|host| match host {
+                    Header::Host(host) => self
+                        .vhosts
+                        .get(&middleware::vhost_name(&host, self.opts.port)),
+                    _ => None,
+                })
+                .unwrap_or(&self.opts)
+        }
+
+        // The default handler, owning the gzipped copies of the served directories when
+        // they're precompressed
+        fn files_handler(opts: &Opts) -> Handler<S> {
+            if !opts.precompress || !opts.compression {
+                return Box::new(|req, opts, _| Self::default_handler(req, opts, None));
+            }
+            let mut store = GzipStore::new(opts.precompress_budget);
+            for directory in opts.vhosts.values().chain([&opts.directory]) {
+                let vhost = Opts {
+                    directory: directory.clone(),
+                    ..opts.clone()
+                };
+                let kept = middleware::precompress(&vhost, &mut store);
+                log::info!("Precompressed {} files under {}", kept, directory);
+            }
+            Box::new(move |req, opts, _| Self::default_handler(req, opts, Some(&store)))
+        }
+
+        // Applied to every response right before it's written, whoever built it
+        fn hide_identity(&self, resp: &mut HTTPResponse) {
+            if self.opts.hide_server {
+                resp.headers.remove_named("Server");
+            }
+        }
+
+        /// Totals of requests answered and bytes written, still readable once
+        /// `serve_forever` has returned
+        pub fn stats(&self) -> Arc<ServerStats> {
+            Arc::clone(&self.stats)
+        }
+
+        /// Live count of pooled connections that are queued or being served, for metrics
+        pub fn connections(&self) -> Arc<AtomicUsize> {
+            Arc::clone(&self.connections)
+        }
+
+        pub fn new(
+            class: HTTPServerClass,
+            opts: Opts,
+            state: Arc<S>,
+            handler: Option<Handler<S>>,
+        ) -> HTTPServer<S> {
+            let reaper = opts.connection_deadline.map(Reaper::start);
+            if opts.max_connections_per_ip != 0 && !opts.trusted_proxies.is_empty() {
+                log::warn!(
+                    "Connections are limited per peer address, clients of a trusted proxy share its {}",
+                    opts.max_connections_per_ip
+                );
+            }
+            let vhosts = opts
+                .vhosts
+                .iter()
+                .map(|(host, directory)| {
+                    let vhost = Opts {
+                        directory: directory.clone(),
+                        vhosts: HashMap::new(),
+                        ..opts.clone()
+                    };
+                    (middleware::vhost_name(host, opts.port), Arc::new(vhost))
+                })
+                .collect();
+            let opts = Arc::new(opts);
+            match handler {
+                Some(handler) => HTTPServer {
+                    class,
+                    opts,
+                    state,
+                    handler,
+                    streaming: None,
+                    on_request: None,
+                    on_response: None,
+                    reaper,
+                    cache: None,
+                    connections: Arc::new(AtomicUsize::new(0)),
+                    in_flight: Arc::new(AtomicUsize::new(0)),
+                    stats: Arc::new(ServerStats::default()),
+                    per_ip: Arc::new(Mutex::new(HashMap::new())),
+                    vhosts,
+                    drain: None,
+                },
+                None => HTTPServer {
+                    class,
+                    handler: Self::files_handler(&opts),
+                    opts,
+                    state,
+                    streaming: None,
+                    on_request: None,
+                    on_response: None,
+                    reaper,
+                    cache: None,
+                    connections: Arc::new(AtomicUsize::new(0)),
+                    in_flight: Arc::new(AtomicUsize::new(0)),
+                    stats: Arc::new(ServerStats::default()),
+                    per_ip: Arc::new(Mutex::new(HashMap::new())),
+                    vhosts,
+                    drain: None,
+                },
+            }
+        }
+
+        /// Hands every request to a streaming handler instead. Bodies up to max_buffered
+        /// bytes are also available in `HTTPRequest.body`, larger ones are only readable
+        /// through the `BodyReader`.
+        pub fn with_streaming_handler(
+            mut self,
+            max_buffered: usize,
+            handler: StreamingHandler<S>,
+        ) -> HTTPServer<S> {
+            self.streaming = Some((max_buffered, handler));
+            self
+        }
+
+        /// Calls the hook with every parsed request before the handler sees it
+        pub fn with_request_hook(mut self, hook: RequestHook) -> HTTPServer<S> {
+            self.on_request = Some(hook);
+            self
+        }
+
+        /// Calls the hook with every handler response right before it's written
+        pub fn with_response_hook(mut self, hook: ResponseHook) -> HTTPServer<S> {
+            self.on_response = Some(hook);
+            self
+        }
+
+        /// Answers GET and HEAD requests on the cache's routes from responses the
+        /// handler gave before, until they're older than the cache's TTL
+        pub fn with_response_cache(mut self, cache: ResponseCache) -> HTTPServer<S> {
+            self.cache = Some(cache);
+            self
+        }
+
+        /// Serves a single in-memory document at every path instead of the directory,
+        /// e.g. a payload piped in on stdin. Auth still applies.
+        pub fn with_body(mut self, body: Vec<u8>, content_type: &str) -> HTTPServer<S> {
+            let content_type = headers::sanitize_field_value(content_type);
+            self.handler = Box::new(move |req, opts, _| {
+                Self::authorize(&req, opts)
+                    .unwrap_or_else(|| middleware::body_handler(&req, &body, &content_type, opts))
+            });
+            self
+        }
+
+        /// Hands requests for directories without an index to the handler when listings
+        /// are off, instead of refusing them. Auth still applies, everything else is
+        /// served as before.
+        pub fn with_directory_handler(mut self, handler: Handler<S>) -> HTTPServer<S> {
+            let fallback = self.handler;
+            self.handler = Box::new(move |req, opts, state| {
+                if !middleware::unindexed_directory(&req, opts) {
+                    return fallback(req, opts, state);
+                }
+                match Self::authorize(&req, opts) {
+                    Some(resp) => resp,
+                    None => handler(req, opts, state),
+                }
+            });
+            self
+        }
+
+        /// Once the flag is raised, stops accepting connections and returns from
+        /// `serve_forever` or `serve_async` when the ones being served are done, e.g. for
+        /// rolling restarts
+        pub fn with_drain_flag(mut self, flag: Arc<AtomicBool>) -> HTTPServer<S> {
+            self.drain = Some(flag);
+            self
+        }
+
+        fn draining(&self) -> bool {
+            self.drain
+                .as_ref()
+                .is_some_and(|flag| flag.load(Ordering::SeqCst))
+        }
+
+        pub fn serve_forever(self) {
+            let listener = listen(&self.opts).expect("Unable to bind!");
+            self.serve(listener);
+        }
+
+        fn serve(self, listener: TcpListener) {
+            match listener.local_addr() {
+                Ok(addr) => log::info!("Started listener on {}", addr),
+                Err(_) => log::info!("Started listener on {}:{}", self.opts.bind, self.opts.port),
+            }
 
-                    for stream in listener.incoming() {
+            if let Some(flag) = &self.drain {
+                wake_on_drain(Arc::clone(flag), &listener);
+            }
+
+            let server = Arc::new(self);
+            match server.class {
+                HTTPServerClass::Simple => {
+                    for stream in accepted(&listener, server.opts.accept_rate) {
+                        if server.draining() {
+                            break;
+                        }
+                        match stream {
+                            Ok(stream) => server.handle_stream(stream),
+                            Err(e) => {
+                                log::error!("Failed to establish a connection: {}", e);
+                            }
+                        }
+                    }
+                }
+                HTTPServerClass::Threaded => {
+                    for stream in accepted(&listener, server.opts.accept_rate) {
+                        if server.draining() {
+                            break;
+                        }
                         match stream {
                             Ok(stream) => {
-                                let handler = Arc::clone(&handler);
-                                let opts = Arc::clone(&self.opts);
-                                std::thread::spawn(move || {
-                                    HTTPServer::handle_stream(stream, &handler, &opts);
-                                });
+                                // Counted before the thread starts so draining can't miss it
+                                let in_flight = InFlight::start(&server.in_flight);
+                                let server = Arc::clone(&server);
+                                let mut builder = std::thread::Builder::new();
+                                if let Some(stack_size) = server.opts.worker_stack_size {
+                                    builder = builder.stack_size(stack_size);
+                                }
+                                if let Err(err) = builder.spawn(move || {
+                                    server.handle_stream(stream);
+                                    drop(in_flight);
+                                }) {
+                                    log::error!("Unable to spawn connection thread: {}", err);
+                                }
                             }
                             Err(e) => {
                                 log::error!("Failed to establish a connection: {}", e);
@@ -262,15 +1374,13 @@ pub mod http_server {
                     }
                 }
                 HTTPServerClass::ThreadPooled(threads) => {
-                    let opts = Arc::clone(&self.opts);
-                    let mut tpq = ThreadPoolQ::new(threads, move |stream| {
-                        HTTPServer::handle_stream(stream, &self.handler, &opts)
-                    });
-                    for stream in listener.incoming() {
+                    let mut tpq = HTTPServer::pool(&server, threads);
+                    for stream in accepted(&listener, server.opts.accept_rate) {
+                        if server.draining() {
+                            break;
+                        }
                         match stream {
-                            Ok(stream) => {
-                                tpq.push_job(stream);
-                            }
+                            Ok(stream) => server.enqueue(&mut tpq, stream),
                             Err(e) => {
                                 log::error!("Failed to establish a connection: {}", e);
                             }
@@ -278,26 +1388,1741 @@ pub mod http_server {
                     }
                 }
             }
+
+            // Reached only once draining, connections already accepted are seen through
+            while server.in_flight.load(Ordering::SeqCst) > 0 {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            log::info!("Drained, no connections left in flight");
+        }
+    }
+
+    // Connections from the listener, paced to at most rate a second when set
+    fn accepted(
+        listener: &TcpListener,
+        rate: Option<u32>,
+    ) -> impl Iterator<Item = io::Result<TcpStream>> + '_ {
+        let mut throttle = rate.map(AcceptThrottle::new);
+        std::iter::from_fn(move || {
+            if let Some(throttle) = &mut throttle {
+                throttle.wait();
+            }
+            Some(listener.accept().map(|(stream, _)| stream))
+        })
+    }
+
+    // Accepting blocks, so once the flag is raised a connection of our own wakes the
+    // listener up to notice
+    fn wake_on_drain(flag: Arc<AtomicBool>, listener: &TcpListener) {
+        let mut addr = match listener.local_addr() {
+            Ok(addr) => addr,
+            Err(err) => {
+                log::error!("Unable to watch for draining: {}", err);
+                return;
+            }
+        };
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        let spawned = std::thread::Builder::new()
+            .name("drain".to_string())
+            .spawn(move || {
+                while !flag.load(Ordering::SeqCst) {
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                log::info!("Draining, no longer accepting connections");
+                let _ = TcpStream::connect(addr);
+            });
+        if let Err(err) = spawned {
+            log::error!("Unable to watch for draining: {}", err);
+        }
+    }
+
+    /// The listening socket passed by systemd socket activation, if this process was given one
+    pub fn socket_activation_fd() -> Option<i32> {
+        let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+        let fds: u32 = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+        // Passed sockets start right after stdin, stdout and stderr
+        (pid == std::process::id() && fds >= 1).then_some(3)
+    }
+
+    // Adopts a listener handed over by a supervisor, otherwise binds a fresh one
+    fn listen(opts: &Opts) -> io::Result<TcpListener> {
+        let Some(fd) = opts.listen_fd else {
+            return bind(opts);
+        };
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::FromRawFd;
+            // Safety: the descriptor was handed to this process to own and isn't used elsewhere
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            // Fails if the descriptor isn't a socket
+            listener.local_addr()?;
+            Ok(listener)
+        }
+        #[cfg(not(unix))]
+        {
+            log::warn!("Ignoring listener fd {}, socket activation needs unix", fd);
+            bind(opts)
+        }
+    }
+
+    // Creates the listening socket with SO_REUSEADDR so a restart can rebind while
+    // connections from the previous run linger in TIME_WAIT
+    fn bind(opts: &Opts) -> io::Result<TcpListener> {
+        let addr = (opts.bind.as_str(), opts.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address to bind"))?;
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        if opts.reuse_port {
+            #[cfg(unix)]
+            socket.set_reuse_port(true)?;
+            #[cfg(not(unix))]
+            log::warn!("SO_REUSEPORT is not supported on this platform");
         }
+        socket.bind(&addr.into())?;
+        socket.listen(128)?;
+        Ok(socket.into())
     }
 
     #[cfg(test)]
     mod tests {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Mutex;
+
+        use crate::test_util::{stream_pair, test_dir};
+
         use super::*;
 
         #[test]
         fn test_create_single_threaded_server() {
-            HTTPServer::new(HTTPServerClass::Simple, Opts::default(), None);
+            HTTPServer::new(HTTPServerClass::Simple, Opts::default(), Arc::new(()), None);
         }
 
         #[test]
         fn test_create_threaded_server() {
-            HTTPServer::new(HTTPServerClass::Threaded, Opts::default(), None);
+            HTTPServer::new(
+                HTTPServerClass::Threaded,
+                Opts::default(),
+                Arc::new(()),
+                None,
+            );
         }
 
         #[test]
         fn test_create_threadpool_server() {
-            HTTPServer::new(HTTPServerClass::ThreadPooled(5), Opts::default(), None);
+            HTTPServer::new(
+                HTTPServerClass::ThreadPooled(5),
+                Opts::default(),
+                Arc::new(()),
+                None,
+            );
+        }
+
+        #[test]
+        fn test_drops_jobs_queued_past_deadline() {
+            let handled = Arc::new(AtomicBool::new(false));
+            let flag = Arc::clone(&handled);
+            let server = HTTPServer::new(
+                HTTPServerClass::ThreadPooled(1),
+                Opts {
+                    queue_timeout: Some(Duration::from_millis(100)),
+                    ..Opts::default()
+                },
+                Arc::new(()),
+                Some(Box::new(move |_, opts, _| {
+                    flag.store(true, Ordering::SeqCst);
+                    HTTPResponse::new(opts.protocol.clone(), ResultCode::OK, Headers::new(), None)
+                })),
+            );
+
+            let (stream, mut client) = stream_pair();
+            client
+                .write_all(b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            let queued = Instant::now() - Duration::from_secs(1);
+            server.handle_queued((stream, queued));
+
+            // Closed without a response, either cleanly or with a reset
+            let mut buf = [0u8; 64];
+            assert!(!matches!(client.read(&mut buf), Ok(n) if n > 0));
+            assert!(!handled.load(Ordering::SeqCst));
+
+            let (stream, mut client) = stream_pair();
+            client
+                .write_all(b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            server.handle_queued((stream, Instant::now()));
+            assert!(handled.load(Ordering::SeqCst));
+        }
+
+        #[test]
+        fn test_reads_body_larger_than_buffer() {
+            let server =
+                HTTPServer::new(HTTPServerClass::Simple, Opts::default(), Arc::new(()), None);
+            let body = vec![b'a'; 4 * 1024 * 1024];
+            let (mut stream, mut client) = stream_pair();
+            let writer = std::thread::spawn(move || {
+                client
+                    .write_all(
+                        format!(
+                            "POST /upload HTTP/1.0\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+                            body.len()
+                        )
+                        .as_bytes(),
+                    )
+                    .unwrap();
+                client.write_all(&body).unwrap();
+                client
+            });
+
+            let (request, streamed) = server.read_request(&mut stream, &mut Vec::new());
+            assert!(streamed.is_none());
+            writer.join().unwrap();
+            let request = HTTPRequest::try_from(&request).unwrap();
+            assert_eq!(request.body.map(|body| body.len()), Some(4 * 1024 * 1024));
+        }
+
+        #[test]
+        fn test_survives_failed_response_write() {
+            let server = HTTPServer::new(
+                HTTPServerClass::Simple,
+                Opts::default(),
+                Arc::new(()),
+                Some(Box::new(|_, opts, _| {
+                    HTTPResponse::new(
+                        opts.protocol.clone(),
+                        ResultCode::OK,
+                        Headers::new(),
+                        Some(vec![b'a'; 64 * 1024]),
+                    )
+                })),
+            );
+            let (stream, mut client) = stream_pair();
+            client
+                .write_all(b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            // Any write on the server side now fails as if the client had gone away
+            stream.shutdown(Shutdown::Write).unwrap();
+            server.handle_stream(stream);
+        }
+
+        #[test]
+        fn test_enables_tcp_keepalive() {
+            let server = HTTPServer::new(
+                HTTPServerClass::Simple,
+                Opts {
+                    tcp_keepalive: Some(crate::TcpKeepalive {
+                        idle: Duration::from_secs(30),
+                        interval: Duration::from_secs(5),
+                    }),
+                    ..Opts::default()
+                },
+                Arc::new(()),
+                None,
+            );
+            let (stream, _client) = stream_pair();
+            assert!(!socket2::SockRef::from(&stream).keepalive().unwrap());
+            server.set_keepalive(socket2::SockRef::from(&stream));
+            assert!(socket2::SockRef::from(&stream).keepalive().unwrap());
+        }
+
+        #[test]
+        fn test_sets_socket_buffer_sizes() {
+            let (stream, _client) = stream_pair();
+            let socket = socket2::SockRef::from(&stream);
+            let (recv, send) = (
+                socket.recv_buffer_size().unwrap(),
+                socket.send_buffer_size().unwrap(),
+            );
+            let defaults =
+                HTTPServer::new(HTTPServerClass::Simple, Opts::default(), Arc::new(()), None);
+            defaults.set_buffers(socket2::SockRef::from(&stream));
+            assert_eq!(socket.recv_buffer_size().unwrap(), recv);
+            assert_eq!(socket.send_buffer_size().unwrap(), send);
+
+            // Small enough to be allowed anywhere, Linux doubles it for bookkeeping
+            let size = 6000;
+            let sized = HTTPServer::new(
+                HTTPServerClass::Simple,
+                Opts {
+                    recv_buffer: Some(size),
+                    send_buffer: Some(size),
+                    ..Opts::default()
+                },
+                Arc::new(()),
+                None,
+            );
+            sized.set_buffers(socket2::SockRef::from(&stream));
+            assert!((size..=size * 2).contains(&socket.recv_buffer_size().unwrap()));
+            assert!((size..=size * 2).contains(&socket.send_buffer_size().unwrap()));
+        }
+
+        // A connection that only counts how many writes reach it, and their sizes
+        #[derive(Default)]
+        struct WriteLog(Vec<usize>);
+
+        impl Read for WriteLog {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Ok(0)
+            }
+        }
+
+        impl Write for WriteLog {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.push(buf.len());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        fn writes_with(write_buffer: usize) -> Vec<usize> {
+            let server = HTTPServer::new(
+                HTTPServerClass::Simple,
+                Opts {
+                    write_buffer,
+                    ..Opts::default()
+                },
+                Arc::new(()),
+                Some(Box::new(|_, opts, _: &Arc<()>| {
+                    HTTPResponse::new(
+                        opts.protocol.clone(),
+                        ResultCode::OK,
+                        Headers::new(),
+                        Some(b"ok".to_vec()),
+                    )
+                })),
+            );
+            let mut conn = WriteLog::default();
+            server.handle_request(
+                &mut conn,
+                None,
+                b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n",
+                None,
+                "test",
+                false,
+            );
+            conn.0
+        }
+
+        #[test]
+        fn test_write_buffer_sends_the_response_at_once() {
+            assert_eq!(writes_with(Opts::default().write_buffer).len(), 1);
+            // Unbuffered the body follows the head on its own
+            let unbuffered = writes_with(0);
+            assert_eq!(unbuffered.len(), 2);
+            assert_eq!(unbuffered[1], 2);
+        }
+
+        #[test]
+        fn test_unbuffered_writes_set_nodelay() {
+            let (stream, _client) = stream_pair();
+            let buffered =
+                HTTPServer::new(HTTPServerClass::Simple, Opts::default(), Arc::new(()), None);
+            buffered.set_nodelay(&stream);
+            assert!(!stream.nodelay().unwrap());
+            let immediate = HTTPServer::new(
+                HTTPServerClass::Simple,
+                Opts {
+                    write_buffer: 0,
+                    ..Opts::default()
+                },
+                Arc::new(()),
+                None,
+            );
+            immediate.set_nodelay(&stream);
+            assert!(stream.nodelay().unwrap());
+        }
+
+        #[test]
+        fn test_connection_ceiling_holds_under_load() {
+            let server = Arc::new(HTTPServer::new(
+                HTTPServerClass::ThreadPooled(1),
+                Opts {
+                    max_connections: 2,
+                    ..Opts::default()
+                },
+                Arc::new(AtomicBool::new(false)),
+                Some(Box::new(|_, opts, release: &Arc<AtomicBool>| {
+                    while !release.load(Ordering::SeqCst) {
+                        std::thread::sleep(Duration::from_millis(5));
+                    }
+                    let mut headers = Headers::new();
+                    headers.set(Header::ContentLength(2));
+                    HTTPResponse::new(
+                        opts.protocol.clone(),
+                        ResultCode::OK,
+                        headers,
+                        Some(b"ok".to_vec()),
+                    )
+                })),
+            ));
+            let connections = server.connections();
+            let mut tpq = HTTPServer::pool(&server, 1);
+
+            let mut clients = Vec::new();
+            for _ in 0..10 {
+                let (stream, client) = stream_pair();
+                server.enqueue(&mut tpq, stream);
+                clients.push(client);
+                assert!(connections.load(Ordering::SeqCst) <= 2);
+            }
+            assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+            for client in clients.iter_mut() {
+                let _ = client.write_all(b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n");
+            }
+            server.state.store(true, Ordering::SeqCst);
+            let statuses: Vec<String> = clients
+                .iter_mut()
+                .map(|client| {
+                    let resp = read_response(client);
+                    resp.lines().next().unwrap().to_string()
+                })
+                .collect();
+            assert_eq!(statuses.iter().filter(|s| s.contains("200")).count(), 2);
+            assert_eq!(statuses.iter().filter(|s| s.contains("503")).count(), 8);
+
+            drop(tpq);
+            assert_eq!(connections.load(Ordering::SeqCst), 0);
+        }
+
+        #[test]
+        fn test_connections_per_ip_are_limited() {
+            let server = Arc::new(HTTPServer::new(
+                HTTPServerClass::Simple,
+                Opts {
+                    max_connections_per_ip: 1,
+                    ..Opts::default()
+                },
+                Arc::new(AtomicBool::new(false)),
+                Some(Box::new(|req, opts, release: &Arc<AtomicBool>| {
+                    if req.uri == "/panic" {
+                        panic!("handler failed");
+                    }
+                    while !release.load(Ordering::SeqCst) {
+                        std::thread::sleep(Duration::from_millis(5));
+                    }
+                    let mut headers = Headers::new();
+                    headers.set(Header::ContentLength(2));
+                    HTTPResponse::new(
+                        opts.protocol.clone(),
+                        ResultCode::OK,
+                        headers,
+                        Some(b"ok".to_vec()),
+                    )
+                })),
+            ));
+            let serve = |request: &'static [u8]| {
+                let (stream, mut client) = stream_pair();
+                client.write_all(request).unwrap();
+                let worker = Arc::clone(&server);
+                let handle = std::thread::spawn(move || worker.handle_stream(stream));
+                (client, handle)
+            };
+
+            let (mut first, handle) = serve(b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n");
+            while server.per_ip.lock().unwrap().is_empty() {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            let (mut second, _) = serve(b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n");
+            assert!(read_response(&mut second).starts_with("HTTP/1.0 503 Service Unavailable\r\n"));
+
+            server.state.store(true, Ordering::SeqCst);
+            assert!(read_response(&mut first).starts_with("HTTP/1.0 200 OK\r\n"));
+            handle.join().unwrap();
+            assert!(server.per_ip.lock().unwrap().is_empty());
+
+            // A panicking handler still gives its slot back
+            let (_client, handle) = serve(b"GET /panic HTTP/1.0\r\nHost: localhost\r\n\r\n");
+            assert!(handle.join().is_err());
+            assert!(server.per_ip.lock().unwrap().is_empty());
+        }
+
+        #[test]
+        fn test_drain_finishes_in_flight_request() {
+            let release = Arc::new(AtomicBool::new(false));
+            let drain = Arc::new(AtomicBool::new(false));
+            let server = HTTPServer::new(
+                HTTPServerClass::Threaded,
+                Opts::default(),
+                Arc::clone(&release),
+                Some(Box::new(|_, opts, release: &Arc<AtomicBool>| {
+                    while !release.load(Ordering::SeqCst) {
+                        std::thread::sleep(Duration::from_millis(5));
+                    }
+                    let mut headers = Headers::new();
+                    headers.set(Header::ContentLength(2));
+                    HTTPResponse::new(
+                        opts.protocol.clone(),
+                        ResultCode::OK,
+                        headers,
+                        Some(b"ok".to_vec()),
+                    )
+                })),
+            )
+            .with_drain_flag(Arc::clone(&drain));
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let serving = std::thread::spawn(move || server.serve(listener));
+
+            let mut client = TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            drain.store(true, Ordering::SeqCst);
+            // Stays up while the request is in flight
+            std::thread::sleep(Duration::from_millis(200));
+            assert!(!serving.is_finished());
+
+            release.store(true, Ordering::SeqCst);
+            assert!(read_response(&mut client).ends_with("\r\n\r\nok"));
+            serving.join().unwrap();
+            assert!(TcpStream::connect(addr).is_err());
+        }
+
+        #[test]
+        fn test_accepts_are_throttled() {
+            let drain = Arc::new(AtomicBool::new(false));
+            let opts = Opts {
+                accept_rate: Some(20),
+                ..Opts::default()
+            };
+            let server = HTTPServer::new(HTTPServerClass::Simple, opts, Arc::new(()), None)
+                .with_drain_flag(Arc::clone(&drain));
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let serving = std::thread::spawn(move || server.serve(listener));
+
+            // A second's worth goes straight through, the rest wait their turn
+            let start = Instant::now();
+            for _ in 0..30 {
+                let mut client = TcpStream::connect(addr).unwrap();
+                client
+                    .write_all(b"GET /Cargo.toml HTTP/1.0\r\nHost: localhost\r\n\r\n")
+                    .unwrap();
+                assert!(read_response(&mut client).starts_with("HTTP/1.0 200 OK\r\n"));
+            }
+            assert!(start.elapsed() >= Duration::from_millis(400));
+
+            drain.store(true, Ordering::SeqCst);
+            serving.join().unwrap();
+        }
+
+        #[test]
+        fn test_stats_outlive_the_server() {
+            let drain = Arc::new(AtomicBool::new(false));
+            let server = counting_server().with_drain_flag(Arc::clone(&drain));
+            let stats = server.stats();
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let serving = std::thread::spawn(move || server.serve(listener));
+
+            let mut written = 0;
+            for _ in 0..3 {
+                let mut client = TcpStream::connect(addr).unwrap();
+                client
+                    .write_all(b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n")
+                    .unwrap();
+                let mut resp = Vec::new();
+                client.read_to_end(&mut resp).unwrap();
+                written += resp.len() as u64;
+            }
+            drain.store(true, Ordering::SeqCst);
+            serving.join().unwrap();
+
+            assert_eq!(stats.requests(), 3);
+            assert_eq!(stats.bytes_written(), written);
+        }
+
+        fn response_request_id(resp: &str) -> Option<String> {
+            resp.lines()
+                .find_map(|line| line.strip_prefix("X-Request-Id: "))
+                .map(|id| id.to_string())
+        }
+
+        #[test]
+        fn test_echoes_request_id() {
+            let server = Arc::new(counting_server());
+            let (stream, mut client) = stream_pair();
+            let worker = Arc::clone(&server);
+            let handle = std::thread::spawn(move || worker.handle_stream(stream));
+
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Request-Id: trace-1234\r\n\r\n")
+                .unwrap();
+            let resp = read_response(&mut client);
+            assert_eq!(response_request_id(&resp), Some("trace-1234".to_string()));
+
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let resp = read_response(&mut client);
+            let generated = response_request_id(&resp).expect("No request id generated");
+            assert_eq!(generated.len(), 36);
+            assert_ne!(generated, "trace-1234");
+            handle.join().unwrap();
+        }
+
+        #[test]
+        fn test_rebinds_after_rapid_restart() {
+            let mut opts = Opts {
+                port: 0,
+                ..Opts::default()
+            };
+            for _ in 0..2 {
+                let listener = bind(&opts).unwrap();
+                opts.port = listener.local_addr().unwrap().port();
+                // Closing from the server side leaves the port in TIME_WAIT
+                let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+                let (server, _) = listener.accept().unwrap();
+                drop(server);
+                drop(client);
+                drop(listener);
+            }
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn test_adopts_passed_listener() {
+            use std::os::unix::io::IntoRawFd;
+
+            let passed = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = passed.local_addr().unwrap();
+            let listener = listen(&Opts {
+                port: 1,
+                listen_fd: Some(passed.into_raw_fd()),
+                ..Opts::default()
+            })
+            .unwrap();
+            assert_eq!(listener.local_addr().unwrap(), addr);
+        }
+
+        #[test]
+        fn test_shares_port_with_reuse_port() {
+            let first = bind(&Opts {
+                port: 0,
+                reuse_port: true,
+                ..Opts::default()
+            })
+            .unwrap();
+            let port = first.local_addr().unwrap().port();
+            bind(&Opts {
+                port,
+                reuse_port: true,
+                ..Opts::default()
+            })
+            .unwrap();
+        }
+
+        fn status_line(server: &HTTPServer, request: &[u8]) -> String {
+            let (stream, mut client) = stream_pair();
+            client.write_all(request).unwrap();
+            server.handle_stream(stream);
+            let resp = read_response(&mut client);
+            resp.lines().next().unwrap().to_string()
+        }
+
+        #[test]
+        fn test_unknown_method_is_not_implemented() {
+            let server =
+                HTTPServer::new(HTTPServerClass::Simple, Opts::default(), Arc::new(()), None);
+            assert_eq!(
+                status_line(&server, b"PROPFIND / HTTP/1.0\r\nHost: localhost\r\n\r\n"),
+                "HTTP/1.0 501 Not Implemented"
+            );
+            assert_eq!(
+                status_line(&server, b"PROP(FIND / HTTP/1.0\r\nHost: localhost\r\n\r\n"),
+                "HTTP/1.0 400 Bad Request"
+            );
+        }
+
+        #[test]
+        fn test_extra_space_in_request_line_is_bad_request() {
+            let server =
+                HTTPServer::new(HTTPServerClass::Simple, Opts::default(), Arc::new(()), None);
+            assert_eq!(
+                status_line(&server, b"GET  HTTP/1.0\r\nHost: localhost\r\n\r\n"),
+                "HTTP/1.0 400 Bad Request"
+            );
+        }
+
+        #[test]
+        fn test_asterisk_form_get_is_bad_request() {
+            let server =
+                HTTPServer::new(HTTPServerClass::Simple, Opts::default(), Arc::new(()), None);
+            assert_eq!(
+                status_line(&server, b"GET * HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+                "HTTP/1.0 400 Bad Request"
+            );
+        }
+
+        #[test]
+        fn test_trace_at_origin_with_max_forwards_zero() {
+            let request = b"TRACE /a HTTP/1.0\r\nHost: localhost\r\nMax-Forwards: 0\r\nAuthorization: Basic dXNlcjpwYXNz\r\n\r\n";
+            let server =
+                HTTPServer::new(HTTPServerClass::Simple, Opts::default(), Arc::new(()), None);
+            assert_eq!(
+                status_line(&server, request),
+                "HTTP/1.0 405 Method Not Allowed"
+            );
+
+            let opts = Opts {
+                trace: true,
+                ..Opts::default()
+            };
+            let server = HTTPServer::new(HTTPServerClass::Simple, opts, Arc::new(()), None);
+            let (stream, mut client) = stream_pair();
+            client.write_all(request).unwrap();
+            server.handle_stream(stream);
+            let resp = read_response(&mut client);
+            assert!(resp.starts_with("HTTP/1.0 200 OK"));
+            assert!(resp.contains("Content-Type: message/http\r\n"));
+            let (_, echoed) = resp.split_once("\r\n\r\n").unwrap();
+            assert!(echoed.starts_with("TRACE /a HTTP/1.0\r\n"));
+            assert!(echoed.contains("Max-Forwards: 0\r\n"));
+            assert!(!echoed.contains("Authorization"));
+        }
+
+        #[test]
+        fn test_hooks_observe_request_then_response() {
+            let seen = Arc::new(Mutex::new(Vec::new()));
+            let on_request = Arc::clone(&seen);
+            let on_response = Arc::clone(&seen);
+            let server =
+                HTTPServer::new(HTTPServerClass::Simple, Opts::default(), Arc::new(()), None)
+                    .with_request_hook(Box::new(move |req| {
+                        on_request.lock().unwrap().push(req.uri.clone());
+                    }))
+                    .with_response_hook(Box::new(move |resp| {
+                        let status: String = resp.status.into();
+                        on_response.lock().unwrap().push(status);
+                    }));
+            assert_eq!(
+                status_line(&server, b"GET /missing HTTP/1.0\r\nHost: localhost\r\n\r\n"),
+                "HTTP/1.0 404 Not Found"
+            );
+            assert_eq!(*seen.lock().unwrap(), ["/missing", "404 Not Found"]);
+        }
+
+        #[test]
+        fn test_slow_handler_connection_is_reaped() {
+            let opts = Opts {
+                connection_deadline: Some(Duration::from_millis(100)),
+                ..Opts::default()
+            };
+            let handler: Handler<()> = Box::new(|_, opts, _| {
+                std::thread::sleep(Duration::from_secs(1));
+                HTTPResponse::new(
+                    opts.protocol.clone(),
+                    ResultCode::OK,
+                    Headers::default(),
+                    None,
+                )
+            });
+            let server = Arc::new(HTTPServer::new(
+                HTTPServerClass::Threaded,
+                opts,
+                Arc::new(()),
+                Some(handler),
+            ));
+            let (stream, mut client) = stream_pair();
+            client
+                .write_all(b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            let worker = Arc::clone(&server);
+            let started = Instant::now();
+            let handle = std::thread::spawn(move || worker.handle_stream(stream));
+
+            // Closed on the client before the handler has anything to say
+            let mut buf = [0u8; 64];
+            assert_eq!(client.read(&mut buf).unwrap(), 0);
+            assert!(started.elapsed() < Duration::from_millis(800));
+            handle.join().unwrap();
+        }
+
+        #[test]
+        fn test_serves_under_url_prefix() {
+            let opts = Opts {
+                url_prefix: Some("/app".to_string()),
+                ..Opts::default()
+            };
+            let server = HTTPServer::new(HTTPServerClass::Simple, opts, Arc::new(()), None);
+            assert_eq!(
+                status_line(
+                    &server,
+                    b"GET /app/Cargo.toml HTTP/1.0\r\nHost: localhost\r\n\r\n"
+                ),
+                "HTTP/1.0 200 OK"
+            );
+            assert_eq!(
+                status_line(
+                    &server,
+                    b"GET /Cargo.toml HTTP/1.0\r\nHost: localhost\r\n\r\n"
+                ),
+                "HTTP/1.0 404 Not Found"
+            );
+
+            let (stream, mut client) = stream_pair();
+            client
+                .write_all(b"GET /app/src/ HTTP/1.0\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            server.handle_stream(stream);
+            let resp = read_response(&mut client);
+            assert!(resp.contains("<a href='/app/src/lib.rs'>lib.rs</a>"));
+        }
+
+        #[test]
+        fn test_trailers_follow_chunked_body() {
+            let opts = Opts {
+                protocol: "HTTP/1.1".to_string(),
+                ..Opts::default()
+            };
+            let handler: Handler<()> = Box::new(|_, opts, _| {
+                let body = b"hello".to_vec();
+                let mut trailers = Headers::new();
+                trailers.set(Header::Generic((
+                    "Digest".to_string(),
+                    format!("len={}", body.len()),
+                )));
+                HTTPResponse::new(
+                    opts.protocol.clone(),
+                    ResultCode::OK,
+                    Headers::default(),
+                    Some(body),
+                )
+                .with_trailers(trailers)
+            });
+            let server =
+                HTTPServer::new(HTTPServerClass::Simple, opts, Arc::new(()), Some(handler));
+            let (stream, mut client) = stream_pair();
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            server.handle_stream(stream);
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).unwrap();
+            let (head, body) = resp.split_once("\r\n\r\n").unwrap();
+            assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+            assert!(head.contains("Trailer: Digest"));
+            assert!(!head.contains("Content-Length"));
+            assert_eq!(body, "5\r\nhello\r\n0\r\nDigest: len=5\r\n\r\n");
+        }
+
+        #[test]
+        fn test_access_record_has_user_agent_and_referer() {
+            let request = HTTPRequest::try_from(
+                &b"GET /a HTTP/1.0\r\nHost: localhost\r\nUser-Agent: curl/8.0\r\nReferer: http://example.com/\r\n\r\n".to_vec(),
+            )
+            .unwrap();
+            let mut record = AccessRecord::new(&request, "127.0.0.1:4000", "req-1");
+            assert_eq!(record.user_agent, "curl/8.0");
+            assert_eq!(record.referer, "http://example.com/");
+            record.status = 200;
+            record.length = 5;
+            assert_eq!(
+                record.to_string(),
+                "GET /a HTTP/1.0 200 5 curl/8.0 http://example.com/ 127.0.0.1:4000 req-1"
+            );
+
+            let request =
+                HTTPRequest::try_from(&b"GET /a HTTP/1.0\r\nHost: localhost\r\n\r\n".to_vec())
+                    .unwrap();
+            let record = AccessRecord::new(&request, "127.0.0.1:4000", "req-2");
+            assert_eq!(
+                (record.user_agent.as_str(), record.referer.as_str()),
+                ("-", "-")
+            );
+        }
+
+        #[test]
+        fn test_vhost_matches_with_and_without_port() {
+            let dir = test_dir("vhost", &[("site.txt", b"vhost")]);
+            let opts = Opts {
+                vhosts: HashMap::from([(
+                    "example.com".to_string(),
+                    dir.to_string_lossy().to_string(),
+                )]),
+                ..Opts::default()
+            };
+            let server = HTTPServer::new(HTTPServerClass::Simple, opts, Arc::new(()), None);
+            for host in ["example.com", "example.com:8080", "EXAMPLE.com"] {
+                let request = format!("GET /site.txt HTTP/1.0\r\nHost: {}\r\n\r\n", host);
+                let resp = status_line(&server, request.as_bytes());
+                assert_eq!(resp, "HTTP/1.0 200 OK", "{}", host);
+            }
+            // Other ports and hosts get the server's own directory
+            for host in ["example.com:9090", "localhost"] {
+                let request = format!("GET /site.txt HTTP/1.0\r\nHost: {}\r\n\r\n", host);
+                let resp = status_line(&server, request.as_bytes());
+                assert_eq!(resp, "HTTP/1.0 404 Not Found", "{}", host);
+            }
+        }
+
+        #[test]
+        fn test_early_hints_precede_response() {
+            let opts = Opts {
+                protocol: "HTTP/1.1".to_string(),
+                early_hints: vec![
+                    (
+                        "/".to_string(),
+                        "</style.css>; rel=preload; as=style".to_string(),
+                    ),
+                    (
+                        "/src".to_string(),
+                        "</app.js>; rel=preload; as=script".to_string(),
+                    ),
+                ],
+                ..Opts::default()
+            };
+            let server = HTTPServer::new(HTTPServerClass::Simple, opts, Arc::new(()), None);
+            let (stream, mut client) = stream_pair();
+            client
+                .write_all(
+                    b"GET /Cargo.toml HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                )
+                .unwrap();
+            server.handle_stream(stream);
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).unwrap();
+            let (hints, final_resp) = resp.split_once("\r\n\r\n").unwrap();
+            assert_eq!(
+                hints,
+                "HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload; as=style"
+            );
+            assert!(final_resp.starts_with("HTTP/1.1 200 OK\r\n"));
+
+            // HTTP/1.0 clients can't take an interim response
+            let resp = status_line(
+                &server,
+                b"GET /Cargo.toml HTTP/1.0\r\nHost: localhost\r\n\r\n",
+            );
+            assert_eq!(resp, "HTTP/1.1 200 OK");
+        }
+
+        #[test]
+        fn test_server_header_hidden() {
+            let opts = Opts {
+                hide_server: true,
+                ..Opts::default()
+            };
+            let server = HTTPServer::new(HTTPServerClass::Simple, opts, Arc::new(()), None);
+            for request in [
+                &b"GET /Cargo.toml HTTP/1.0\r\nHost: localhost\r\n\r\n"[..],
+                b"GET /missing HTTP/1.0\r\nHost: localhost\r\n\r\n",
+                b"OPTIONS * HTTP/1.0\r\nHost: localhost\r\n\r\n",
+                b"GET / HTTP/9.9\r\nHost: localhost\r\n\r\n",
+            ] {
+                let (stream, mut client) = stream_pair();
+                client.write_all(request).unwrap();
+                server.handle_stream(stream);
+                let resp = read_response(&mut client);
+                assert!(resp.starts_with("HTTP/1.0 "));
+                assert!(!resp.contains("\r\nServer:"), "{}", resp);
+            }
+        }
+
+        #[test]
+        fn test_method_override_on_post() {
+            let post = b"POST /Cargo.toml HTTP/1.0\r\nHost: localhost\r\nX-HTTP-Method-Override: GET\r\n\r\n";
+            let get = b"GET /Cargo.toml HTTP/1.0\r\nHost: localhost\r\nX-HTTP-Method-Override: CONNECT\r\n\r\n";
+            let server =
+                HTTPServer::new(HTTPServerClass::Simple, Opts::default(), Arc::new(()), None);
+            assert_eq!(
+                status_line(&server, post),
+                "HTTP/1.0 405 Method Not Allowed"
+            );
+
+            let opts = Opts {
+                method_override: true,
+                ..Opts::default()
+            };
+            let server = HTTPServer::new(HTTPServerClass::Simple, opts, Arc::new(()), None);
+            assert_eq!(status_line(&server, post), "HTTP/1.0 200 OK");
+            // Only POST is overridden
+            assert_eq!(status_line(&server, get), "HTTP/1.0 200 OK");
+        }
+
+        #[test]
+        fn test_disabled_method_is_not_allowed() {
+            let opts = Opts {
+                disabled_methods: vec![Method::HEAD],
+                ..Opts::default()
+            };
+            let server = HTTPServer::new(HTTPServerClass::Simple, opts, Arc::new(()), None);
+            let (stream, mut client) = stream_pair();
+            client
+                .write_all(b"HEAD / HTTP/1.0\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            server.handle_stream(stream);
+            let resp = read_response(&mut client);
+            assert!(resp.starts_with("HTTP/1.0 405 Method Not Allowed"));
+            assert!(resp.contains("Allow: GET,OPTIONS\r\n"));
+            assert_eq!(
+                status_line(&server, b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n"),
+                "HTTP/1.0 200 OK"
+            );
+        }
+
+        #[test]
+        fn test_trace_and_connect_refused_by_default() {
+            let server =
+                HTTPServer::new(HTTPServerClass::Simple, Opts::default(), Arc::new(()), None);
+            for request in [
+                &b"TRACE / HTTP/1.0\r\nHost: localhost\r\n\r\n"[..],
+                b"CONNECT example.com:443 HTTP/1.0\r\nHost: example.com:443\r\n\r\n",
+            ] {
+                assert_eq!(
+                    status_line(&server, request),
+                    "HTTP/1.0 405 Method Not Allowed"
+                );
+            }
+
+            let opts = Opts {
+                trace: true,
+                ..Opts::default()
+            };
+            let server = HTTPServer::new(HTTPServerClass::Simple, opts, Arc::new(()), None);
+            assert_eq!(
+                status_line(
+                    &server,
+                    b"CONNECT example.com:443 HTTP/1.0\r\nHost: example.com:443\r\n\r\n"
+                ),
+                "HTTP/1.0 405 Method Not Allowed"
+            );
+        }
+
+        #[test]
+        fn test_request_errors_map_to_status() {
+            let opts = Opts {
+                max_headers: 2,
+                ..Opts::default()
+            };
+            let server = HTTPServer::new(HTTPServerClass::Simple, opts, Arc::new(()), None);
+            assert_eq!(
+                status_line(
+                    &server,
+                    b"GET / HTTP/1.0\r\nHost: localhost\r\nA: 1\r\nB: 2\r\n\r\n"
+                ),
+                "HTTP/1.0 431 Request Header Fields Too Large"
+            );
+            assert_eq!(
+                status_line(&server, b"GET / HTTP/2.0\r\nHost: localhost\r\n\r\n"),
+                "HTTP/1.0 505 HTTP Version Not Supported"
+            );
+            assert_eq!(
+                status_line(&server, b"GET /\r\nHost: localhost\r\n\r\n"),
+                "HTTP/1.0 400 Bad Request"
+            );
+            assert_eq!(
+                status_line(
+                    &server,
+                    b"POST / HTTP/1.1\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n0\r\n\r\n"
+                ),
+                "HTTP/1.0 400 Bad Request"
+            );
+        }
+
+        #[test]
+        fn test_unsupported_body_encoding_is_refused() {
+            let server =
+                HTTPServer::new(HTTPServerClass::Simple, Opts::default(), Arc::new(()), None);
+            assert_eq!(
+                status_line(
+                    &server,
+                    b"POST / HTTP/1.0\r\nHost: localhost\r\nContent-Encoding: br\r\nContent-Length: 4\r\n\r\nWiki"
+                ),
+                "HTTP/1.0 415 Unsupported Media Type"
+            );
+        }
+
+        #[test]
+        fn test_cors_origin_on_actual_responses() {
+            let opts = Opts {
+                cors_origin: Some("https://app.example.com".to_string()),
+                ..Opts::default()
+            };
+            let server = HTTPServer::new(HTTPServerClass::Simple, opts, Arc::new(()), None);
+            for method in ["GET", "HEAD"] {
+                let (stream, mut client) = stream_pair();
+                client
+                    .write_all(
+                        format!(
+                            "{} /Cargo.toml HTTP/1.0\r\nHost: localhost\r\nOrigin: https://app.example.com\r\n\r\n",
+                            method
+                        )
+                        .as_bytes(),
+                    )
+                    .unwrap();
+                server.handle_stream(stream);
+                let mut resp = String::new();
+                client.read_to_string(&mut resp).unwrap();
+                assert!(resp.starts_with("HTTP/1.0 200 OK\r\n"));
+                assert!(
+                    resp.contains("\r\nAccess-Control-Allow-Origin: https://app.example.com\r\n")
+                );
+                assert!(resp.contains("\r\nVary: Origin\r\n"));
+            }
+        }
+
+        #[test]
+        fn test_parent_segments_are_refused() {
+            let opts = Opts {
+                deny_dotfiles: false,
+                ..Opts::default()
+            };
+            let server = HTTPServer::new(HTTPServerClass::Simple, opts, Arc::new(()), None);
+            assert_eq!(
+                status_line(
+                    &server,
+                    b"GET /src/../Cargo.toml HTTP/1.0\r\nHost: localhost\r\n\r\n"
+                ),
+                "HTTP/1.0 400 Bad Request"
+            );
+            assert_eq!(
+                status_line(
+                    &server,
+                    b"GET //src/./lib.rs HTTP/1.0\r\nHost: localhost\r\n\r\n"
+                ),
+                "HTTP/1.0 200 OK"
+            );
+        }
+
+        #[test]
+        fn test_oversized_decoded_body_is_refused() {
+            let opts = Opts {
+                max_decoded_body: 1024,
+                ..Opts::default()
+            };
+            let server = HTTPServer::new(HTTPServerClass::Simple, opts, Arc::new(()), None);
+            let body = crate::compression::compress_bytes(
+                &[0; 64 * 1024],
+                &crate::compression::ContentEncoding::GZIP,
+                9,
+            )
+            .unwrap();
+            let mut request = format!(
+                "PUT /upload HTTP/1.0\r\nHost: localhost\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+                body.len()
+            )
+            .into_bytes();
+            request.extend_from_slice(&body);
+            assert_eq!(
+                status_line(&server, &request),
+                "HTTP/1.0 413 Payload Too Large"
+            );
+        }
+
+        #[test]
+        fn test_serves_in_memory_body() {
+            let server =
+                HTTPServer::new(HTTPServerClass::Simple, Opts::default(), Arc::new(()), None)
+                    .with_body(b"piped payload".to_vec(), "text/plain");
+            let (stream, mut client) = stream_pair();
+            client
+                .write_all(b"GET /any/path HTTP/1.0\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            server.handle_stream(stream);
+            let resp = read_response(&mut client);
+            assert!(resp.starts_with("HTTP/1.0 200 OK"));
+            assert!(resp.contains("Content-Type: text/plain\r\n"));
+            assert!(resp.ends_with("\r\n\r\npiped payload"));
+
+            assert_eq!(
+                status_line(&server, b"POST / HTTP/1.0\r\nHost: localhost\r\n\r\n"),
+                "HTTP/1.0 405 Method Not Allowed"
+            );
+        }
+
+        #[test]
+        fn test_unindexed_directory_falls_through_to_handler() {
+            let opts = Opts {
+                directory_listing: false,
+                ..Opts::default()
+            };
+            let server = HTTPServer::new(HTTPServerClass::Simple, opts, Arc::new(()), None)
+                .with_directory_handler(Box::new(|req, opts, _| {
+                    let body = format!("search {}", req.uri).into_bytes();
+                    let mut headers = Headers::new();
+                    headers.set(Header::ContentLength(body.len()));
+                    HTTPResponse::new(opts.protocol.clone(), ResultCode::OK, headers, Some(body))
+                }));
+            let get = |request: &[u8]| {
+                let (stream, mut client) = stream_pair();
+                client.write_all(request).unwrap();
+                server.handle_stream(stream);
+                read_response(&mut client)
+            };
+
+            let resp = get(b"GET /src HTTP/1.0\r\nHost: localhost\r\n\r\n");
+            assert!(resp.starts_with("HTTP/1.0 200 OK\r\n"));
+            assert!(resp.ends_with("search /src"));
+            // Files are still served, and only GET and HEAD fall through
+            let resp = get(b"GET /Cargo.toml HTTP/1.0\r\nHost: localhost\r\n\r\n");
+            assert!(resp.contains("[package]"));
+            let resp = get(b"DELETE /src HTTP/1.0\r\nHost: localhost\r\n\r\n");
+            assert!(!resp.contains("search"));
+        }
+
+        #[test]
+        fn test_post_without_handler_is_not_allowed() {
+            let server = counting_server();
+            let req = HTTPRequest {
+                method: Method::POST,
+                uri: "/index.html".to_string(),
+                version: "HTTP/1.0".to_string(),
+                headers: Headers::new(),
+                body: Some(b"data".to_vec()),
+                trailers: None,
+                peer: None,
+            };
+            let resp = HTTPServer::<()>::default_handler(req, &server.opts, None);
+            assert_eq!(resp.status, ResultCode::MethodNotAllowed);
+            assert_eq!(
+                resp.headers.get(HeaderVariant::Allow),
+                Some(Header::Allow(vec![
+                    Method::GET,
+                    Method::HEAD,
+                    Method::OPTIONS
+                ]))
+            );
+        }
+
+        #[test]
+        fn test_handler_receives_peer_address() {
+            let server = HTTPServer::new(
+                HTTPServerClass::Simple,
+                Opts::default(),
+                Arc::new(()),
+                Some(Box::new(|req, opts, _| {
+                    let peer = req.peer.map(|addr| addr.to_string()).unwrap_or_default();
+                    HTTPResponse::new(
+                        opts.protocol.clone(),
+                        ResultCode::OK,
+                        Headers::new(),
+                        Some(peer.into_bytes()),
+                    )
+                })),
+            );
+
+            let (stream, mut client) = stream_pair();
+            client
+                .write_all(b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            server.handle_stream(stream);
+
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).unwrap();
+            assert!(resp.ends_with(&client.local_addr().unwrap().to_string()));
+        }
+
+        fn bad_request_body(opts: Opts) -> String {
+            let server = HTTPServer::new(HTTPServerClass::Simple, opts, Arc::new(()), None);
+            let (stream, mut client) = stream_pair();
+            client
+                .write_all(b"GET / HTTP/1.0\r\nDate: yesterday\r\n\r\n")
+                .unwrap();
+            server.handle_stream(stream);
+
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).unwrap();
+            assert!(resp.starts_with("HTTP/1.0 400 Bad Request\r\n"));
+            resp
+        }
+
+        #[test]
+        fn test_handler_shares_state() {
+            let server = HTTPServer::new(
+                HTTPServerClass::Simple,
+                Opts::default(),
+                Arc::new(Mutex::new(0usize)),
+                Some(Box::new(|_, opts, counter: &Arc<Mutex<usize>>| {
+                    let mut count = counter.lock().unwrap();
+                    *count += 1;
+                    HTTPResponse::new(
+                        opts.protocol.clone(),
+                        ResultCode::OK,
+                        Headers::new(),
+                        Some(count.to_string().into_bytes()),
+                    )
+                })),
+            );
+
+            for expected in 1..=3 {
+                let (stream, mut client) = stream_pair();
+                client
+                    .write_all(b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n")
+                    .unwrap();
+                server.handle_stream(stream);
+
+                let mut resp = String::new();
+                client.read_to_string(&mut resp).unwrap();
+                assert!(resp.ends_with(&expected.to_string()));
+            }
+            assert_eq!(*server.state.lock().unwrap(), 3);
+        }
+
+        // Reads a single length-delimited response from the client side
+        fn read_response(client: &mut TcpStream) -> String {
+            let mut resp = Vec::new();
+            let mut buf = [0u8; 1024];
+            loop {
+                let n = client.read(&mut buf).unwrap();
+                assert_ne!(n, 0, "Connection closed mid-response");
+                resp.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&resp).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let len = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("Content-Length: "))
+                        .map(|len| len.parse::<usize>().unwrap())
+                        .unwrap_or(0);
+                    if body.len() >= len {
+                        return text;
+                    }
+                }
+            }
         }
+
+        fn counting_server() -> HTTPServer<Mutex<usize>> {
+            HTTPServer::new(
+                HTTPServerClass::Simple,
+                Opts::default(),
+                Arc::new(Mutex::new(0usize)),
+                Some(Box::new(|_, opts, counter: &Arc<Mutex<usize>>| {
+                    *counter.lock().unwrap() += 1;
+                    let mut headers = Headers::new();
+                    headers.set(Header::ContentLength(2));
+                    HTTPResponse::new(
+                        opts.protocol.clone(),
+                        ResultCode::OK,
+                        headers,
+                        Some(b"ok".to_vec()),
+                    )
+                })),
+            )
+        }
+
+        #[test]
+        fn test_streaming_handler_reads_large_bodies_on_demand() {
+            let server = Arc::new(
+                HTTPServer::new(HTTPServerClass::Simple, Opts::default(), Arc::new(()), None)
+                    .with_streaming_handler(
+                        1024,
+                        Box::new(|req, body, opts, _| {
+                            let mut received = Vec::new();
+                            body.read_to_end(&mut received).unwrap();
+                            let text = format!("{} {}", received.len(), req.body.is_some());
+                            let mut headers = Headers::new();
+                            headers.set(Header::ContentLength(text.len()));
+                            HTTPResponse::new(
+                                opts.protocol.clone(),
+                                ResultCode::OK,
+                                headers,
+                                Some(text.into_bytes()),
+                            )
+                        }),
+                    ),
+            );
+            let (stream, mut client) = stream_pair();
+            let worker = Arc::clone(&server);
+            let handle = std::thread::spawn(move || worker.handle_stream(stream));
+
+            let large = vec![b'a'; 64 * 1024];
+            client
+                .write_all(
+                    format!(
+                        "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+                        large.len()
+                    )
+                    .as_bytes(),
+                )
+                .unwrap();
+            client.write_all(&large).unwrap();
+            assert!(read_response(&mut client).ends_with("\r\n\r\n65536 false"));
+
+            client
+                .write_all(
+                    b"POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nsmall",
+                )
+                .unwrap();
+            assert!(read_response(&mut client).ends_with("\r\n\r\n5 true"));
+
+            drop(client);
+            handle.join().unwrap();
+        }
+
+        #[test]
+        fn test_keep_alive_reuses_connection() {
+            let server = Arc::new(counting_server());
+            let (stream, mut client) = stream_pair();
+            let worker = Arc::clone(&server);
+            let handle = std::thread::spawn(move || worker.handle_stream(stream));
+
+            for _ in 0..2 {
+                client
+                    .write_all(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+                    .unwrap();
+                let resp = read_response(&mut client);
+                assert!(resp.contains("Connection: keep-alive\r\n"));
+            }
+            drop(client);
+            handle.join().unwrap();
+            assert_eq!(*server.state.lock().unwrap(), 2);
+        }
+
+        #[test]
+        fn test_range_past_end_is_not_satisfiable() {
+            let server =
+                HTTPServer::new(HTTPServerClass::Simple, Opts::default(), Arc::new(()), None);
+            let size = std::fs::metadata("Cargo.toml").unwrap().len();
+            let (stream, mut client) = stream_pair();
+            let request = format!(
+                "GET /Cargo.toml HTTP/1.0\r\nHost: localhost\r\nRange: bytes={}-\r\n\r\n",
+                size + 100
+            );
+            client.write_all(request.as_bytes()).unwrap();
+            server.handle_stream(stream);
+
+            let resp = read_response(&mut client);
+            assert!(resp.starts_with("HTTP/1.0 416 Range Not Satisfiable\r\n"));
+            assert!(resp.contains(&format!("\r\nContent-Range: bytes */{}\r\n", size)));
+        }
+
+        #[test]
+        fn test_cached_route_invokes_handler_once() {
+            let dir = test_dir("response-cache", &[]);
+            let server = counting_server().with_response_cache(
+                ResponseCache::new(dir.to_path_buf(), Duration::from_secs(60)).route("/report"),
+            );
+            for _ in 0..2 {
+                let (stream, mut client) = stream_pair();
+                client
+                    .write_all(b"GET /report HTTP/1.0\r\nHost: localhost\r\n\r\n")
+                    .unwrap();
+                server.handle_stream(stream);
+                let resp = read_response(&mut client);
+                assert!(resp.starts_with("HTTP/1.0 200 OK\r\n"));
+                assert!(resp.ends_with("\r\n\r\nok"));
+            }
+            assert_eq!(*server.state.lock().unwrap(), 1);
+
+            // Other paths always reach the handler
+            let (stream, mut client) = stream_pair();
+            client
+                .write_all(b"GET /other HTTP/1.0\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            server.handle_stream(stream);
+            assert!(read_response(&mut client).starts_with("HTTP/1.0 200 OK\r\n"));
+            assert_eq!(*server.state.lock().unwrap(), 2);
+        }
+
+        #[test]
+        fn test_pipelined_requests_in_one_write() {
+            let server = counting_server();
+            let (stream, mut client) = stream_pair();
+            client
+                .write_all(
+                    b"GET /a HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                    POST /b HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n0\r\n\r\n\
+                    GET /c HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                )
+                .unwrap();
+            server.handle_stream(stream);
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).unwrap();
+            assert_eq!(resp.matches("HTTP/1.0 200 OK\r\n").count(), 3);
+            assert_eq!(*server.state.lock().unwrap(), 3);
+        }
+
+        #[test]
+        fn test_cached_responses_keep_their_encoding() {
+            let page = "<p>hello</p>".repeat(100);
+            let dir = test_dir("encoding-cache", &[("page.html", page.as_bytes())]);
+            let cache = test_dir("encoding-cache-entries", &[]);
+            let opts = Opts {
+                directory: dir.to_str().unwrap().to_string(),
+                ..Opts::default()
+            };
+            let server = HTTPServer::new(HTTPServerClass::Simple, opts, Arc::new(()), None)
+                .with_response_cache(
+                    ResponseCache::new(cache.to_path_buf(), Duration::from_secs(60)).route("/"),
+                );
+            let (stream, mut client) = stream_pair();
+            client
+                .write_all(
+                    b"GET /page.html HTTP/1.0\r\nHost: localhost\r\nAccept-Encoding: gzip\r\n\r\n",
+                )
+                .unwrap();
+            server.handle_stream(stream);
+            assert!(read_response(&mut client).contains("\r\nContent-Encoding: gzip\r\n"));
+
+            let (stream, mut client) = stream_pair();
+            client
+                .write_all(b"GET /page.html HTTP/1.0\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            server.handle_stream(stream);
+            let resp = read_response(&mut client);
+            assert!(!resp.contains("Content-Encoding"));
+            assert!(resp.ends_with(&page));
+        }
+
+        #[test]
+        fn test_authenticated_responses_are_not_cached() {
+            let dir = test_dir("private-cache", &[]);
+            let opts = Opts {
+                auth: Some(crate::Auth {
+                    username: "admin".to_string(),
+                    password: "secret".to_string(),
+                }),
+                ..Opts::default()
+            };
+            let server = HTTPServer::new(HTTPServerClass::Simple, opts, Arc::new(()), None)
+                .with_response_cache(
+                    ResponseCache::new(dir.to_path_buf(), Duration::from_secs(60)).route("/"),
+                );
+
+            let (stream, mut client) = stream_pair();
+            client
+                .write_all(
+                    b"GET /Cargo.toml HTTP/1.0\r\nHost: localhost\r\nAuthorization: Basic YWRtaW46c2VjcmV0\r\n\r\n",
+                )
+                .unwrap();
+            server.handle_stream(stream);
+            assert!(read_response(&mut client).starts_with("HTTP/1.0 200 OK\r\n"));
+
+            let (stream, mut client) = stream_pair();
+            client
+                .write_all(b"GET /Cargo.toml HTTP/1.0\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            server.handle_stream(stream);
+            assert!(read_response(&mut client).starts_with("HTTP/1.0 401 Unauthorized\r\n"));
+        }
+
+        #[test]
+        fn test_http10_keep_alive_for_static_file() {
+            let server = Arc::new(HTTPServer::new(
+                HTTPServerClass::Simple,
+                Opts::default(),
+                Arc::new(()),
+                None,
+            ));
+            let (stream, mut client) = stream_pair();
+            let worker = Arc::clone(&server);
+            let handle = std::thread::spawn(move || worker.handle_stream(stream));
+
+            for _ in 0..2 {
+                client
+                    .write_all(b"GET /Cargo.toml HTTP/1.0\r\nHost: localhost\r\nConnection: keep-alive\r\n\r\n")
+                    .unwrap();
+                let resp = read_response(&mut client);
+                assert!(resp.starts_with("HTTP/1.0 200 OK"));
+                assert!(resp.contains("Content-Length: "));
+                assert!(resp.contains("Connection: keep-alive\r\n"));
+            }
+            drop(client);
+            handle.join().unwrap();
+        }
+
+        #[test]
+        fn test_http10_response_is_never_chunked() {
+            let opts = Opts {
+                protocol: "HTTP/1.1".to_string(),
+                ..Opts::default()
+            };
+            let handler: Handler<()> = Box::new(|_, opts, _| {
+                let mut trailers = Headers::new();
+                trailers.set(Header::Generic(("Digest".to_string(), "len=5".to_string())));
+                let mut headers = Headers::default();
+                headers.set(Header::ContentLength(5));
+                HTTPResponse::new(
+                    opts.protocol.clone(),
+                    ResultCode::OK,
+                    headers,
+                    Some(b"hello".to_vec()),
+                )
+                .with_trailers(trailers)
+            });
+            let server =
+                HTTPServer::new(HTTPServerClass::Simple, opts, Arc::new(()), Some(handler));
+            let (stream, mut client) = stream_pair();
+            client
+                .write_all(b"GET / HTTP/1.0\r\nHost: localhost\r\nConnection: keep-alive\r\n\r\n")
+                .unwrap();
+            let handle = std::thread::spawn(move || server.handle_stream(stream));
+            let resp = read_response(&mut client);
+            assert!(resp.contains("Content-Length: 5\r\n"));
+            assert!(resp.contains("Connection: keep-alive\r\n"));
+            assert!(!resp.contains("chunked"));
+            assert!(resp.ends_with("\r\n\r\nhello"));
+            drop(client);
+            handle.join().unwrap();
+        }
+
+        #[test]
+        fn test_refused_post_body_is_read_in_full() {
+            let server = Arc::new(HTTPServer::new(
+                HTTPServerClass::Simple,
+                Opts::default(),
+                Arc::new(()),
+                None,
+            ));
+            let (stream, mut client) = stream_pair();
+            let worker = Arc::clone(&server);
+            let handle = std::thread::spawn(move || worker.handle_stream(stream));
+
+            let body = "x".repeat(10_000);
+            client
+                .write_all(
+                    format!(
+                        "POST /upload HTTP/1.0\r\nHost: localhost\r\nConnection: keep-alive\r\nContent-Length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                    .as_bytes(),
+                )
+                .unwrap();
+            let resp = read_response(&mut client);
+            assert!(resp.starts_with("HTTP/1.0 405 Method Not Allowed"));
+            assert!(resp.contains("Connection: keep-alive\r\n"));
+
+            // Nothing of the body is left over to be mistaken for the next request
+            client
+                .write_all(b"GET /Cargo.toml HTTP/1.0\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            assert!(read_response(&mut client).starts_with("HTTP/1.0 200 OK"));
+            drop(client);
+            handle.join().unwrap();
+        }
+
+        #[test]
+        fn test_closes_after_max_requests() {
+            let mut server = counting_server();
+            server.opts = Arc::new(Opts {
+                max_requests_per_connection: 2,
+                ..Opts::default()
+            });
+            let server = Arc::new(server);
+            let (stream, mut client) = stream_pair();
+            let worker = Arc::clone(&server);
+            let handle = std::thread::spawn(move || worker.handle_stream(stream));
+
+            let request = b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n";
+            client.write_all(request).unwrap();
+            assert!(read_response(&mut client).contains("Connection: keep-alive\r\n"));
+            client.write_all(request).unwrap();
+            assert!(read_response(&mut client).contains("Connection: close\r\n"));
+            handle.join().unwrap();
+
+            // The third request goes nowhere, the server already hung up
+            let _ = client.write_all(request);
+            let mut buf = [0u8; 64];
+            assert!(!matches!(client.read(&mut buf), Ok(n) if n > 0));
+            assert_eq!(*server.state.lock().unwrap(), 2);
+        }
+
+        #[test]
+        fn test_connection_close_ends_connection() {
+            let server = counting_server();
+            let (stream, mut client) = stream_pair();
+            client
+                .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            server.handle_stream(stream);
+
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).unwrap();
+            assert!(resp.contains("Connection: close\r\n"));
+        }
+
+        #[test]
+        fn test_bad_request_detail_when_verbose() {
+            let resp = bad_request_body(Opts {
+                verbose_errors: true,
+                ..Opts::default()
+            });
+            assert!(resp.contains("Unable to parse Date value yesterday"));
+        }
+
+        #[test]
+        fn test_bad_request_generic_by_default() {
+            let resp = bad_request_body(Opts::default());
+            assert!(resp.ends_with(&error_page(ResultCode::BadRequest)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_accepts_defaults() {
+        assert_eq!(Opts::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_rejects_missing_directory() {
+        let opts = Opts {
+            directory: "./does-not-exist".to_string(),
+            ..Opts::default()
+        };
+        assert_eq!(
+            opts.validate(),
+            Err("./does-not-exist is not a directory".to_string())
+        );
+    }
+
+    #[test]
+    fn test_validate_rejects_header_injection() {
+        let opts = Opts {
+            cors_origin: Some("https://example.com\r\nSet-Cookie: a=b".to_string()),
+            ..Opts::default()
+        };
+        assert_eq!(
+            opts.validate(),
+            Err(
+                "\"https://example.com\\r\\nSet-Cookie: a=b\" can't be sent in a header"
+                    .to_string()
+            )
+        );
+        let mut opts = Opts::default();
+        opts.mime_overrides
+            .insert("log".to_string(), "text/plain\nX-Injected: 1".to_string());
+        assert!(opts.validate().is_err());
+    }
+
+    #[test]
+    fn test_rejects_malformed_auth() {
+        assert!(Auth::try_from("admin").is_err());
+        assert!(Auth::try_from(":password").is_err());
+        assert_eq!(
+            Auth::try_from("admin:pass:word"),
+            Ok(Auth {
+                username: "admin".to_string(),
+                password: "pass:word".to_string(),
+            })
+        );
+
+        let opts = Opts {
+            auth_rules: vec![(
+                "/admin".to_string(),
+                Auth {
+                    username: String::new(),
+                    password: "password".to_string(),
+                },
+            )],
+            ..Opts::default()
+        };
+        assert!(opts.validate().is_err());
     }
 }
@@ -1,79 +1,154 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
 use base64::Engine;
 use chrono::Utc;
 
 use crate::{
-    file::{File, FileError},
+    compression,
+    file::{File, FileError, GzipStore},
     http10::{
-        content_codings::ContentEncoding,
+        content_codings::{self, ContentEncoding},
         headers::{Header, HeaderVariant, Headers},
+        methods::Method,
         request::HTTPRequest,
         response::HTTPResponse,
         result_codes::ResultCode,
     },
-    util::html::{dir_listing, error_page},
+    util::{
+        etag,
+        html::{dir_listing, error_page},
+    },
     Auth, Opts,
 };
 
 #[derive(Debug)]
 pub struct AuthError {}
 
-pub fn get_handler(req: &HTTPRequest, opts: &Opts) -> HTTPResponse {
+// Answers GET and HEAD from the served directory, taking gzipped copies from the
+// store when it has them
+pub fn get_handler(req: &HTTPRequest, opts: &Opts, gzipped: Option<&GzipStore>) -> HTTPResponse {
+    let resp = load_response(req, opts, gzipped);
+    // Ranges count bytes of the identity representation, so a partial response is
+    // never encoded whatever Accept-Encoding says. Conditionals are settled first,
+    // a client revalidating a partial download gets its 304 rather than a range.
+    let range = match &resp.body {
+        Some(body) if resp.status == ResultCode::OK => requested_range(req, body.len()),
+        _ => None,
+    };
+    let mut resp = match range {
+        Some(range) => partial_response(resp, range, opts),
+        None => compress_response(req, resp, opts),
+    };
+    // Whatever get_handler answers with a body can be resumed with a range
+    if matches!(resp.status, ResultCode::OK | ResultCode::PartialContent) {
+        resp.headers.set(Header::AcceptRanges("bytes".to_string()));
+    }
+    // Nothing we could encode it with and the client refuses it as is
+    if resp.status == ResultCode::OK
+        && resp.headers.get(HeaderVariant::ContentEncoding).is_none()
+        && !identity_accepted(req)
+    {
+        resp = not_acceptable(Headers::new(), opts);
+    }
+    // The body is still assembled for HEAD so its length and ETag match GET
+    if req.method == Method::HEAD {
+        resp.body = None;
+    }
+    resp
+}
+
+fn load_response(req: &HTTPRequest, opts: &Opts, gzipped: Option<&GzipStore>) -> HTTPResponse {
     let mut headers = Headers::new();
     headers.set(Header::Date(Utc::now().into()));
     headers.set(Header::Server("Rusty Webserver".to_string()));
 
-    let f = File::try_load(&req.uri, &opts.directory);
+    let f = File::try_load(&req.uri, opts);
     match f {
         Ok(mut file) => {
-            let cond_modified = req.headers.get(HeaderVariant::IfModifiedSince);
-            if let Some(cond_modified) = cond_modified {
-                let Header::IfModifiedSince(dt) = cond_modified else {
-                    unimplemented!()
-                };
-                if dt > file.get_modified() {
-                    return HTTPResponse::new(
-                        opts.protocol.clone(),
-                        ResultCode::NotModified,
-                        headers,
-                        None,
-                    );
-                }
+            if !accepted(req, &file.get_mime(), opts) {
+                return not_acceptable(headers, opts);
             }
-            let encodings = req.headers.get(HeaderVariant::ContentEncoding);
-
-            match encodings {
-                Some(Header::AcceptEncoding(encodings)) => {
-                    if encodings
-                        .iter()
-                        .find(|encoding| **encoding == ContentEncoding::TOKEN)
-                        .is_none()
-                    {
-                        headers.set(Header::ContentEncoding(encodings[0].clone()));
-                        match file.compress(&encodings[0], opts.ratio) {
-                            Ok(f) => file = f,
-                            Err(err) => {
-                                log::error!("Unable to compress file: {}", err.to_string());
-                                headers = Headers::default();
-                                headers.set(Header::ContentType("text/html".to_string()));
-                                return HTTPResponse::new(
-                                    opts.protocol.clone(),
-                                    ResultCode::InternalServerError,
-                                    headers,
-                                    Some(
-                                        error_page(ResultCode::InternalServerError)
-                                            .as_bytes()
-                                            .to_vec(),
-                                    ),
-                                );
-                            }
-                        }
+            // Revalidations get the directive too, it may have changed since
+            if let Some(directive) = cache_directive(file.get_extension(), &file.get_mime(), opts) {
+                headers.set(Header::Generic((
+                    "Cache-Control".to_string(),
+                    directive.to_string(),
+                )));
+            }
+            let mut tag = etag::from_content(&file.get_content());
+            // If-None-Match takes precedence over If-Modified-Since (RFC 7232 6)
+            let not_modified = match req.headers.get(HeaderVariant::IfNoneMatch) {
+                Some(Header::IfNoneMatch(tags)) => etag::none_match(&tags, &tag),
+                _ => match req.headers.get(HeaderVariant::IfModifiedSince) {
+                    // HTTP dates only carry whole seconds
+                    Some(Header::IfModifiedSince(dt)) => {
+                        file.get_modified().timestamp() <= dt.timestamp()
                     }
+                    _ => false,
+                },
+            };
+            if not_modified {
+                headers.set(Header::ETag(tag));
+                return HTTPResponse::new(
+                    opts.protocol.clone(),
+                    ResultCode::NotModified,
+                    headers,
+                    None,
+                );
+            }
+            // Some APIs prefer saying outright that there is nothing to send
+            if opts.empty_as_no_content && file.get_size() == 0 {
+                headers.set(Header::LastModified(file.get_modified()));
+                headers.set(Header::ETag(tag));
+                return HTTPResponse::new(
+                    opts.protocol.clone(),
+                    ResultCode::NoContent,
+                    headers,
+                    None,
+                );
+            }
+            // Copies compressed ahead of time beat compressing on every request,
+            // but can't be cut into ranges of the original
+            let ranged = requested_range(req, file.get_size()).is_some();
+            if let (Some(Header::AcceptEncoding(accepted)), false, true) = (
+                req.headers.get(HeaderVariant::AcceptEncoding),
+                ranged,
+                opts.compression,
+            ) {
+                let encodings = content_codings::preference(
+                    &accepted,
+                    &[
+                        ContentEncoding::BR,
+                        ContentEncoding::GZIP,
+                        ContentEncoding::IDENTITY,
+                    ],
+                );
+                // Anything ranked below identity isn't worth serving
+                let wanted: Vec<ContentEncoding> = encodings
+                    .into_iter()
+                    .take_while(|encoding| *encoding != ContentEncoding::IDENTITY)
+                    .collect();
+                let kept = || Some((gzipped?.get(&file)?, ContentEncoding::GZIP));
+                let precompressed = match file.precompressed(&wanted) {
+                    None if wanted.contains(&ContentEncoding::GZIP) => kept(),
+                    found => found,
+                };
+                if let Some((precompressed, encoding)) = precompressed {
+                    headers.set(Header::ContentEncoding(encoding));
+                    file = precompressed;
+                    tag = etag::weaken(&tag);
                 }
-                _ => (),
             }
-            headers.set(Header::ContentType(file.get_mime()));
+            headers.set(Header::ContentType(with_charset(
+                file.get_mime(),
+                &opts.charset,
+            )));
             headers.set(Header::ContentLength(file.get_size()));
             headers.set(Header::LastModified(file.get_modified()));
+            headers.set(Header::ETag(tag));
             HTTPResponse::new(
                 opts.protocol.clone(),
                 ResultCode::OK,
@@ -82,49 +157,73 @@ pub fn get_handler(req: &HTTPRequest, opts: &Opts) -> HTTPResponse {
             )
         }
         Err(err) => match err {
-            FileError::ReadError(err) if err.kind() == std::io::ErrorKind::NotFound => {
-                headers.set(Header::ContentType("text/html".to_string()));
-                HTTPResponse::new(
-                    opts.protocol.clone(),
-                    ResultCode::NotFound,
-                    headers,
-                    Some(error_page(ResultCode::NotFound).as_bytes().to_vec()),
-                )
-            }
-            FileError::ReadError(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
-                headers.set(Header::ContentType("text/html".to_string()));
-                HTTPResponse::new(
-                    opts.protocol.clone(),
-                    ResultCode::Forbidden,
-                    headers,
-                    Some(error_page(ResultCode::Forbidden).as_bytes().to_vec()),
-                )
-            }
+            FileError::NotFound => error_response(ResultCode::NotFound, headers, opts),
+            FileError::PermissionDenied => error_response(ResultCode::Forbidden, headers, opts),
             FileError::IsADirectory => {
                 log::debug!("{} is a directory", &req.uri);
+                // The directory exists, it just isn't ours to show
+                if !opts.directory_listing {
+                    return error_response(ResultCode::Forbidden, headers, opts);
+                }
+                // Relative links in the listing resolve against the directory itself
+                if !req.uri.ends_with('/') {
+                    let target = format!("{}{}/", url_prefix(opts), req.uri);
+                    headers.set(Header::location(&target));
+                    return error_response(ResultCode::MovedPermanently, headers, opts);
+                }
                 // Get a listing of files
-                let files = match File::get_listing(&req.uri, &opts.directory) {
+                let mut files = match File::get_listing(&req.uri, &opts.directory) {
                     Ok(list) => list,
                     Err(err) => {
                         log::error!("Unable to get directory listing {}", err.to_string());
-                        headers.set(Header::ContentType("text/html".to_string()));
-                        return HTTPResponse::new(
-                            opts.protocol.clone(),
-                            ResultCode::InternalServerError,
-                            headers,
-                            Some(
-                                error_page(ResultCode::InternalServerError)
-                                    .as_bytes()
-                                    .to_vec(),
-                            ),
-                        );
+                        return error_response(ResultCode::InternalServerError, headers, opts);
                     }
                 };
+                if !accepted(req, "text/html", opts) {
+                    return not_acceptable(headers, opts);
+                }
+                if opts.deny_dotfiles {
+                    files.retain(|path| {
+                        !Path::new(path)
+                            .file_name()
+                            .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+                    });
+                }
+                // Keep the listing, and so its ETag, stable between requests
+                files.sort();
                 log::debug!("Returning files: {}", &files.join("\n"));
 
-                let body = dir_listing(files);
+                let body = dir_listing(files, request_path(&req.uri), url_prefix(opts));
+                let tag = etag::from_content(body.as_bytes());
+                headers.set(Header::ETag(tag.clone()));
+                let modified = File::listing_modified(&req.uri, &opts.directory).ok();
+                if let Some(modified) = modified {
+                    headers.set(Header::LastModified(modified));
+                }
+                // Same precedence as for files
+                let not_modified = match req.headers.get(HeaderVariant::IfNoneMatch) {
+                    Some(Header::IfNoneMatch(tags)) => etag::none_match(&tags, &tag),
+                    _ => match (req.headers.get(HeaderVariant::IfModifiedSince), modified) {
+                        (Some(Header::IfModifiedSince(dt)), Some(modified)) => {
+                            modified.timestamp() <= dt.timestamp()
+                        }
+                        _ => false,
+                    },
+                };
+                if not_modified {
+                    return HTTPResponse::new(
+                        opts.protocol.clone(),
+                        ResultCode::NotModified,
+                        headers,
+                        None,
+                    );
+                }
 
-                headers.set(Header::ContentType("text/html".to_string()));
+                headers.set(Header::ContentType(with_charset(
+                    "text/html".to_string(),
+                    &opts.charset,
+                )));
+                headers.set(Header::ContentLength(body.len()));
                 HTTPResponse::new(
                     opts.protocol.clone(),
                     ResultCode::OK,
@@ -132,39 +231,705 @@ pub fn get_handler(req: &HTTPRequest, opts: &Opts) -> HTTPResponse {
                     Some(body.into()),
                 )
             }
-            _ => {
-                headers.set(Header::ContentType("text/html".to_string()));
-                HTTPResponse::new(
-                    opts.protocol.clone(),
-                    ResultCode::InternalServerError,
-                    headers,
-                    Some(
-                        error_page(ResultCode::InternalServerError)
-                            .as_bytes()
-                            .to_vec(),
-                    ),
-                )
+            FileError::ReadError(err) => {
+                log::error!("Unable to read {}: {}", &req.uri, err);
+                error_response(ResultCode::InternalServerError, headers, opts)
             }
         },
     }
 }
 
-pub fn basic_auth(req: &HTTPRequest, auth: &Auth) -> Result<(), AuthError> {
-    let auth_header = req.headers.get(HeaderVariant::Authorization);
+// Serves one in-memory document for GET and HEAD at any path
+pub fn body_handler(
+    req: &HTTPRequest,
+    body: &[u8],
+    content_type: &str,
+    opts: &Opts,
+) -> HTTPResponse {
+    let mut headers = Headers::new();
+    headers.set(Header::Date(Utc::now().into()));
+    headers.set(Header::Server("Rusty Webserver".to_string()));
+    if !matches!(req.method, Method::GET | Method::HEAD) {
+        headers.set(Header::Allow(vec![Method::GET, Method::HEAD]));
+        return error_response(ResultCode::MethodNotAllowed, headers, opts);
+    }
+    let tag = etag::from_content(body);
+    if let Some(Header::IfNoneMatch(tags)) = req.headers.get(HeaderVariant::IfNoneMatch) {
+        if etag::none_match(&tags, &tag) {
+            headers.set(Header::ETag(tag));
+            return HTTPResponse::new(
+                opts.protocol.clone(),
+                ResultCode::NotModified,
+                headers,
+                None,
+            );
+        }
+    }
+    headers.set(Header::ContentType(content_type.to_string()));
+    headers.set(Header::ContentLength(body.len()));
+    headers.set(Header::ETag(tag));
+    headers.set(Header::AcceptRanges("none".to_string()));
+    let resp = HTTPResponse::new(
+        opts.protocol.clone(),
+        ResultCode::OK,
+        headers,
+        Some(body.to_vec()),
+    );
+    let mut resp = compress_response(req, resp, opts);
+    if req.method == Method::HEAD {
+        resp.body = None;
+    }
+    resp
+}
 
-    if let Some(auth_header) = auth_header {
-        let Header::Authorization(inner) = auth_header else {
-            return Err(AuthError {});
+// Methods the built in file handler answers for a resource
+pub fn allowed_methods(uri: &str, opts: &Opts) -> Vec<Method> {
+    let mut methods = vec![Method::GET, Method::HEAD, Method::OPTIONS];
+    if writable(uri, opts) {
+        methods.extend([Method::PUT, Method::DELETE]);
+    }
+    if opts.trace {
+        methods.push(Method::TRACE);
+    }
+    methods.retain(|method| !opts.disabled_methods.contains(method));
+    methods
+}
+
+// Files can only change under a mount configured as writable, and only while
+// the directory holding the target isn't read-only on disk
+fn writable(uri: &str, opts: &Opts) -> bool {
+    let path = request_path(uri);
+    if !opts
+        .writable_paths
+        .iter()
+        .any(|prefix| under_prefix(path, prefix))
+    {
+        return false;
+    }
+    let target = Path::new(&opts.directory).join(path.trim_start_matches('/'));
+    match target.parent().map(fs::metadata) {
+        Some(Ok(metadata)) => metadata.is_dir() && !metadata.permissions().readonly(),
+        _ => false,
+    }
+}
+
+// Echoes the request back as it was received. This server is always the origin
+// and so the final hop, whatever is left of Max-Forwards, 0 included.
+pub fn trace_handler(req: &HTTPRequest, opts: &Opts) -> HTTPResponse {
+    if let Some(Header::MaxForwards(hops)) = req.headers.get(HeaderVariant::MaxForwards) {
+        log::debug!("Answering TRACE at the origin with Max-Forwards {}", hops);
+    }
+    let mut echoed = req.headers.clone();
+    for sensitive in ["Authorization", "Proxy-Authorization", "Cookie"] {
+        echoed.remove_named(sensitive);
+    }
+    let message = format!(
+        "{} {} {}\r\n{}",
+        String::from(req.method.clone()),
+        req.uri,
+        req.version,
+        echoed
+    );
+
+    let mut headers = Headers::new();
+    headers.set(Header::Date(Utc::now().into()));
+    headers.set(Header::Server("Rusty Webserver".to_string()));
+    headers.set(Header::ContentType("message/http".to_string()));
+    headers.set(Header::ContentLength(message.len()));
+    HTTPResponse::new(
+        opts.protocol.clone(),
+        ResultCode::OK,
+        headers,
+        Some(message.into_bytes()),
+    )
+}
+
+pub fn method_not_allowed(req: &HTTPRequest, opts: &Opts) -> HTTPResponse {
+    let mut headers = Headers::new();
+    headers.set(Header::Allow(allowed_methods(&req.uri, opts)));
+    error_response(ResultCode::MethodNotAllowed, headers, opts)
+}
+
+// Stores the request body as the target file
+pub fn put_handler(req: &HTTPRequest, opts: &Opts) -> HTTPResponse {
+    if !writable(&req.uri, opts) {
+        return method_not_allowed(req, opts);
+    }
+    let mut headers = Headers::new();
+    headers.set(Header::Date(Utc::now().into()));
+    headers.set(Header::Server("Rusty Webserver".to_string()));
+    let body = req.body.as_deref().unwrap_or_default();
+    match File::store(request_path(&req.uri), body, opts) {
+        Ok(created) => {
+            log::info!("Stored {} bytes at {}", body.len(), req.uri);
+            headers.set(Header::ContentLength(0));
+            let status = if created {
+                ResultCode::Created
+            } else {
+                ResultCode::NoContent
+            };
+            HTTPResponse::new(opts.protocol.clone(), status, headers, None)
+        }
+        Err(err) => file_error_response(err, headers, opts),
+    }
+}
+
+pub fn delete_handler(req: &HTTPRequest, opts: &Opts) -> HTTPResponse {
+    if !writable(&req.uri, opts) {
+        return method_not_allowed(req, opts);
+    }
+    let mut headers = Headers::new();
+    headers.set(Header::Date(Utc::now().into()));
+    headers.set(Header::Server("Rusty Webserver".to_string()));
+    match File::remove(request_path(&req.uri), opts) {
+        Ok(()) => {
+            log::info!("Removed {}", req.uri);
+            HTTPResponse::new(opts.protocol.clone(), ResultCode::NoContent, headers, None)
+        }
+        Err(err) => file_error_response(err, headers, opts),
+    }
+}
+
+fn file_error_response(err: FileError, headers: Headers, opts: &Opts) -> HTTPResponse {
+    let status = match err {
+        FileError::NotFound => ResultCode::NotFound,
+        FileError::PermissionDenied | FileError::IsADirectory => ResultCode::Forbidden,
+        FileError::ReadError(err) => {
+            log::error!("Unable to write file: {}", err);
+            ResultCode::InternalServerError
+        }
+    };
+    error_response(status, headers, opts)
+}
+
+fn error_response(status: ResultCode, mut headers: Headers, opts: &Opts) -> HTTPResponse {
+    let body = error_page(status).into_bytes();
+    headers.set(Header::ContentType("text/html".to_string()));
+    // Delimited so the connection can be kept open
+    headers.set(Header::ContentLength(body.len()));
+    HTTPResponse::new(opts.protocol.clone(), status, headers, Some(body))
+}
+
+// Answers OPTIONS with the supported methods. CORS preflights, which carry
+// Access-Control-Request-Method, also get the Access-Control-* grants.
+pub fn options_handler(req: &HTTPRequest, opts: &Opts) -> HTTPResponse {
+    let mut headers = Headers::new();
+    headers.set(Header::Date(Utc::now().into()));
+    headers.set(Header::Server("Rusty Webserver".to_string()));
+    let allowed = allowed_methods(&req.uri, opts);
+    headers.set(Header::Allow(allowed.clone()));
+    if allowed.contains(&Method::GET) {
+        headers.set(Header::AcceptRanges("bytes".to_string()));
+    }
+    headers.set(Header::ContentLength(0));
+
+    let preflight = req
+        .headers
+        .get_generic("Access-Control-Request-Method")
+        .is_some();
+    if let (true, Some(origin)) = (preflight, &opts.cors_origin) {
+        let mut grant = |name: &str, value: String| {
+            headers.set(Header::Generic((name.to_string(), value)));
+        };
+        grant("Access-Control-Allow-Origin", origin.clone());
+        grant(
+            "Access-Control-Allow-Methods",
+            allowed
+                .into_iter()
+                .map(Into::<String>::into)
+                .collect::<Vec<String>>()
+                .join(", "),
+        );
+        if let Some(requested) = req.headers.get_generic("Access-Control-Request-Headers") {
+            grant("Access-Control-Allow-Headers", requested);
+        }
+        if let Some(max_age) = opts.cors_max_age {
+            grant("Access-Control-Max-Age", max_age.as_secs().to_string());
+        }
+    }
+    HTTPResponse::new(opts.protocol.clone(), ResultCode::NoContent, headers, None)
+}
+
+// Lets the CORS origin read the response itself, which the preflight alone doesn't.
+// Vary keeps shared caches from handing it to requests from other origins.
+pub fn allow_origin(mut resp: HTTPResponse, opts: &Opts) -> HTTPResponse {
+    if let Some(origin) = &opts.cors_origin {
+        resp.headers.set(Header::Generic((
+            "Access-Control-Allow-Origin".to_string(),
+            origin.clone(),
+        )));
+        resp.headers
+            .set(Header::Generic(("Vary".to_string(), "Origin".to_string())));
+    }
+    resp
+}
+
+// Compresses whatever body was assembled, be it a file, a listing or an error
+// page, when the client accepts a coding we can produce and the type is worth it
+fn compress_response(req: &HTTPRequest, resp: HTTPResponse, opts: &Opts) -> HTTPResponse {
+    compress_response_with(req, resp, opts, compression::compress_bytes)
+}
+
+// compress_response with the compressor passed in, so its failures can be tested
+fn compress_response_with(
+    req: &HTTPRequest,
+    mut resp: HTTPResponse,
+    opts: &Opts,
+    compress: impl Fn(&[u8], &ContentEncoding, u32) -> std::io::Result<Vec<u8>>,
+) -> HTTPResponse {
+    let Some(body) = &resp.body else {
+        return resp;
+    };
+    if !opts.compression {
+        return resp;
+    }
+    // Already encoded, e.g. served from a precompressed sibling
+    if resp.headers.get(HeaderVariant::ContentEncoding).is_some() {
+        return resp;
+    }
+    let Some(Header::AcceptEncoding(accepted)) = req.headers.get(HeaderVariant::AcceptEncoding)
+    else {
+        return resp;
+    };
+    let Some(Header::ContentType(mime)) = resp.headers.get(HeaderVariant::ContentType) else {
+        return resp;
+    };
+    // Size and type only decide while the client would take the body as is
+    let identity = content_codings::quality(&accepted, &ContentEncoding::IDENTITY) > 0;
+    if accepted
+        .iter()
+        .any(|coding| coding.encoding == ContentEncoding::TOKEN)
+        || identity
+            && (body.len() < opts.min_compress_size
+                || !should_compress(&mime, &opts.compression_policy))
+    {
+        return resp;
+    }
+    let candidates = [
+        ContentEncoding::GZIP,
+        ContentEncoding::DEFLATE,
+        ContentEncoding::IDENTITY,
+    ];
+    let encoding = match content_codings::preference(&accepted, &candidates).first() {
+        Some(ContentEncoding::IDENTITY) | None => return resp,
+        Some(encoding) => encoding.clone(),
+    };
+
+    log::debug!("Encoding {} response for {} as {}", mime, req.uri, encoding);
+    match compress(body, &encoding, opts.ratio) {
+        // Not worth the client's time to decode if it barely shrank
+        Ok(compressed)
+            if identity
+                && (body.len() - compressed.len().min(body.len())) * 100
+                    < body.len() * opts.min_compression_gain as usize =>
+        {
+            log::debug!(
+                "Sending {} uncompressed, {} only took it from {} to {} bytes",
+                req.uri,
+                encoding,
+                body.len(),
+                compressed.len()
+            );
+            resp
+        }
+        Ok(compressed) => {
+            resp.headers.set(Header::ContentEncoding(encoding));
+            resp.headers.set(Header::ContentLength(compressed.len()));
+            if let Some(Header::ETag(tag)) = resp.headers.get(HeaderVariant::ETag) {
+                resp.headers.set(Header::ETag(etag::weaken(&tag)));
+            }
+            resp.body = Some(compressed);
+            resp
+        }
+        // The body is as good as ever uncompressed, get_handler still answers 406 if
+        // the client refuses identity
+        Err(err) => {
+            log::warn!(
+                "Sending {} uncompressed, {} failed: {}",
+                req.uri,
+                encoding,
+                err
+            );
+            resp
+        }
+    }
+}
+
+// Cuts a full response down to the requested byte range, or answers 416 when
+// the range lies outside the body
+fn partial_response(
+    mut resp: HTTPResponse,
+    range: Result<(usize, usize), ()>,
+    opts: &Opts,
+) -> HTTPResponse {
+    let body = resp.body.take().unwrap_or_default();
+    match range {
+        Ok((first, last)) => {
+            let part = body[first..=last].to_vec();
+            resp.status = ResultCode::PartialContent;
+            resp.headers.set(Header::ContentLength(part.len()));
+            resp.headers.set(Header::Generic((
+                "Content-Range".to_string(),
+                format!("bytes {}-{}/{}", first, last, body.len()),
+            )));
+            resp.body = Some(part);
+            resp
+        }
+        Err(()) => {
+            let mut headers = Headers::new();
+            headers.set(Header::Generic((
+                "Content-Range".to_string(),
+                format!("bytes */{}", body.len()),
+            )));
+            error_response(ResultCode::RangeNotSatisfiable, headers, opts)
+        }
+    }
+}
+
+// The range asked for over a body of len bytes. Anything but a single range of
+// bytes is ignored and the whole body sent (RFC 7233 3.1).
+fn requested_range(req: &HTTPRequest, len: usize) -> Option<Result<(usize, usize), ()>> {
+    byte_range(&req.headers.get_generic("Range")?, len)
+}
+
+// Parses a single `bytes=first-last`, `bytes=first-` or `bytes=-suffix` range into
+// inclusive offsets into a body of len bytes. None when the header doesn't ask
+// for one range of bytes, an error when the range lies outside the body.
+fn byte_range(range: &str, len: usize) -> Option<Result<(usize, usize), ()>> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+    let range = if first.is_empty() {
+        let suffix = last.parse::<usize>().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(Err(()));
+        }
+        (len - suffix.min(len), len - 1)
+    } else {
+        let first = first.parse::<usize>().ok()?;
+        let last = match last {
+            "" => usize::MAX,
+            last => last.parse::<usize>().ok()?,
+        };
+        if last < first {
+            return None;
+        }
+        if first >= len {
+            return Some(Err(()));
+        }
+        (first, last.min(len - 1))
+    };
+    Some(Ok(range))
+}
+
+// Only consults the Accept header in strict mode, clients are otherwise served
+// whatever the file is.
+fn accepted(req: &HTTPRequest, mime: &str, opts: &Opts) -> bool {
+    if !opts.strict_accept {
+        return true;
+    }
+    match req.headers.get(HeaderVariant::Accept) {
+        Some(Header::Accept(ranges)) => accepts(&ranges, mime),
+        _ => true,
+    }
+}
+
+// Whether the client takes a body without any content coding
+fn identity_accepted(req: &HTTPRequest) -> bool {
+    match req.headers.get(HeaderVariant::AcceptEncoding) {
+        Some(Header::AcceptEncoding(accepted)) => {
+            content_codings::quality(&accepted, &ContentEncoding::IDENTITY) > 0
+        }
+        _ => true,
+    }
+}
+
+fn not_acceptable(headers: Headers, opts: &Opts) -> HTTPResponse {
+    error_response(ResultCode::NotAcceptable, headers, opts)
+}
+
+// Checks a MIME type against the media ranges of an Accept header. The most
+// specific matching range decides, and a q of 0 on it means not acceptable.
+pub fn accepts(ranges: &str, mime: &str) -> bool {
+    let mime = mime
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    let (typ, _) = mime.split_once('/').unwrap_or((&mime, ""));
+    let mut best: Option<(u8, f32)> = None;
+    for range in ranges.split(',') {
+        let mut params = range.split(';');
+        let range = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let specificity = if range == mime {
+            2
+        } else if range == format!("{}/*", typ) {
+            1
+        } else if range == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        let q = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match best {
+            Some((best, _)) if best >= specificity => (),
+            _ => best = Some((specificity, q)),
+        }
+    }
+    matches!(best, Some((_, q)) if q > 0.0)
+}
+
+// Declares the charset on textual MIME types so clients don't have to guess it.
+// Binary types, and types that already carry a charset, are left alone.
+pub fn with_charset(mime: String, charset: &Option<String>) -> String {
+    let Some(charset) = charset else {
+        return mime;
+    };
+    let lower = mime.to_ascii_lowercase();
+    let essence = lower.split(';').next().unwrap_or("").trim();
+    let textual = essence.starts_with("text/")
+        || essence == "application/json"
+        || essence == "application/xml";
+    if !textual || lower.contains("charset=") {
+        return mime;
+    }
+    format!("{}; charset={}", mime, charset)
+}
+
+// Gzips every file under the served directory that responses would be compressed
+// for into the store, so serving them costs no compression. Returns how many were kept.
+pub fn precompress(opts: &Opts, store: &mut GzipStore) -> usize {
+    let mut kept = 0;
+    let mut dirs = vec![String::new()];
+    while let Some(dir) = dirs.pop() {
+        let path = Path::new(&opts.directory).join(dir.trim_start_matches('/'));
+        let Ok(entries) = fs::read_dir(&path) else {
+            log::warn!("Unable to precompress files in {}", path.display());
+            continue;
         };
-        let mut inner = inner.split(' ');
-        let typ = inner.next();
-        let token = inner.next();
-        if typ.is_none() || token.is_none() || typ.unwrap() != "Basic" {
+        for entry in entries.filter_map(Result::ok) {
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            let uri = format!("{}/{}", dir, name);
+            match entry.file_type() {
+                Ok(kind) if kind.is_dir() && !(opts.deny_dotfiles && name.starts_with('.')) => {
+                    dirs.push(uri)
+                }
+                Ok(kind) if kind.is_file() => {
+                    if let Ok(file) = File::try_load(&uri, opts) {
+                        kept += usize::from(keep_gzipped(&file, opts, store));
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    kept
+}
+
+// Same rules as compress_response, minus the client's say
+fn keep_gzipped(file: &File, opts: &Opts, store: &mut GzipStore) -> bool {
+    let content = file.get_content();
+    if content.len() < opts.min_compress_size
+        || !should_compress(&file.get_mime(), &opts.compression_policy)
+    {
+        return false;
+    }
+    let Ok(compressed) = compression::compress_bytes(&content, &ContentEncoding::GZIP, opts.ratio)
+    else {
+        return false;
+    };
+    if (content.len() - compressed.len().min(content.len())) * 100
+        < content.len() * opts.min_compression_gain as usize
+    {
+        return false;
+    }
+    store.keep(file, compressed)
+}
+
+// Looks up whether a MIME type should be compressed, preferring an exact match
+// over a `type/*` wildcard over `*/*`. Unlisted types are left alone.
+pub fn should_compress(mime: &str, policy: &HashMap<String, bool>) -> bool {
+    let (mime, wildcard) = policy_keys(mime);
+    policy
+        .get(&mime)
+        .or_else(|| policy.get(&wildcard))
+        .or_else(|| policy.get("*/*"))
+        .copied()
+        .unwrap_or(false)
+}
+
+// A MIME type without parameters and its `type/*` wildcard, as policies are keyed
+fn policy_keys(mime: &str) -> (String, String) {
+    let mime = mime
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    let wildcard = match mime.split_once('/') {
+        Some((typ, _)) => format!("{}/*", typ),
+        None => "*/*".to_string(),
+    };
+    (mime, wildcard)
+}
+
+// The Cache-Control directive configured for a file, the extension is more specific
+// than its MIME type, which is more specific than the wildcards
+fn cache_directive<'a>(extension: Option<&str>, mime: &str, opts: &'a Opts) -> Option<&'a str> {
+    let policy = &opts.cache_control;
+    let (mime, wildcard) = policy_keys(mime);
+    extension
+        .and_then(|ext| policy.get(ext))
+        .or_else(|| policy.get(&mime))
+        .or_else(|| policy.get(&wildcard))
+        .or_else(|| policy.get("*/*"))
+        .map(String::as_str)
+}
+
+// Picks the credentials guarding a path. The longest prefix rule that matches
+// whole path segments wins, otherwise the server wide auth applies if set. Paths
+// are compared as files are found: normalized, and ignoring case when files are.
+pub fn auth_for<'a>(uri: &str, opts: &'a Opts) -> Option<&'a Auth> {
+    let fold = |path: &str| match opts.case_insensitive {
+        true => path.to_lowercase(),
+        false => path.to_string(),
+    };
+    let path = fold(request_path(
+        &normalize_uri(uri).unwrap_or_else(|| uri.to_string()),
+    ));
+    opts.auth_rules
+        .iter()
+        .filter(|(prefix, _)| under_prefix(&path, &fold(prefix)))
+        .max_by_key(|(prefix, _)| prefix.trim_end_matches('/').len())
+        .map(|(_, auth)| auth)
+        .or(opts.auth.as_ref())
+}
+
+// The request target with empty and `.` segments dropped from its path, so auth rules
+// and the file system both see `//admin/./x` as `/admin/x`. None when a segment is
+// `..`, which could climb over a rule's prefix or out of the served directory.
+pub fn normalize_uri(uri: &str) -> Option<String> {
+    let path = request_path(uri);
+    let mut normal = String::with_capacity(uri.len());
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => continue,
+            ".." => return None,
+            segment => {
+                normal.push('/');
+                normal.push_str(segment);
+            }
+        }
+    }
+    if normal.is_empty() || path.ends_with('/') {
+        normal.push('/');
+    }
+    normal.push_str(&uri[path.len()..]);
+    Some(normal)
+}
+
+// The 103 Early Hints to send before answering, carrying the Link values of every
+// early_hints prefix the path is under. Interim responses mean nothing before
+// HTTP/1.1, so neither side may be speaking 1.0.
+pub fn early_hints(req: &HTTPRequest, opts: &Opts) -> Option<HTTPResponse> {
+    if req.method != Method::GET && req.method != Method::HEAD {
+        return None;
+    }
+    if req.version != "HTTP/1.1" || opts.protocol != "HTTP/1.1" {
+        return None;
+    }
+    let uri = strip_url_prefix(&req.uri, opts)?;
+    let path = request_path(&uri);
+    let links: Vec<&str> = opts
+        .early_hints
+        .iter()
+        .filter(|(prefix, _)| under_prefix(path, prefix))
+        .map(|(_, link)| link.as_str())
+        .collect();
+    if links.is_empty() {
+        return None;
+    }
+    let mut headers = Headers::new();
+    headers.set(Header::Generic(("Link".to_string(), links.join(", "))));
+    Some(HTTPResponse::new(
+        opts.protocol.clone(),
+        ResultCode::EarlyHints,
+        headers,
+        None,
+    ))
+}
+
+// The configured url_prefix without a trailing slash, empty when unset
+fn url_prefix(opts: &Opts) -> &str {
+    opts.url_prefix
+        .as_deref()
+        .unwrap_or("")
+        .trim_end_matches('/')
+}
+
+// Maps a request target under the url_prefix onto the served directory, None when
+// it lies outside the prefix
+pub fn strip_url_prefix(uri: &str, opts: &Opts) -> Option<String> {
+    let prefix = url_prefix(opts);
+    if !under_prefix(request_path(uri), prefix) {
+        return None;
+    }
+    let rest = &uri[prefix.len()..];
+    if rest.starts_with('/') {
+        Some(rest.to_string())
+    } else {
+        Some(format!("/{}", rest))
+    }
+}
+
+// Whether the request is for a directory that would be refused for lack of an index
+pub fn unindexed_directory(req: &HTTPRequest, opts: &Opts) -> bool {
+    if opts.directory_listing || !matches!(req.method, Method::GET | Method::HEAD) {
+        return false;
+    }
+    strip_url_prefix(&req.uri, opts)
+        .is_some_and(|uri| File::is_unindexed_dir(request_path(&uri), opts))
+}
+
+pub fn not_found(opts: &Opts) -> HTTPResponse {
+    error_response(ResultCode::NotFound, Headers::new(), opts)
+}
+
+pub fn bad_request(opts: &Opts) -> HTTPResponse {
+    error_response(ResultCode::BadRequest, Headers::new(), opts)
+}
+
+// The path of a request target without its query or fragment
+fn request_path(uri: &str) -> &str {
+    uri.split(['?', '#']).next().unwrap_or("")
+}
+
+// Whether a path lies under a prefix, matching whole segments only
+fn under_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix.trim_end_matches('/')) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+pub fn basic_auth(req: &HTTPRequest, auth: &Auth) -> Result<(), AuthError> {
+    if let Some(credentials) = req.headers.authorization() {
+        if !credentials.is_scheme("Basic") {
             return Err(AuthError {});
         }
+        let token = credentials.credentials;
         if base64::engine::general_purpose::STANDARD
             .encode(format!("{}:{}", auth.username, auth.password).as_bytes())
-            == token.unwrap()
+            == token
         {
             return Ok(());
         }
@@ -172,37 +937,1063 @@ pub fn basic_auth(req: &HTTPRequest, auth: &Auth) -> Result<(), AuthError> {
             "Auth failure: {} does not match {}",
             base64::engine::general_purpose::STANDARD
                 .encode(format!("{}:{}", auth.username, auth.password)),
-            token.unwrap()
+            token
         );
     }
     Err(AuthError {})
 }
 
+// Resolves the real client address when the peer is a trusted proxy by taking
+// the rightmost X-Forwarded-For entry that isn't itself a trusted proxy.
+// Returns None for untrusted peers so their X-Forwarded-For can't be spoofed.
+pub fn forwarded_client(req: &HTTPRequest, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = req.peer?;
+    if !trusted_proxies.contains(&peer.ip()) {
+        return None;
+    }
+    let forwarded = req.headers.get_generic("X-Forwarded-For")?;
+    for entry in forwarded.rsplit(',') {
+        let addr = entry.trim().parse::<IpAddr>().ok()?;
+        if !trusted_proxies.contains(&addr) {
+            return Some(addr);
+        }
+    }
+    None
+}
+
+// Host header value as virtual hosts are looked up by, lowercased and without the
+// port when it's the one bound to, since clients only leave it off for port 80.
+// IPv6 literals keep their brackets so their colons aren't taken for the port.
+pub fn vhost_name(host: &str, port: u16) -> String {
+    let host = host.trim().to_ascii_lowercase();
+    let (name, explicit_port) = if host.starts_with('[') {
+        match host.find(']') {
+            Some(end) => host.split_at(end + 1),
+            None => return host,
+        }
+    } else {
+        match host.rfind(':') {
+            Some(colon) => host.split_at(colon),
+            None => (host.as_str(), ""),
+        }
+    };
+    if explicit_port.is_empty() || explicit_port == format!(":{}", port) {
+        name.to_string()
+    } else {
+        host.clone()
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use std::io::Read;
+
+    use chrono::{DateTime, Duration};
+    use flate2::read::GzDecoder;
+
     use crate::http10::headers::{Header, Headers};
+    use crate::test_util::test_dir;
 
     use super::*;
 
-    #[test]
-    fn test_basic_auth_success() {
-        let mut headers = Headers::new();
-        headers.set(Header::Authorization(
-            "Basic YWRtaW46cGFzc3dvcmQ=".to_string(),
-        ));
-        let req = HTTPRequest {
-            method: crate::http10::methods::Method::GET,
-            uri: "/".to_string(),
+    fn test_opts(dir: &Path) -> Opts {
+        Opts {
+            directory: dir.to_str().unwrap().to_string(),
+            ..Opts::default()
+        }
+    }
+
+    fn request(method: Method, uri: &str, headers: Headers) -> HTTPRequest {
+        HTTPRequest {
+            method,
+            uri: uri.to_string(),
             version: "HTTP/1.0".to_string(),
             headers,
             body: None,
+            trailers: None,
+            peer: None,
+        }
+    }
+
+    fn response_etag(resp: &HTTPResponse) -> String {
+        let Some(Header::ETag(tag)) = resp.headers.get(HeaderVariant::ETag) else {
+            panic!("Response has no ETag");
         };
-        let auth = Auth {
-            username: "admin".to_string(),
-            password: "password".to_string(),
-        };
+        tag
+    }
 
-        assert!(basic_auth(&req, &auth).is_ok());
+    #[test]
+    fn test_matching_etag_overrides_stale_date() {
+        let dir = test_dir("etag-match", &[("index.txt", b"hello")]);
+        let opts = test_opts(&dir);
+        let tag = response_etag(&get_handler(
+            &request(Method::GET, "/index.txt", Headers::new()),
+            &opts,
+            None,
+        ));
+
+        let mut headers = Headers::new();
+        headers.set(Header::IfNoneMatch(vec![tag]));
+        headers.set(Header::IfModifiedSince(
+            DateTime::parse_from_rfc2822("Tue, 15 Nov 1994 08:12:31 GMT").unwrap(),
+        ));
+        let resp = get_handler(&request(Method::GET, "/index.txt", headers), &opts, None);
+        assert_eq!(resp.status, ResultCode::NotModified);
+        assert!(resp.body.is_none());
+    }
+
+    #[test]
+    fn test_mismatched_etag_overrides_fresh_date() {
+        let dir = test_dir("etag-mismatch", &[("index.txt", b"hello")]);
+        let opts = test_opts(&dir);
+
+        let mut headers = Headers::new();
+        headers.set(Header::IfNoneMatch(vec!["\"stale\"".to_string()]));
+        headers.set(Header::IfModifiedSince(
+            (Utc::now() + Duration::days(1)).into(),
+        ));
+        let resp = get_handler(&request(Method::GET, "/index.txt", headers), &opts, None);
+        assert_eq!(resp.status, ResultCode::OK);
+        assert_eq!(resp.body, Some(b"hello".to_vec()));
+    }
+
+    #[test]
+    fn test_directory_listing_etag() {
+        let dir = test_dir("listing-etag", &[("a.txt", b"a"), ("b.txt", b"b")]);
+        let opts = test_opts(&dir);
+
+        let first = get_handler(&request(Method::GET, "/", Headers::new()), &opts, None);
+        let second = get_handler(&request(Method::GET, "/", Headers::new()), &opts, None);
+        assert_eq!(first.status, ResultCode::OK);
+        assert_eq!(response_etag(&first), response_etag(&second));
+
+        let mut headers = Headers::new();
+        headers.set(Header::IfNoneMatch(vec![response_etag(&first)]));
+        let resp = get_handler(&request(Method::GET, "/", headers), &opts, None);
+        assert_eq!(resp.status, ResultCode::NotModified);
+        assert!(resp.body.is_none());
+
+        std::fs::write(dir.join("c.txt"), b"c").unwrap();
+        let changed = get_handler(&request(Method::GET, "/", Headers::new()), &opts, None);
+        assert_ne!(response_etag(&first), response_etag(&changed));
+    }
+
+    #[test]
+    fn test_strips_url_prefix() {
+        let opts = Opts {
+            url_prefix: Some("/app/".to_string()),
+            ..Opts::default()
+        };
+        assert_eq!(
+            strip_url_prefix("/app/css/site.css", &opts),
+            Some("/css/site.css".to_string())
+        );
+        assert_eq!(strip_url_prefix("/app", &opts), Some("/".to_string()));
+        assert_eq!(
+            strip_url_prefix("/app?a=1", &opts),
+            Some("/?a=1".to_string())
+        );
+        assert_eq!(strip_url_prefix("/application", &opts), None);
+        assert_eq!(strip_url_prefix("/css/site.css", &opts), None);
+    }
+
+    #[test]
+    fn test_unlisted_directory_is_forbidden() {
+        let dir = test_dir("no-listing", &[("docs/a.txt", b"a")]);
+        let opts = Opts {
+            directory_listing: false,
+            ..test_opts(&dir)
+        };
+        let resp = get_handler(&request(Method::GET, "/docs", Headers::new()), &opts, None);
+        assert_eq!(resp.status, ResultCode::Forbidden);
+    }
+
+    #[test]
+    fn test_missing_directory_is_not_found() {
+        let dir = test_dir("missing-dir", &[("docs/a.txt", b"a")]);
+        for listing in [true, false] {
+            let opts = Opts {
+                directory_listing: listing,
+                ..test_opts(&dir)
+            };
+            let resp = get_handler(
+                &request(Method::GET, "/missing/", Headers::new()),
+                &opts,
+                None,
+            );
+            assert_eq!(resp.status, ResultCode::NotFound);
+        }
+    }
+
+    #[test]
+    fn test_head_directory_listing_headers() {
+        let dir = test_dir("listing-head", &[("a.txt", b"a")]);
+        let opts = test_opts(&dir);
+
+        let get = get_handler(&request(Method::GET, "/", Headers::new()), &opts, None);
+        let head = get_handler(&request(Method::HEAD, "/", Headers::new()), &opts, None);
+        assert!(head.body.is_none());
+        for variant in [
+            HeaderVariant::ContentType,
+            HeaderVariant::ContentLength,
+            HeaderVariant::ETag,
+            HeaderVariant::LastModified,
+        ] {
+            assert!(get.headers.get(variant.clone()).is_some());
+            assert_eq!(head.headers.get(variant.clone()), get.headers.get(variant));
+        }
+        assert_eq!(response_type(&head), "text/html; charset=utf-8");
+        assert_length_matches_body(&get);
+    }
+
+    #[test]
+    fn test_listing_parent_link() {
+        let dir = test_dir("listing-parent", &[("a/b/c.txt", b"c")]);
+        let listing = |uri: &str| {
+            let resp = get_handler(
+                &request(Method::GET, uri, Headers::new()),
+                &test_opts(&dir),
+                None,
+            );
+            String::from_utf8(resp.body.unwrap()).unwrap()
+        };
+        assert!(!listing("/").contains("../"));
+        assert!(listing("/a/").contains("<li><a href='/'>../</a></li>"));
+        assert!(listing("/a/b/").contains("<li><a href='/a/'>../</a></li>"));
+    }
+
+    #[test]
+    fn test_directories_redirect_to_trailing_slash() {
+        let dir = test_dir(
+            "slash-redirect",
+            &[
+                ("docs/a.txt", b"a"),
+                ("docs\r\nSet-Cookie: a=b/x.txt", b"x"),
+            ],
+        );
+        let opts = Opts {
+            url_prefix: Some("/app".to_string()),
+            ..test_opts(&dir)
+        };
+        let redirect = |uri: &str| {
+            let resp = get_handler(&request(Method::GET, uri, Headers::new()), &opts, None);
+            assert_eq!(resp.status, ResultCode::MovedPermanently);
+            resp.headers
+                .get(HeaderVariant::Location)
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(redirect("/docs"), "Location: /app/docs/");
+        assert_eq!(
+            redirect("/docs\r\nSet-Cookie: a=b"),
+            "Location: /app/docs%0D%0ASet-Cookie:%20a=b/"
+        );
+        let resp = get_handler(&request(Method::GET, "/docs/", Headers::new()), &opts, None);
+        assert_eq!(resp.status, ResultCode::OK);
+    }
+
+    #[test]
+    fn test_listing_links_by_request_path() {
+        // The served directory is somewhere under the system temp dir, not ./
+        let dir = test_dir("listing-links", &[("a/b/c.txt", b"c")]);
+        let opts = Opts {
+            url_prefix: Some("/app".to_string()),
+            ..test_opts(&dir)
+        };
+        let resp = get_handler(&request(Method::GET, "/a/b/", Headers::new()), &opts, None);
+        let body = String::from_utf8(resp.body.unwrap()).unwrap();
+        assert!(
+            body.contains("<li><a href='/app/a/b/c.txt'>c.txt</a></li>"),
+            "{}",
+            body
+        );
+    }
+
+    #[test]
+    fn test_listing_hides_dotfiles() {
+        let dir = test_dir(
+            "listing-dotfiles",
+            &[(".env", b"SECRET=1"), ("normal.txt", b"hi")],
+        );
+        let listing = |opts: &Opts| {
+            let resp = get_handler(&request(Method::GET, "/", Headers::new()), opts, None);
+            String::from_utf8(resp.body.unwrap()).unwrap()
+        };
+        let body = listing(&test_opts(&dir));
+        assert!(body.contains("normal.txt"));
+        assert!(!body.contains(".env"));
+        let resp = get_handler(
+            &request(Method::GET, "/.env", Headers::new()),
+            &test_opts(&dir),
+            None,
+        );
+        assert_eq!(resp.status, ResultCode::NotFound);
+
+        let opts = Opts {
+            deny_dotfiles: false,
+            ..test_opts(&dir)
+        };
+        assert!(listing(&opts).contains(".env"));
+    }
+
+    #[test]
+    fn test_directory_listing_if_modified_since() {
+        let dir = test_dir("listing-ims", &[("a.txt", b"a")]);
+        let opts = test_opts(&dir);
+        let get = get_handler(&request(Method::GET, "/", Headers::new()), &opts, None);
+        let Some(Header::LastModified(modified)) = get.headers.get(HeaderVariant::LastModified)
+        else {
+            panic!("Listing has no Last-Modified");
+        };
+
+        for method in [Method::GET, Method::HEAD] {
+            let mut headers = Headers::new();
+            headers.set(Header::IfModifiedSince(modified));
+            let resp = get_handler(&request(method, "/", headers), &opts, None);
+            assert_eq!(resp.status, ResultCode::NotModified);
+            assert!(resp.body.is_none());
+        }
+    }
+
+    #[test]
+    fn test_if_modified_since_last_modified() {
+        let dir = test_dir("ims", &[("index.txt", b"hello")]);
+        let opts = test_opts(&dir);
+        let resp = get_handler(
+            &request(Method::GET, "/index.txt", Headers::new()),
+            &opts,
+            None,
+        );
+        let Some(Header::LastModified(modified)) = resp.headers.get(HeaderVariant::LastModified)
+        else {
+            panic!("Response has no Last-Modified");
+        };
+
+        let mut headers = Headers::new();
+        headers.set(Header::IfModifiedSince(modified));
+        let resp = get_handler(&request(Method::GET, "/index.txt", headers), &opts, None);
+        assert_eq!(resp.status, ResultCode::NotModified);
+    }
+
+    #[test]
+    fn test_cache_control_by_extension() {
+        let dir = test_dir(
+            "cache-control",
+            &[
+                ("logo.png", b"png"),
+                ("index.html", b"<p>hi</p>"),
+                ("app.js", b"run()"),
+                ("notes.txt", b"notes"),
+            ],
+        );
+        let opts = Opts {
+            cache_control: HashMap::from([
+                ("image/*".to_string(), "max-age=31536000".to_string()),
+                ("html".to_string(), "no-cache".to_string()),
+                ("js".to_string(), "max-age=604800".to_string()),
+                ("*/*".to_string(), "max-age=60".to_string()),
+            ]),
+            ..test_opts(&dir)
+        };
+        let directive = |uri: &str| {
+            get_handler(&request(Method::GET, uri, Headers::new()), &opts, None)
+                .headers
+                .get_generic("Cache-Control")
+        };
+        assert_eq!(directive("/logo.png").as_deref(), Some("max-age=31536000"));
+        assert_eq!(directive("/index.html").as_deref(), Some("no-cache"));
+        assert_eq!(directive("/app.js").as_deref(), Some("max-age=604800"));
+        assert_eq!(directive("/notes.txt").as_deref(), Some("max-age=60"));
+        assert_eq!(directive("/missing.txt"), None);
+
+        // Revalidated files keep their directive
+        let etag = get_handler(
+            &request(Method::GET, "/logo.png", Headers::new()),
+            &opts,
+            None,
+        )
+        .headers
+        .get(HeaderVariant::ETag);
+        let Some(Header::ETag(tag)) = etag else {
+            panic!("Response has no ETag");
+        };
+        let mut headers = Headers::new();
+        headers.set(Header::IfNoneMatch(vec![tag]));
+        let resp = get_handler(&request(Method::GET, "/logo.png", headers), &opts, None);
+        assert_eq!(resp.status, ResultCode::NotModified);
+        assert_eq!(
+            resp.headers.get_generic("Cache-Control").as_deref(),
+            Some("max-age=31536000")
+        );
+    }
+
+    #[test]
+    fn test_unmodified_range_is_not_modified() {
+        let dir = test_dir("ims-range", &[("index.txt", b"hello")]);
+        let opts = test_opts(&dir);
+        let resp = get_handler(
+            &request(Method::GET, "/index.txt", Headers::new()),
+            &opts,
+            None,
+        );
+        let Some(Header::LastModified(modified)) = resp.headers.get(HeaderVariant::LastModified)
+        else {
+            panic!("Response has no Last-Modified");
+        };
+        let ranged = |since| {
+            let mut headers = Headers::new();
+            headers.set(Header::IfModifiedSince(since));
+            headers.set(Header::Generic((
+                "Range".to_string(),
+                "bytes=0-1".to_string(),
+            )));
+            get_handler(&request(Method::GET, "/index.txt", headers), &opts, None)
+        };
+
+        let resp = ranged(modified);
+        assert_eq!(resp.status, ResultCode::NotModified);
+        assert!(resp.body.is_none());
+        assert!(resp.headers.get_generic("Content-Range").is_none());
+
+        let resp = ranged(modified - chrono::Duration::days(1));
+        assert_eq!(resp.status, ResultCode::PartialContent);
+        assert_eq!(resp.body, Some(b"he".to_vec()));
+    }
+
+    fn response_type(resp: &HTTPResponse) -> String {
+        let Some(Header::ContentType(mime)) = resp.headers.get(HeaderVariant::ContentType) else {
+            panic!("Response has no Content-Type");
+        };
+        mime
+    }
+
+    #[test]
+    fn test_charset_only_on_text_types() {
+        let dir = test_dir(
+            "charset",
+            &[("index.html", b"<p>caf\xc3\xa9</p>"), ("pixel.png", b"png")],
+        );
+        let opts = test_opts(&dir);
+
+        let html = get_handler(
+            &request(Method::GET, "/index.html", Headers::new()),
+            &opts,
+            None,
+        );
+        assert_eq!(response_type(&html), "text/html; charset=utf-8");
+        let png = get_handler(
+            &request(Method::GET, "/pixel.png", Headers::new()),
+            &opts,
+            None,
+        );
+        assert_eq!(response_type(&png), "image/png");
+
+        let opts = Opts {
+            charset: None,
+            ..test_opts(&dir)
+        };
+        let html = get_handler(
+            &request(Method::GET, "/index.html", Headers::new()),
+            &opts,
+            None,
+        );
+        assert_eq!(response_type(&html), "text/html");
+    }
+
+    #[test]
+    fn test_with_charset_keeps_existing_charset() {
+        let charset = Some("utf-8".to_string());
+        assert_eq!(
+            with_charset("application/json".to_string(), &charset),
+            "application/json; charset=utf-8"
+        );
+        assert_eq!(
+            with_charset("text/plain; charset=latin1".to_string(), &charset),
+            "text/plain; charset=latin1"
+        );
+        assert_eq!(
+            with_charset("application/octet-stream".to_string(), &charset),
+            "application/octet-stream"
+        );
+    }
+
+    #[test]
+    fn test_accept_ranges() {
+        assert!(accepts("text/html", "text/html"));
+        assert!(!accepts("text/plain", "text/html"));
+        assert!(accepts("text/*", "text/html"));
+        assert!(accepts("image/png, */*;q=0.1", "text/html"));
+        assert!(!accepts("*/*, text/html;q=0", "text/html"));
+        assert!(accepts("text/*;q=0, text/html", "text/html"));
+    }
+
+    #[test]
+    fn test_strict_accept_returns_not_acceptable() {
+        let dir = test_dir("strict-accept", &[("index.html", b"<p>hello</p>")]);
+        let mut headers = Headers::new();
+        headers.set(Header::Accept("text/plain".to_string()));
+
+        let lenient = test_opts(&dir);
+        let resp = get_handler(
+            &request(Method::GET, "/index.html", headers.clone()),
+            &lenient,
+            None,
+        );
+        assert_eq!(resp.status, ResultCode::OK);
+
+        let strict = Opts {
+            strict_accept: true,
+            ..test_opts(&dir)
+        };
+        let resp = get_handler(&request(Method::GET, "/index.html", headers), &strict, None);
+        assert_eq!(resp.status, ResultCode::NotAcceptable);
+
+        let mut headers = Headers::new();
+        headers.set(Header::Accept("text/plain, text/*;q=0.5".to_string()));
+        let resp = get_handler(&request(Method::GET, "/index.html", headers), &strict, None);
+        assert_eq!(resp.status, ResultCode::OK);
+    }
+
+    fn accept_gzip() -> Headers {
+        let mut headers = Headers::new();
+        headers.set(Header::AcceptEncoding(vec![ContentEncoding::GZIP.into()]));
+        headers
+    }
+
+    #[test]
+    fn test_compression_policy_matching() {
+        let policy = Opts::default().compression_policy;
+        assert!(should_compress("text/html", &policy));
+        assert!(should_compress("application/json; charset=utf-8", &policy));
+        assert!(!should_compress("image/png", &policy));
+        assert!(should_compress("image/svg+xml", &policy));
+        assert!(!should_compress("application/octet-stream", &policy));
+
+        let policy = HashMap::from([("*/*".to_string(), true)]);
+        assert!(should_compress("application/octet-stream", &policy));
+    }
+
+    #[test]
+    fn test_minimum_compression_gain() {
+        // Base64 of noise is text that gzip can only shave about a quarter off
+        let mut seed: u32 = 7;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        let encoded = base64::engine::general_purpose::STANDARD.encode(noise);
+        let dir = test_dir(
+            "compression-gain",
+            &[
+                ("noise.txt", encoded.as_bytes()),
+                ("notes.txt", &[b'a'; 4096]),
+            ],
+        );
+        let opts = Opts {
+            min_compression_gain: 40,
+            ..test_opts(&dir)
+        };
+
+        let resp = get_handler(
+            &request(Method::GET, "/noise.txt", accept_gzip()),
+            &opts,
+            None,
+        );
+        assert_eq!(response_encoding(&resp), None);
+        assert_eq!(resp.body, Some(encoded.into_bytes()));
+        assert_length_matches_body(&resp);
+
+        let resp = get_handler(
+            &request(Method::GET, "/notes.txt", accept_gzip()),
+            &opts,
+            None,
+        );
+        assert_eq!(response_encoding(&resp), Some(ContentEncoding::GZIP));
+    }
+
+    #[test]
+    fn test_range_is_served_uncompressed() {
+        let text = "hello range hello range hello range hello range";
+        let dir = test_dir(
+            "range",
+            &[("notes.txt", text.as_bytes()), ("notes.txt.gz", b"gz")],
+        );
+        let opts = test_opts(&dir);
+        let ranged = |range: &str| {
+            let mut headers = accept_encoding("gzip");
+            headers.set(Header::Generic(("Range".to_string(), range.to_string())));
+            get_handler(&request(Method::GET, "/notes.txt", headers), &opts, None)
+        };
+
+        let resp = ranged("bytes=6-10");
+        assert_eq!(resp.status, ResultCode::PartialContent);
+        assert_eq!(response_encoding(&resp), None);
+        assert_eq!(resp.body, Some(b"range".to_vec()));
+        assert_eq!(
+            resp.headers.get_generic("Content-Range"),
+            Some(format!("bytes 6-10/{}", text.len()))
+        );
+        assert_length_matches_body(&resp);
+
+        let resp = ranged("bytes=-5");
+        assert_eq!(resp.body, Some(b"range".to_vec()));
+
+        let resp = ranged("bytes=1000-");
+        assert_eq!(resp.status, ResultCode::RangeNotSatisfiable);
+        assert_eq!(
+            resp.headers.get_generic("Content-Range"),
+            Some(format!("bytes */{}", text.len()))
+        );
+
+        // Several ranges aren't supported, the whole body is sent as usual
+        let resp = ranged("bytes=0-4,6-10");
+        assert_eq!(resp.status, ResultCode::OK);
+        assert_eq!(response_encoding(&resp), Some(ContentEncoding::GZIP));
+    }
+
+    #[test]
+    fn test_failed_compression_sends_identity() {
+        let dir = test_dir("compression-fails", &[("page.html", b"<p>hello</p>")]);
+        let opts = test_opts(&dir);
+        let req = request(Method::GET, "/page.html", accept_gzip());
+        let identity = load_response(&req, &opts, None);
+        let failing = |_: &[u8], _: &ContentEncoding, _: u32| -> std::io::Result<Vec<u8>> {
+            Err(std::io::Error::other("compressor broke"))
+        };
+        let resp = compress_response_with(&req, identity.clone(), &opts, failing);
+        assert_eq!(resp.status, ResultCode::OK);
+        assert_eq!(response_encoding(&resp), None);
+        assert_eq!(resp.body, identity.body);
+        assert_length_matches_body(&resp);
+    }
+
+    #[test]
+    fn test_advertises_range_support() {
+        let dir = test_dir("accept-ranges", &[("notes.txt", b"notes")]);
+        let opts = test_opts(&dir);
+        let accept_ranges = |resp: HTTPResponse| match resp.headers.get(HeaderVariant::AcceptRanges)
+        {
+            Some(Header::AcceptRanges(units)) => Some(units),
+            _ => None,
+        };
+        let get = |uri: &str| get_handler(&request(Method::GET, uri, Headers::new()), &opts, None);
+        assert_eq!(accept_ranges(get("/notes.txt")), Some("bytes".to_string()));
+        assert_eq!(accept_ranges(get("/missing.txt")), None);
+        let options = options_handler(
+            &request(Method::OPTIONS, "/notes.txt", Headers::new()),
+            &opts,
+        );
+        assert_eq!(accept_ranges(options), Some("bytes".to_string()));
+        let document = body_handler(
+            &request(Method::GET, "/", Headers::new()),
+            b"document",
+            "text/plain",
+            &opts,
+        );
+        assert_eq!(accept_ranges(document), Some("none".to_string()));
+    }
+
+    #[test]
+    fn test_byte_range_parsing() {
+        assert_eq!(byte_range("bytes=0-4", 10), Some(Ok((0, 4))));
+        assert_eq!(byte_range("bytes=5-", 10), Some(Ok((5, 9))));
+        assert_eq!(byte_range("bytes=5-100", 10), Some(Ok((5, 9))));
+        assert_eq!(byte_range("bytes=-3", 10), Some(Ok((7, 9))));
+        assert_eq!(byte_range("bytes=-30", 10), Some(Ok((0, 9))));
+        assert_eq!(byte_range("bytes=10-", 10), Some(Err(())));
+        assert_eq!(byte_range("bytes=-0", 10), Some(Err(())));
+        assert_eq!(byte_range("bytes=4-2", 10), None);
+        assert_eq!(byte_range("items=0-4", 10), None);
+        assert_eq!(byte_range("bytes=a-b", 10), None);
+    }
+
+    #[test]
+    fn test_compression_follows_policy() {
+        let dir = test_dir(
+            "compression-policy",
+            &[("notes.txt", b"hello hello hello"), ("pixel.png", b"png")],
+        );
+        let opts = test_opts(&dir);
+
+        let resp = get_handler(
+            &request(Method::GET, "/notes.txt", accept_gzip()),
+            &opts,
+            None,
+        );
+        assert_eq!(
+            resp.headers.get(HeaderVariant::ContentEncoding),
+            Some(Header::ContentEncoding(ContentEncoding::GZIP))
+        );
+
+        let resp = get_handler(
+            &request(Method::GET, "/pixel.png", accept_gzip()),
+            &opts,
+            None,
+        );
+        assert_eq!(resp.headers.get(HeaderVariant::ContentEncoding), None);
+        assert_eq!(resp.body, Some(b"png".to_vec()));
+    }
+
+    fn accept(encodings: Vec<ContentEncoding>) -> Headers {
+        let mut headers = Headers::new();
+        headers.set(Header::AcceptEncoding(
+            encodings.into_iter().map(Into::into).collect(),
+        ));
+        headers
+    }
+
+    fn response_encoding(resp: &HTTPResponse) -> Option<ContentEncoding> {
+        match resp.headers.get(HeaderVariant::ContentEncoding) {
+            Some(Header::ContentEncoding(encoding)) => Some(encoding),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_serves_precompressed_gzip_sibling() {
+        let dir = test_dir(
+            "precompressed-gz",
+            &[("app.js", b"console.log(1)"), ("app.js.gz", b"gz-bytes")],
+        );
+        let opts = test_opts(&dir);
+        let resp = get_handler(
+            &request(Method::GET, "/app.js", accept(vec![ContentEncoding::GZIP])),
+            &opts,
+            None,
+        );
+        assert_eq!(response_encoding(&resp), Some(ContentEncoding::GZIP));
+        assert_eq!(resp.body, Some(b"gz-bytes".to_vec()));
+        assert_eq!(response_type(&resp), "text/javascript; charset=utf-8");
+        assert_length_matches_body(&resp);
+    }
+
+    #[test]
+    fn test_serves_files_precompressed_at_startup() {
+        let dir = test_dir(
+            "precompress",
+            &[
+                ("page.txt", b"hello hello hello hello"),
+                ("photo.png", b"png png png png png png"),
+                ("tiny.txt", b"hi"),
+            ],
+        );
+        let opts = Opts {
+            precompress: true,
+            min_compress_size: 10,
+            ..test_opts(&dir)
+        };
+        let mut store = GzipStore::new(1024);
+        assert_eq!(precompress(&opts, &mut store), 1);
+
+        // A rewrite that keeps the modification time goes unnoticed, the body comes
+        // from memory
+        let path = dir.join("page.txt");
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        fs::write(&path, b"HELLO hello hello hello").unwrap();
+        let touch = |time| {
+            let file = fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(time).unwrap();
+        };
+        touch(modified);
+        let decoded = || {
+            let resp = get_handler(
+                &request(Method::GET, "/page.txt", accept_gzip()),
+                &opts,
+                Some(&store),
+            );
+            assert_eq!(response_encoding(&resp), Some(ContentEncoding::GZIP));
+            assert_length_matches_body(&resp);
+            let mut decoded = Vec::new();
+            GzDecoder::new(resp.body.unwrap().as_slice())
+                .read_to_end(&mut decoded)
+                .unwrap();
+            decoded
+        };
+        assert_eq!(decoded(), b"hello hello hello hello");
+
+        // Once it changes it's compressed afresh
+        touch(modified + std::time::Duration::from_secs(1));
+        assert_eq!(decoded(), b"HELLO hello hello hello");
+    }
+
+    #[test]
+    fn test_serves_precompressed_brotli_sibling_by_preference() {
+        let dir = test_dir(
+            "precompressed-br",
+            &[
+                ("style.css", b"body {}"),
+                ("style.css.gz", b"gz-bytes"),
+                ("style.css.br", b"br-bytes"),
+            ],
+        );
+        let opts = test_opts(&dir);
+        let resp = get_handler(
+            &request(
+                Method::GET,
+                "/style.css",
+                accept(vec![ContentEncoding::BR, ContentEncoding::GZIP]),
+            ),
+            &opts,
+            None,
+        );
+        assert_eq!(response_encoding(&resp), Some(ContentEncoding::BR));
+        assert_eq!(resp.body, Some(b"br-bytes".to_vec()));
+        assert_eq!(response_type(&resp), "text/css; charset=utf-8");
+
+        let resp = get_handler(
+            &request(
+                Method::GET,
+                "/style.css",
+                accept(vec![ContentEncoding::GZIP, ContentEncoding::BR]),
+            ),
+            &opts,
+            None,
+        );
+        assert_eq!(resp.body, Some(b"gz-bytes".to_vec()));
+    }
+
+    fn accept_encoding(value: &str) -> Headers {
+        Headers::try_from(format!("Accept-Encoding: {}\r\n\r\n", value).as_str()).unwrap()
+    }
+
+    #[test]
+    fn test_accept_encoding_identity_and_any() {
+        let dir = test_dir(
+            "accept-any",
+            &[
+                ("app.js", b"let a = 1; let a = 1; let a = 1;"),
+                ("pixel.png", b"png"),
+            ],
+        );
+        let opts = test_opts(&dir);
+        let get = |uri: &str, value: &str| {
+            get_handler(
+                &request(Method::GET, uri, accept_encoding(value)),
+                &opts,
+                None,
+            )
+        };
+
+        assert_eq!(
+            response_encoding(&get("/app.js", "*")),
+            Some(ContentEncoding::GZIP)
+        );
+        assert_eq!(
+            response_encoding(&get("/app.js", "*, gzip;q=0")),
+            Some(ContentEncoding::DEFLATE)
+        );
+        let resp = get("/app.js", "identity, gzip;q=0.5");
+        assert_eq!(response_encoding(&resp), None);
+        assert_eq!(resp.status, ResultCode::OK);
+
+        // Refusing identity forces compression even for types we'd leave alone
+        assert_eq!(
+            response_encoding(&get("/pixel.png", "gzip, identity;q=0")),
+            Some(ContentEncoding::GZIP)
+        );
+        assert_eq!(
+            get("/app.js", "br, identity;q=0").status,
+            ResultCode::NotAcceptable
+        );
+        assert_eq!(get("/app.js", "*;q=0").status, ResultCode::NotAcceptable);
+        assert_eq!(get("/missing.js", "*;q=0").status, ResultCode::NotFound);
+    }
+
+    #[test]
+    fn test_skips_stale_precompressed_sibling() {
+        let dir = test_dir(
+            "precompressed-stale",
+            &[("index.html", b"<p>new</p>"), ("index.html.br", b"old")],
+        );
+        let sibling = std::fs::File::options()
+            .write(true)
+            .open(dir.join("index.html.br"))
+            .unwrap();
+        sibling
+            .set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(60))
+            .unwrap();
+        let opts = test_opts(&dir);
+
+        // Without a fresh sibling or a brotli compressor the file goes out as is
+        let resp = get_handler(
+            &request(
+                Method::GET,
+                "/index.html",
+                accept(vec![ContentEncoding::BR]),
+            ),
+            &opts,
+            None,
+        );
+        assert_eq!(response_encoding(&resp), None);
+        assert_eq!(resp.body, Some(b"<p>new</p>".to_vec()));
+    }
+
+    #[test]
+    fn test_large_listing_is_compressed() {
+        let files: Vec<(String, Vec<u8>)> = (0..200)
+            .map(|i| (format!("report-{:04}.csv", i), b"x".to_vec()))
+            .collect();
+        let files: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(name, content)| (name.as_str(), content.as_slice()))
+            .collect();
+        let dir = test_dir("listing-gzip", &files);
+        let opts = test_opts(&dir);
+
+        let identity = get_handler(&request(Method::GET, "/", Headers::new()), &opts, None);
+        let compressed = get_handler(&request(Method::GET, "/", accept_gzip()), &opts, None);
+        assert_eq!(response_encoding(&compressed), Some(ContentEncoding::GZIP));
+        assert_length_matches_body(&compressed);
+        assert!(compressed.body.as_ref().unwrap().len() < identity.body.as_ref().unwrap().len());
+
+        let mut decoded = Vec::new();
+        GzDecoder::new(compressed.body.as_ref().unwrap().as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(Some(decoded), identity.body);
+    }
+
+    #[test]
+    fn test_small_bodies_skip_compression() {
+        let dir = test_dir("min-compress", &[("tiny.txt", b"tiny")]);
+        let opts = Opts {
+            min_compress_size: 1024,
+            ..test_opts(&dir)
+        };
+        let resp = get_handler(
+            &request(Method::GET, "/tiny.txt", accept_gzip()),
+            &opts,
+            None,
+        );
+        assert_eq!(response_encoding(&resp), None);
+        let resp = get_handler(
+            &request(Method::GET, "/missing.txt", accept_gzip()),
+            &opts,
+            None,
+        );
+        assert_eq!(response_encoding(&resp), None);
+
+        // Error pages are compressed like anything else once they're large enough
+        let resp = get_handler(
+            &request(Method::GET, "/missing.txt", accept_gzip()),
+            &test_opts(&dir),
+            None,
+        );
+        assert_eq!(response_encoding(&resp), Some(ContentEncoding::GZIP));
+    }
+
+    #[test]
+    fn test_compression_disabled() {
+        let dir = test_dir(
+            "no-compression",
+            &[
+                ("page.html", b"<p>compressible</p>"),
+                ("app.js", b"a"),
+                ("app.js.gz", b"gz"),
+            ],
+        );
+        let opts = Opts {
+            compression: false,
+            ..test_opts(&dir)
+        };
+        for uri in ["/page.html", "/app.js", "/missing.html", "/"] {
+            let resp = get_handler(&request(Method::GET, uri, accept_gzip()), &opts, None);
+            assert_eq!(response_encoding(&resp), None, "{}", uri);
+        }
+        let resp = get_handler(&request(Method::GET, "/app.js", accept_gzip()), &opts, None);
+        assert_eq!(resp.body, Some(b"a".to_vec()));
+    }
+
+    fn assert_length_matches_body(resp: &HTTPResponse) {
+        let Some(Header::ContentLength(len)) = resp.headers.get(HeaderVariant::ContentLength)
+        else {
+            panic!("Response has no Content-Length");
+        };
+        assert_eq!(len, resp.body.as_ref().unwrap().len());
+    }
+
+    #[test]
+    fn test_generated_pages_have_byte_length() {
+        let dir = test_dir("generated-length", &[("café/naïve.txt", b"a")]);
+        let opts = test_opts(&dir);
+
+        let listing = get_handler(&request(Method::GET, "/café/", Headers::new()), &opts, None);
+        assert_eq!(listing.status, ResultCode::OK);
+        let body = String::from_utf8(listing.body.clone().unwrap()).unwrap();
+        assert!(body.contains("naïve.txt"));
+        assert!(body.len() > body.chars().count());
+        assert_length_matches_body(&listing);
+
+        let missing = get_handler(
+            &request(Method::GET, "/café/gone", Headers::new()),
+            &opts,
+            None,
+        );
+        assert_eq!(missing.status, ResultCode::NotFound);
+        assert_length_matches_body(&missing);
+        let opts = Opts {
+            directory_listing: false,
+            ..opts
+        };
+        let refused = get_handler(&request(Method::GET, "/café", Headers::new()), &opts, None);
+        assert_eq!(refused.status, ResultCode::Forbidden);
+        assert_length_matches_body(&refused);
+    }
+
+    #[test]
+    fn test_content_length_matches_body() {
+        let content = "compress me ".repeat(100);
+        let dir = test_dir("content-length", &[("big.txt", content.as_bytes())]);
+        let opts = test_opts(&dir);
+
+        let compressed = get_handler(
+            &request(Method::GET, "/big.txt", accept_gzip()),
+            &opts,
+            None,
+        );
+        assert!(compressed
+            .headers
+            .get(HeaderVariant::ContentEncoding)
+            .is_some());
+        assert!(compressed.body.as_ref().unwrap().len() < content.len());
+        assert_length_matches_body(&compressed);
+
+        let identity = get_handler(
+            &request(Method::GET, "/big.txt", Headers::new()),
+            &opts,
+            None,
+        );
+        assert!(identity
+            .headers
+            .get(HeaderVariant::ContentEncoding)
+            .is_none());
+        assert_eq!(identity.body.as_ref().unwrap().len(), content.len());
+        assert_length_matches_body(&identity);
+    }
+
+    #[test]
+    fn test_basic_auth_success() {
+        let mut headers = Headers::new();
+        headers.set(Header::Authorization(
+            "Basic YWRtaW46cGFzc3dvcmQ=".to_string(),
+        ));
+        let req = HTTPRequest {
+            method: crate::http10::methods::Method::GET,
+            uri: "/".to_string(),
+            version: "HTTP/1.0".to_string(),
+            headers,
+            body: None,
+            trailers: None,
+            peer: None,
+        };
+        let auth = Auth {
+            username: "admin".to_string(),
+            password: "password".to_string(),
+        };
+
+        assert!(basic_auth(&req, &auth).is_ok());
     }
 
     #[test]
@@ -213,6 +2004,8 @@ mod test {
             version: "HTTP/1.0".to_string(),
             headers: Headers::new(),
             body: None,
+            trailers: None,
+            peer: None,
         };
         let auth = Auth {
             username: "admin".to_string(),
@@ -233,6 +2026,8 @@ mod test {
             version: "HTTP/1.0".to_string(),
             headers,
             body: None,
+            trailers: None,
+            peer: None,
         };
         let auth = Auth {
             username: "admin".to_string(),
@@ -240,4 +2035,349 @@ mod test {
         };
         assert!(basic_auth(&req, &auth).is_err());
     }
+
+    fn authorized_request(uri: &str, username: &str, password: &str) -> HTTPRequest {
+        let mut headers = Headers::new();
+        headers.set(Header::Authorization(format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", username, password))
+        )));
+        request(Method::GET, uri, headers)
+    }
+
+    #[test]
+    fn test_auth_rules_by_prefix() {
+        let account = |username: &str| Auth {
+            username: username.to_string(),
+            password: "secret".to_string(),
+        };
+        let opts = Opts {
+            auth_rules: vec![
+                ("/admin".to_string(), account("admin")),
+                ("/reports".to_string(), account("analyst")),
+                ("/reports/private/".to_string(), account("auditor")),
+            ],
+            ..Opts::default()
+        };
+
+        let check = |uri: &str, username: &str| {
+            let auth = auth_for(uri, &opts).expect("Path should require auth");
+            basic_auth(&authorized_request(uri, username, "secret"), auth).is_ok()
+        };
+        assert!(check("/admin/users", "admin"));
+        assert!(!check("/admin/users", "analyst"));
+        assert!(check("/reports?year=2024", "analyst"));
+        assert!(!check("/reports", "admin"));
+        assert!(check("/reports/private/q3.csv", "auditor"));
+        assert!(!check("/reports/private/q3.csv", "analyst"));
+
+        assert!(auth_for("/", &opts).is_none());
+        assert!(auth_for("/administrator", &opts).is_none());
+    }
+
+    #[test]
+    fn test_auth_rules_fall_back_to_global_auth() {
+        let opts = Opts {
+            auth: Some(Auth {
+                username: "admin".to_string(),
+                password: "password".to_string(),
+            }),
+            auth_rules: vec![(
+                "/public-ish".to_string(),
+                Auth {
+                    username: "guest".to_string(),
+                    password: "guest".to_string(),
+                },
+            )],
+            ..Opts::default()
+        };
+        assert_eq!(auth_for("/index.html", &opts), opts.auth.as_ref());
+        assert_eq!(
+            auth_for("/public-ish/a", &opts),
+            Some(&opts.auth_rules[0].1)
+        );
+    }
+
+    #[test]
+    fn test_normalize_uri() {
+        assert_eq!(normalize_uri("/").as_deref(), Some("/"));
+        assert_eq!(
+            normalize_uri("//admin/./x?a=/b").as_deref(),
+            Some("/admin/x?a=/b")
+        );
+        assert_eq!(normalize_uri("/docs/").as_deref(), Some("/docs/"));
+        assert_eq!(normalize_uri("/public/../admin/x"), None);
+        assert_eq!(normalize_uri("/.."), None);
+        assert_eq!(normalize_uri("/a..b/.c").as_deref(), Some("/a..b/.c"));
+    }
+
+    #[test]
+    fn test_auth_rules_match_resolved_paths() {
+        let opts = Opts {
+            auth_rules: vec![
+                (
+                    "/admin".to_string(),
+                    Auth {
+                        username: "admin".to_string(),
+                        password: "secret".to_string(),
+                    },
+                ),
+                (
+                    "/secret.txt".to_string(),
+                    Auth {
+                        username: "keeper".to_string(),
+                        password: "secret".to_string(),
+                    },
+                ),
+            ],
+            ..Opts::default()
+        };
+        assert!(auth_for("//admin/x", &opts).is_some());
+        assert!(auth_for("/./admin", &opts).is_some());
+        // Only a case insensitive server would find secret.txt for /SECRET.TXT
+        assert!(auth_for("/SECRET.TXT", &opts).is_none());
+        let insensitive = Opts {
+            case_insensitive: true,
+            ..opts
+        };
+        assert_eq!(
+            auth_for("/SECRET.TXT", &insensitive).map(|auth| auth.username.as_str()),
+            Some("keeper")
+        );
+        assert!(auth_for("/Admin/users", &insensitive).is_some());
+    }
+
+    fn cors_opts() -> Opts {
+        Opts {
+            cors_origin: Some("https://app.example.com".to_string()),
+            cors_max_age: Some(std::time::Duration::from_secs(600)),
+            ..Opts::default()
+        }
+    }
+
+    #[test]
+    fn test_preflight_is_cacheable() {
+        let mut headers = Headers::new();
+        headers.set(Header::Generic((
+            "Origin".to_string(),
+            "https://app.example.com".to_string(),
+        )));
+        headers.set(Header::Generic((
+            "Access-Control-Request-Method".to_string(),
+            "GET".to_string(),
+        )));
+        let resp = options_handler(&request(Method::OPTIONS, "/api", headers), &cors_opts());
+        assert_eq!(resp.status, ResultCode::NoContent);
+        assert_eq!(
+            resp.headers.get_generic("Access-Control-Max-Age"),
+            Some("600".to_string())
+        );
+        assert_eq!(
+            resp.headers.get_generic("Access-Control-Allow-Origin"),
+            Some("https://app.example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn test_plain_options_has_no_max_age() {
+        let resp = options_handler(
+            &request(Method::OPTIONS, "/api", Headers::new()),
+            &cors_opts(),
+        );
+        assert_eq!(resp.status, ResultCode::NoContent);
+        assert!(resp.headers.get(HeaderVariant::Allow).is_some());
+        assert!(resp.headers.get_generic("Access-Control-Max-Age").is_none());
+        assert!(resp
+            .headers
+            .get_generic("Access-Control-Allow-Origin")
+            .is_none());
+    }
+
+    #[test]
+    fn test_actual_responses_allow_origin() {
+        let resp = allow_origin(
+            get_handler(
+                &request(Method::GET, "/Cargo.toml", Headers::new()),
+                &cors_opts(),
+                None,
+            ),
+            &cors_opts(),
+        );
+        assert_eq!(resp.status, ResultCode::OK);
+        assert_eq!(
+            resp.headers.get_generic("Access-Control-Allow-Origin"),
+            Some("https://app.example.com".to_string())
+        );
+        assert_eq!(resp.headers.get_generic("Vary"), Some("Origin".to_string()));
+
+        let resp = allow_origin(
+            get_handler(
+                &request(Method::GET, "/Cargo.toml", Headers::new()),
+                &Opts::default(),
+                None,
+            ),
+            &Opts::default(),
+        );
+        assert!(resp
+            .headers
+            .get_generic("Access-Control-Allow-Origin")
+            .is_none());
+    }
+
+    #[test]
+    fn test_empty_file_as_no_content() {
+        let dir = test_dir("empty-file", &[("empty.txt", b"")]);
+        let req = request(Method::GET, "/empty.txt", Headers::new());
+
+        let resp = get_handler(&req, &test_opts(&dir), None);
+        assert_eq!(resp.status, ResultCode::OK);
+        assert_eq!(resp.body, Some(Vec::new()));
+
+        let opts = Opts {
+            empty_as_no_content: true,
+            ..test_opts(&dir)
+        };
+        let resp = get_handler(&req, &opts, None);
+        assert_eq!(resp.status, ResultCode::NoContent);
+        assert!(resp.body.is_none());
+        assert!(resp.headers.get(HeaderVariant::ContentType).is_none());
+        assert!(resp.headers.get(HeaderVariant::ContentLength).is_none());
+    }
+
+    fn writable_opts(dir: &Path) -> Opts {
+        Opts {
+            writable_paths: vec!["/uploads".to_string()],
+            ..test_opts(dir)
+        }
+    }
+
+    fn allow(resp: &HTTPResponse) -> Vec<Method> {
+        let Some(Header::Allow(methods)) = resp.headers.get(HeaderVariant::Allow) else {
+            panic!("Response has no Allow");
+        };
+        methods
+    }
+
+    #[test]
+    fn test_allow_reflects_writable_mounts() {
+        let dir = test_dir(
+            "allow-mounts",
+            &[("index.html", b"<html></html>"), ("uploads/a.txt", b"a")],
+        );
+        let opts = writable_opts(&dir);
+        let options =
+            |uri: &str| options_handler(&request(Method::OPTIONS, uri, Headers::new()), &opts);
+
+        assert_eq!(
+            allow(&options("/uploads/a.txt")),
+            vec![
+                Method::GET,
+                Method::HEAD,
+                Method::OPTIONS,
+                Method::PUT,
+                Method::DELETE
+            ]
+        );
+        assert_eq!(
+            allow(&options("/index.html")),
+            vec![Method::GET, Method::HEAD, Method::OPTIONS]
+        );
+
+        // A mount that is read-only on disk can't take writes either
+        let uploads = dir.join("uploads");
+        let mut permissions = std::fs::metadata(&uploads).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&uploads, permissions.clone()).unwrap();
+        let read_only = allow(&options("/uploads/a.txt"));
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        std::fs::set_permissions(&uploads, permissions).unwrap();
+        assert_eq!(read_only, vec![Method::GET, Method::HEAD, Method::OPTIONS]);
+    }
+
+    #[test]
+    fn test_put_and_delete_on_writable_mount() {
+        let dir = test_dir("put-delete", &[("index.html", b"<html></html>")]);
+        std::fs::create_dir_all(dir.join("uploads")).unwrap();
+        let opts = writable_opts(&dir);
+        let put = |uri: &str, body: &[u8]| {
+            let mut req = request(Method::PUT, uri, Headers::new());
+            req.body = Some(body.to_vec());
+            put_handler(&req, &opts)
+        };
+        let delete =
+            |uri: &str| delete_handler(&request(Method::DELETE, uri, Headers::new()), &opts);
+
+        assert_eq!(put("/uploads/b.txt", b"first").status, ResultCode::Created);
+        assert_eq!(
+            put("/uploads/b.txt", b"second").status,
+            ResultCode::NoContent
+        );
+        assert_eq!(std::fs::read(dir.join("uploads/b.txt")).unwrap(), b"second");
+        assert_eq!(
+            put("/uploads/../escaped.txt", b"x").status,
+            ResultCode::Forbidden
+        );
+        assert!(!dir.join("escaped.txt").exists());
+
+        assert_eq!(delete("/uploads/b.txt").status, ResultCode::NoContent);
+        assert_eq!(delete("/uploads/b.txt").status, ResultCode::NotFound);
+
+        // Outside the mount the methods aren't allowed at all
+        let resp = put("/index.html", b"changed");
+        assert_eq!(resp.status, ResultCode::MethodNotAllowed);
+        assert_eq!(
+            allow(&resp),
+            vec![Method::GET, Method::HEAD, Method::OPTIONS]
+        );
+        assert_eq!(delete("/index.html").status, ResultCode::MethodNotAllowed);
+        assert_eq!(
+            std::fs::read(dir.join("index.html")).unwrap(),
+            b"<html></html>"
+        );
+    }
+
+    fn forwarded_request(peer: &str, forwarded: &str) -> HTTPRequest {
+        let mut headers = Headers::new();
+        headers.set(Header::Generic((
+            "X-Forwarded-For".to_string(),
+            forwarded.to_string(),
+        )));
+        HTTPRequest {
+            method: crate::http10::methods::Method::GET,
+            uri: "/".to_string(),
+            version: "HTTP/1.0".to_string(),
+            headers,
+            body: None,
+            trailers: None,
+            peer: Some(peer.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_forwarded_client_from_trusted_proxy() {
+        let trusted: Vec<IpAddr> = vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+        let req = forwarded_request("10.0.0.1:4000", "6.6.6.6, 203.0.113.9, 10.0.0.2");
+        assert_eq!(
+            forwarded_client(&req, &trusted),
+            Some("203.0.113.9".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_forwarded_client_ignored_from_untrusted_peer() {
+        let trusted: Vec<IpAddr> = vec!["10.0.0.1".parse().unwrap()];
+        let req = forwarded_request("192.0.2.7:4000", "203.0.113.9");
+        assert_eq!(forwarded_client(&req, &trusted), None);
+    }
+
+    #[test]
+    fn test_vhost_name_drops_bound_port() {
+        assert_eq!(vhost_name("Example.com:8080", 8080), "example.com");
+        assert_eq!(vhost_name("example.com", 8080), "example.com");
+        assert_eq!(vhost_name("example.com:9090", 8080), "example.com:9090");
+        assert_eq!(vhost_name("[::1]:8080", 8080), "[::1]");
+        assert_eq!(vhost_name("[::1]", 8080), "[::1]");
+        assert_eq!(vhost_name("[::1]:9090", 8080), "[::1]:9090");
+    }
 }
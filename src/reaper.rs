@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Enforces a hard deadline on connections. A supervisor thread shuts down the
+// sockets of connections served for too long, through a cloned handle, so a
+// worker stuck on the connection fails its next read or write and is freed.
+pub struct Reaper {
+    deadline: Duration,
+    next: AtomicU64,
+    connections: Mutex<HashMap<u64, (Instant, TcpStream)>>,
+}
+
+// Stops tracking the connection once dropped
+pub struct Tracked<'a> {
+    reaper: &'a Reaper,
+    id: u64,
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut connections) = self.reaper.connections.lock() {
+            connections.remove(&self.id);
+        }
+    }
+}
+
+impl Reaper {
+    // Starts the supervisor, which goes away with the last reference to the reaper
+    pub fn start(deadline: Duration) -> Arc<Reaper> {
+        let reaper = Arc::new(Reaper {
+            deadline,
+            next: AtomicU64::new(0),
+            connections: Mutex::new(HashMap::new()),
+        });
+        let supervised = Arc::downgrade(&reaper);
+        // Overshooting the deadline by a tenth is close enough
+        let interval = (deadline / 10).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        thread::Builder::new()
+            .name("reaper".to_string())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                match supervised.upgrade() {
+                    Some(reaper) => reaper.reap(),
+                    None => return,
+                }
+            })
+            .expect("Unable to start the connection reaper");
+        reaper
+    }
+
+    pub fn track(&self, stream: &TcpStream) -> Option<Tracked<'_>> {
+        let handle = match stream.try_clone() {
+            Ok(handle) => handle,
+            Err(err) => {
+                log::warn!("Unable to track connection for reaping: {}", err);
+                return None;
+            }
+        };
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        self.connections
+            .lock()
+            .ok()?
+            .insert(id, (Instant::now(), handle));
+        Some(Tracked { reaper: self, id })
+    }
+
+    fn reap(&self) {
+        let Ok(mut connections) = self.connections.lock() else {
+            return;
+        };
+        connections.retain(|_, (started, stream)| {
+            let served = started.elapsed();
+            if served < self.deadline {
+                return true;
+            }
+            let remote = stream
+                .peer_addr()
+                .map_or("Invalid Address".to_string(), |addr| addr.to_string());
+            log::warn!("Reaping connection from {} after {:?}", remote, served);
+            let _ = stream.shutdown(Shutdown::Both);
+            false
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::stream_pair;
+    use std::io::Read;
+
+    #[test]
+    fn finished_connections_are_untracked() {
+        let reaper = Reaper::start(Duration::from_secs(60));
+        let (server, _client) = stream_pair();
+        let tracked = reaper.track(&server).unwrap();
+        assert_eq!(reaper.connections.lock().unwrap().len(), 1);
+        drop(tracked);
+        assert!(reaper.connections.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn overdue_connections_are_shut_down() {
+        let reaper = Reaper::start(Duration::from_millis(50));
+        let (server, mut client) = stream_pair();
+        let _tracked = reaper.track(&server).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+        assert!(reaper.connections.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,45 @@
+// Fixtures shared by the unit tests of several modules
+
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+// A directory under the system temp dir, removed again once the test drops it
+pub struct TempDir(PathBuf);
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// Creates a fresh directory populated with files, their paths may include subdirectories.
+// The name keeps tests running in parallel apart.
+pub fn test_dir(name: &str, files: &[(&str, &[u8])]) -> TempDir {
+    let dir = std::env::temp_dir().join(format!("rusty-webserver-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    for (path, content) in files {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+    TempDir(dir)
+}
+
+// Both ends of a loopback connection, the accepted one first
+pub fn stream_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    (server, client)
+}
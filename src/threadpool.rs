@@ -1,116 +1,342 @@
 use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+
+// Order in which queued jobs are picked up by the workers
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum QueueOrder {
+    // Oldest job first
+    Fifo,
+    // Newest job first, lets stale jobs age out under overload. Workers only look
+    // at their own queue's newest, so across queues the order is approximate.
+    Lifo,
+}
 
 // Thread pool that accepts jobs and threads handle them when they
-// become available
+// become available. Every worker has its own queue so pushing and taking
+// jobs doesn't serialize on one lock, idle workers steal from the others.
 pub struct ThreadPoolQ<T> {
-    queue: Arc<Mutex<Option<VecDeque<T>>>>,
+    shared: Arc<Shared<T>>,
     threads: Vec<Option<thread::JoinHandle<()>>>,
-    cvar: Arc<(Condvar, Mutex<bool>)>,
+    next: usize,
+    // How long drop waits for busy workers, forever if None
+    shutdown_grace: Option<Duration>,
+}
+
+struct Shared<T> {
+    queues: Vec<Mutex<VecDeque<T>>>,
+    order: QueueOrder,
+    // Jobs pushed but not yet taken by a worker
+    pending: AtomicUsize,
+    // Workers waiting for a job, pushes only wake someone if there are any
+    sleeping: AtomicUsize,
+    shutdown: AtomicBool,
+    idle: (Mutex<()>, Condvar),
+}
+
+impl<T> Shared<T> {
+    fn new(size: usize, order: QueueOrder) -> Shared<T> {
+        Shared {
+            queues: (0..size).map(|_| Mutex::new(VecDeque::new())).collect(),
+            order,
+            pending: AtomicUsize::new(0),
+            sleeping: AtomicUsize::new(0),
+            shutdown: AtomicBool::new(false),
+            idle: (Mutex::new(()), Condvar::new()),
+        }
+    }
+
+    // Takes from the worker's own queue first, then from its neighbours, holding one
+    // lock at a time. Pushes go round the queues in turn, so the fronts are roughly
+    // the oldest jobs overall and the backs the newest.
+    fn take(&self, worker: usize) -> Option<T> {
+        let count = self.queues.len();
+        let job = (0..count).find_map(|offset| {
+            let mut queue = self.queues[(worker + offset) % count].lock().unwrap();
+            match self.order {
+                QueueOrder::Fifo => queue.pop_front(),
+                QueueOrder::Lifo => queue.pop_back(),
+            }
+        });
+        if job.is_some() {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+        job
+    }
+
+    fn work(&self, worker: usize, f: &(impl Fn(T) + ?Sized)) {
+        loop {
+            if let Some(job) = self.take(worker) {
+                f(job);
+                continue;
+            }
+            let lock = self.idle.0.lock().unwrap();
+            self.sleeping.fetch_add(1, Ordering::SeqCst);
+            // A job may still be on its way into a queue, look again rather than sleep
+            if self.pending.load(Ordering::SeqCst) > 0 {
+                self.sleeping.fetch_sub(1, Ordering::SeqCst);
+                continue;
+            }
+            if self.shutdown.load(Ordering::SeqCst) {
+                return;
+            }
+            drop(self.idle.1.wait(lock).unwrap());
+            self.sleeping.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
 }
 
 impl<T> ThreadPoolQ<T>
 where
     T: Send + 'static,
 {
-    pub fn new(size: usize, f: impl Fn(T) -> () + Send + Sync + 'static) -> ThreadPoolQ<T> {
-        let mut threads: Vec<Option<thread::JoinHandle<()>>> = Vec::with_capacity(size);
-        let q: Arc<Mutex<Option<VecDeque<T>>>> = Arc::new(Mutex::new(Some(VecDeque::new())));
-        let cvar = Arc::new((Condvar::new(), Mutex::new(true)));
+    pub fn new(
+        size: usize,
+        order: QueueOrder,
+        f: impl Fn(T) + Send + Sync + 'static,
+    ) -> ThreadPoolQ<T> {
+        Self::with_stack_size(size, order, None, f)
+    }
+
+    // Pool of size workers calling f on each job, with stack_size bytes of stack
+    // instead of the platform default when set. There must be at least one worker.
+    pub fn with_stack_size(
+        size: usize,
+        order: QueueOrder,
+        stack_size: Option<usize>,
+        f: impl Fn(T) + Send + Sync + 'static,
+    ) -> ThreadPoolQ<T> {
+        assert!(size > 0, "A thread pool needs at least one worker");
+        let shared = Arc::new(Shared::new(size, order));
         let f = Arc::new(f);
-        for _ in 0..size {
-            let q = Arc::clone(&q);
-            let cvar = Arc::clone(&cvar);
-            let f = Arc::clone(&f);
-            threads.push(Some(thread::spawn(move || loop {
-                let mut queue = q.lock().unwrap();
-                if queue.is_none() {
-                    return;
-                }
-                let job = queue.as_mut().unwrap().pop_front();
-                drop(queue);
-                if job.is_none() {
-                    let mut lock = cvar.1.lock().unwrap();
-                    *lock = false;
-                    drop(lock);
-                    drop(cvar.0.wait(cvar.1.lock().unwrap()));
-                    continue;
+        let threads = (0..size)
+            .map(|worker| {
+                let shared = Arc::clone(&shared);
+                let f = Arc::clone(&f);
+                let mut builder = thread::Builder::new().name(format!("rusty-worker-{}", worker));
+                if let Some(stack_size) = stack_size {
+                    builder = builder.stack_size(stack_size);
                 }
-                if job.is_some() {
-                    f(job.unwrap());
-                }
-            })));
-        }
+                let handle = builder
+                    .spawn(move || shared.work(worker, f.as_ref()))
+                    .expect("Unable to spawn worker thread");
+                Some(handle)
+            })
+            .collect();
         ThreadPoolQ {
-            queue: q,
+            shared,
             threads,
-            cvar,
+            next: 0,
+            shutdown_grace: None,
         }
     }
 
+    // Bounds how long dropping the pool waits for the workers to finish the queue.
+    // Workers still busy after that are detached and left to run.
+    pub fn with_shutdown_grace(mut self, grace: Option<Duration>) -> ThreadPoolQ<T> {
+        self.shutdown_grace = grace;
+        self
+    }
+
     pub fn push_job(&mut self, job: T) {
-        let mut q = self.queue.lock().unwrap();
-        q.as_mut().unwrap().push_back(job);
-        let mut lock = self.cvar.1.lock().unwrap();
-        *lock = true;
-        self.cvar.0.notify_all();
+        // Spread jobs over the workers' queues in turn
+        let worker = self.next;
+        self.next = (self.next + 1) % self.shared.queues.len();
+        self.shared.pending.fetch_add(1, Ordering::SeqCst);
+        self.shared.queues[worker].lock().unwrap().push_back(job);
+        if self.shared.sleeping.load(Ordering::SeqCst) > 0 {
+            // Taking the lock waits for a worker that is about to sleep to do so
+            drop(self.shared.idle.0.lock().unwrap());
+            self.shared.idle.1.notify_one();
+        }
     }
 }
 
 impl<T> Drop for ThreadPoolQ<T> {
     fn drop(&mut self) {
-        loop {
-            let q = self.queue.lock().unwrap();
-            if q.is_some() && q.as_ref().unwrap().is_empty() {
-                break;
+        // Workers finish the queued jobs before they exit
+        let lock = self.shared.idle.0.lock().unwrap();
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        drop(lock);
+        self.shared.idle.1.notify_all();
+        if let Some(grace) = self.shutdown_grace {
+            let deadline = Instant::now() + grace;
+            while Instant::now() < deadline
+                && self.threads.iter().flatten().any(|t| !t.is_finished())
+            {
+                thread::sleep(Duration::from_millis(10));
             }
-            drop(q);
         }
-        let mut q = self.queue.lock().unwrap();
-        q.take();
-        drop(q);
-        let mut lock = self.cvar.1.lock().unwrap();
-        *lock = true;
-        drop(lock);
-        self.cvar.0.notify_all();
+        let mut detached = 0;
         for t in &mut self.threads {
-            if let Some(t) = t.take() {
-                t.join().unwrap();
+            match t.take() {
+                Some(t) if self.shutdown_grace.is_none() || t.is_finished() => t.join().unwrap(),
+                Some(_) => detached += 1,
+                None => (),
             }
         }
+        if detached > 0 {
+            log::warn!(
+                "Detaching {} workers still busy after {:?}",
+                detached,
+                self.shutdown_grace.unwrap_or_default()
+            );
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
 
     use super::*;
 
     #[test]
     fn creates_a_new_threadpool() {
-        let tp = ThreadPoolQ::<usize>::new(5, |_| {
+        let tp = ThreadPoolQ::<usize>::new(5, QueueOrder::Fifo, |_| {
             thread::sleep(Duration::from_secs(1));
         });
         assert_eq!(5, tp.threads.capacity());
-        let q = tp.queue.lock().unwrap();
-        assert!(q.is_some());
+        assert_eq!(5, tp.shared.queues.len());
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one worker")]
+    fn refuses_an_empty_pool() {
+        ThreadPoolQ::new(0, QueueOrder::Fifo, |_: usize| {});
     }
 
     #[test]
     fn handles_jobs_in_order() {
-        let mut tp = ThreadPoolQ::new(1, |num: usize| {
+        let mut tp = ThreadPoolQ::new(1, QueueOrder::Fifo, |num: usize| {
             println!("Received: {}", num);
             thread::sleep(Duration::from_secs(1));
         });
         tp.push_job(1);
         tp.push_job(2);
         thread::sleep(Duration::from_secs(1));
-        let q = tp.queue.lock().unwrap();
-        assert_eq!(q.as_ref().unwrap().len(), 1);
+        let q = tp.shared.queues[0].lock().unwrap();
+        assert_eq!(q.len(), 1);
         let mut nq: VecDeque<usize> = VecDeque::new();
         nq.push_back(2);
-        assert_eq!(q.as_ref().unwrap(), &nq);
+        assert_eq!(*q, nq);
+    }
+
+    fn processing_order(order: QueueOrder) -> Vec<usize> {
+        let processed = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Arc::clone(&processed);
+        let mut tp = ThreadPoolQ::new(1, order, move |num: usize| {
+            recorder.lock().unwrap().push(num);
+            thread::sleep(Duration::from_millis(200));
+        });
+        // Let the worker pick up the first job so the rest queue behind it
+        tp.push_job(1);
+        thread::sleep(Duration::from_millis(50));
+        tp.push_job(2);
+        tp.push_job(3);
+        tp.push_job(4);
+        drop(tp);
+        let processed = processed.lock().unwrap();
+        processed.clone()
+    }
+
+    #[test]
+    fn processes_oldest_first_in_fifo_mode() {
+        assert_eq!(processing_order(QueueOrder::Fifo), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn processes_newest_first_in_lifo_mode() {
+        assert_eq!(processing_order(QueueOrder::Lifo), vec![1, 4, 3, 2]);
+    }
+
+    #[test]
+    fn runs_jobs_with_custom_stack_size() {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let mut tp = ThreadPoolQ::with_stack_size(
+            2,
+            QueueOrder::Fifo,
+            Some(4 * 1024 * 1024),
+            move |num: usize| {
+                // Use a good part of the stack to show it's really there
+                let buf = [num as u8; 1024 * 1024];
+                tx.lock()
+                    .unwrap()
+                    .send(std::hint::black_box(buf)[1024])
+                    .unwrap();
+            },
+        );
+        tp.push_job(7);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 7);
+    }
+
+    #[test]
+    fn names_worker_threads() {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let mut tp = ThreadPoolQ::new(2, QueueOrder::Fifo, move |_: usize| {
+            let name = thread::current().name().map(str::to_string);
+            tx.lock().unwrap().send(name).unwrap();
+        });
+        tp.push_job(0);
+        tp.push_job(1);
+        let names: Vec<Option<String>> = (0..2)
+            .map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        assert!(names
+            .iter()
+            .all(|name| matches!(name.as_deref(), Some("rusty-worker-0" | "rusty-worker-1"))));
+    }
+
+    #[test]
+    fn drop_stops_waiting_after_grace_period() {
+        let mut tp = ThreadPoolQ::new(1, QueueOrder::Fifo, |_: usize| {
+            thread::sleep(Duration::from_secs(2));
+        })
+        .with_shutdown_grace(Some(Duration::from_millis(100)));
+        tp.push_job(1);
+        thread::sleep(Duration::from_millis(20));
+        let start = Instant::now();
+        drop(tp);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn idle_workers_steal_queued_jobs() {
+        let (tx, rx) = mpsc::channel::<()>();
+        let (tx, rx) = (Mutex::new(tx), Mutex::new(rx));
+        let unblocked = Arc::new(AtomicBool::new(false));
+        let recorder = Arc::clone(&unblocked);
+        let mut tp = ThreadPoolQ::new(2, QueueOrder::Fifo, move |num: usize| match num {
+            // Holds its worker until job 2 has run
+            0 => {
+                let received = rx.lock().unwrap().recv_timeout(Duration::from_secs(5));
+                recorder.store(received.is_ok(), Ordering::SeqCst);
+            }
+            2 => tx.lock().unwrap().send(()).unwrap(),
+            _ => {}
+        });
+        // Jobs 0 and 2 share a queue, 2 only runs if the other worker steals it
+        tp.push_job(0);
+        tp.push_job(1);
+        tp.push_job(2);
+        drop(tp);
+        assert!(unblocked.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn lifo_takes_own_newest_job_before_stealing() {
+        let shared = Shared::new(2, QueueOrder::Lifo);
+        for num in 2..8 {
+            shared.queues[num % 2].lock().unwrap().push_back(num);
+            shared.pending.fetch_add(1, Ordering::SeqCst);
+        }
+        let taken: Vec<usize> = (0..6).map_while(|_| shared.take(0)).collect();
+        assert_eq!(taken, vec![6, 4, 2, 7, 5, 3]);
+        assert_eq!(shared.pending.load(Ordering::SeqCst), 0);
     }
 }
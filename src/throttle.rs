@@ -0,0 +1,80 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Token bucket pacing accepted connections. It holds up to a second's worth of
+// tokens so short bursts pass straight through, beyond that every connection
+// waits for the next token instead of being refused.
+pub struct AcceptThrottle {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl AcceptThrottle {
+    pub fn new(per_second: u32) -> AcceptThrottle {
+        let rate = f64::from(per_second.max(1));
+        AcceptThrottle {
+            rate,
+            tokens: rate,
+            last: Instant::now(),
+        }
+    }
+
+    // Takes a token, sleeping until one is available
+    pub fn wait(&mut self) {
+        let delay = self.take(Instant::now());
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+    }
+
+    // Takes a token at now, returning how long until it's actually there. The
+    // bucket may go into debt, later callers then wait behind the earlier ones.
+    fn take(&mut self, now: Instant) -> Duration {
+        let refill = now.saturating_duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.last = now;
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bursts_up_to_the_rate_pass() {
+        let mut throttle = AcceptThrottle::new(10);
+        let now = throttle.last;
+        for _ in 0..10 {
+            assert_eq!(throttle.take(now), Duration::ZERO);
+        }
+        assert_eq!(throttle.take(now), Duration::from_millis(100));
+        assert_eq!(throttle.take(now), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn tokens_refill_over_time() {
+        let mut throttle = AcceptThrottle::new(10);
+        let start = throttle.last;
+        for _ in 0..10 {
+            throttle.take(start);
+        }
+        let later = start + Duration::from_millis(300);
+        for _ in 0..3 {
+            assert_eq!(throttle.take(later), Duration::ZERO);
+        }
+        assert!(throttle.take(later) > Duration::ZERO);
+        // Idle time never saves up more than one burst
+        let idle = later + Duration::from_secs(60);
+        for _ in 0..10 {
+            assert_eq!(throttle.take(idle), Duration::ZERO);
+        }
+        assert!(throttle.take(idle) > Duration::ZERO);
+    }
+}
@@ -3,7 +3,15 @@ pub mod html {
 
     use crate::http10::result_codes::ResultCode;
 
-    pub fn dir_listing(paths: Vec<String>) -> String {
+    // Lists the directory at request path dir. Links are made absolute under prefix,
+    // the path the site is mounted at, and the root gets no link to its parent.
+    // Entries link by their file name alone, wherever the directory is on disk.
+    pub fn dir_listing(paths: Vec<String>, dir: &str, prefix: &str) -> String {
+        let parent = dir
+            .trim_end_matches('/')
+            .rsplit_once('/')
+            .map(|(parent, _)| format!("<li><a href='{}{}/'>../</a></li>", prefix, escape(parent)));
+        let base = format!("{}{}/", prefix, dir.trim_end_matches('/'));
         format!(
             "<html>\n\
                 <head>\n\
@@ -11,23 +19,38 @@ pub mod html {
                 </head>\n\
                 <body>\n\
                     <ul>\n\
-                        <li><a href='../'>../</a></li>\n\
                         {}\n\
                     </ul>\n\
                 </body>\n\
             </html>",
-            paths
-                .iter()
-                .map(|path| format!(
-                    "<li><a href='{}'>{}</a></li>",
-                    &path[1..],
-                    Path::new(&path).file_name().unwrap().to_str().unwrap(),
-                ))
+            parent
+                .into_iter()
+                .chain(paths.iter().filter_map(|path| {
+                    let name = Path::new(path).file_name()?.to_string_lossy();
+                    Some(format!(
+                        "<li><a href='{}'>{}</a></li>",
+                        escape(&format!("{}{}", base, encode_segment(&name))),
+                        escape(&name),
+                    ))
+                }))
                 .collect::<Vec<String>>()
                 .join("\n")
         )
     }
 
+    // Percent-encodes a path segment, leaving only the characters unreserved in URIs
+    fn encode_segment(segment: &str) -> String {
+        segment
+            .bytes()
+            .map(|byte| match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    (byte as char).to_string()
+                }
+                byte => format!("%{:02X}", byte),
+            })
+            .collect()
+    }
+
     pub fn error_page(err: ResultCode) -> String {
         format!(
             "<html>\n\
@@ -38,24 +61,58 @@ pub mod html {
                 <h1>{}</h1>\n\
             </body>\n\
         </html>",
-            Into::<String>::into(err),
+            err.reason_phrase(),
             Into::<String>::into(err)
         )
     }
 
+    pub fn error_page_with_detail(err: ResultCode, detail: &str) -> String {
+        format!(
+            "<html>\n\
+            <head>\n\
+                <title>{}</title>\n\
+            </head>\n\
+            <body>\n\
+                <h1>{}</h1>\n\
+                <p>{}</p>\n\
+            </body>\n\
+        </html>",
+            err.reason_phrase(),
+            Into::<String>::into(err),
+            escape(detail)
+        )
+    }
+
+    // Escapes text so it can be safely embedded in generated pages
+    pub fn escape(text: &str) -> String {
+        text.chars()
+            .map(|c| match c {
+                '&' => "&amp;".to_string(),
+                '<' => "&lt;".to_string(),
+                '>' => "&gt;".to_string(),
+                '"' => "&quot;".to_string(),
+                '\'' => "&#39;".to_string(),
+                _ => c.to_string(),
+            })
+            .collect()
+    }
+
     #[cfg(test)]
     mod test {
         use super::*;
         #[test]
         fn test_directory_listing() {
-            let listing = dir_listing(vec!["./index.html".to_string(), "./banana.php".to_string()]);
+            let listing = dir_listing(
+                vec!["./index.html".to_string(), "./banana.php".to_string()],
+                "/",
+                "",
+            );
             let html = "<html>\n\
                     <head>\n\
                         <title>Directory Listing</title>\n\
                     </head>\n\
                     <body>\n\
                         <ul>\n\
-                            <li><a href='../'>../</a></li>\n\
                             <li><a href='/index.html'>index.html</a></li>\n\
                             <li><a href='/banana.php'>banana.php</a></li>\n\
                         </ul>\n\
@@ -66,23 +123,254 @@ pub mod html {
 
         #[test]
         fn test_directory_listing_subpath() {
-            let listing = dir_listing(vec![
-                "./src/index.html".to_string(),
-                "./yellow/banana.php".to_string(),
-            ]);
+            let listing = dir_listing(
+                vec![
+                    "./src/index.html".to_string(),
+                    "./src/banana.php".to_string(),
+                ],
+                "/src/",
+                "",
+            );
             let html = "<html>\n\
                     <head>\n\
                         <title>Directory Listing</title>\n\
                     </head>\n\
                     <body>\n\
                         <ul>\n\
-                            <li><a href='../'>../</a></li>\n\
+                            <li><a href='/'>../</a></li>\n\
                             <li><a href='/src/index.html'>index.html</a></li>\n\
-                            <li><a href='/yellow/banana.php'>banana.php</a></li>\n\
+                            <li><a href='/src/banana.php'>banana.php</a></li>\n\
                         </ul>\n\
                     </body>\n\
                 </html>";
             assert_eq!(listing, html);
         }
+
+        #[test]
+        fn test_directory_listing_under_prefix() {
+            let listing = dir_listing(vec!["./src/index.html".to_string()], "/src/", "/app");
+            assert!(listing.contains("<li><a href='/app/src/index.html'>index.html</a></li>"));
+            assert!(listing.contains("<li><a href='/app/'>../</a></li>"));
+            let listing = dir_listing(Vec::new(), "/", "/app");
+            assert!(!listing.contains("../"));
+        }
+
+        #[test]
+        fn test_directory_listing_outside_working_dir() {
+            let listing = dir_listing(
+                vec![
+                    "/srv/www/docs/a.txt".to_string(),
+                    "/srv/www/docs/it's <new>.txt".to_string(),
+                    "/srv/www/docs/50%#1?.txt".to_string(),
+                ],
+                "/docs",
+                "/app",
+            );
+            assert!(listing.contains("<li><a href='/app/docs/a.txt'>a.txt</a></li>"));
+            assert!(listing.contains(
+                "<li><a href='/app/docs/it%27s%20%3Cnew%3E.txt'>it&#39;s &lt;new&gt;.txt</a></li>"
+            ));
+            assert!(
+                listing.contains("<li><a href='/app/docs/50%25%231%3F.txt'>50%#1?.txt</a></li>")
+            );
+            assert!(!listing.contains("srv"));
+        }
+
+        #[test]
+        fn test_directory_listing_nested_parent() {
+            let listing = dir_listing(vec!["./a/b/c.txt".to_string()], "/a/b/", "");
+            assert!(listing.contains("<li><a href='/a/'>../</a></li>"));
+            let listing = dir_listing(Vec::new(), "/a/b", "");
+            assert!(listing.contains("<li><a href='/a/'>../</a></li>"));
+            let listing = dir_listing(Vec::new(), "/it's/", "");
+            assert!(listing.contains("<li><a href='/'>../</a></li>"));
+            let listing = dir_listing(Vec::new(), "/it's/sub/", "");
+            assert!(listing.contains("<li><a href='/it&#39;s/'>../</a></li>"));
+        }
+
+        #[test]
+        fn test_error_page_title() {
+            let page = error_page(ResultCode::NotFound);
+            assert!(page.contains("<title>Not Found</title>"));
+            assert!(page.contains("<h1>404 Not Found</h1>"));
+        }
+
+        #[test]
+        fn test_error_page_escapes_detail() {
+            let page = error_page_with_detail(ResultCode::BadRequest, "<script>");
+            assert!(page.contains("<p>&lt;script&gt;</p>"));
+            assert!(!page.contains("<script>"));
+        }
+    }
+}
+
+pub mod etag {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    // Strong entity tag derived from the representation's bytes
+    pub fn from_content(content: &[u8]) -> String {
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        format!("\"{:016x}\"", hasher.finish())
+    }
+
+    // Marks a tag as weak, used when the body is transformed (e.g. compressed)
+    pub fn weaken(tag: &str) -> String {
+        if tag.starts_with("W/") {
+            tag.to_string()
+        } else {
+            format!("W/{}", tag)
+        }
+    }
+
+    // Weak comparison as used by If-None-Match (RFC 7232 2.3.2)
+    pub fn weak_eq(a: &str, b: &str) -> bool {
+        a.trim_start_matches("W/") == b.trim_start_matches("W/")
+    }
+
+    // Whether any tag from an If-None-Match list matches the current tag
+    pub fn none_match(tags: &[String], current: &str) -> bool {
+        tags.iter().any(|tag| tag == "*" || weak_eq(tag, current))
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn test_etag_is_stable_for_content() {
+            assert_eq!(from_content(b"hello"), from_content(b"hello"));
+            assert_ne!(from_content(b"hello"), from_content(b"world"));
+        }
+
+        #[test]
+        fn test_weak_comparison() {
+            let tag = from_content(b"hello");
+            assert!(weak_eq(&weaken(&tag), &tag));
+            assert!(none_match(&["*".to_string()], &tag));
+            assert!(!none_match(&[from_content(b"world")], &tag));
+        }
+    }
+}
+
+pub mod request_id {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    // Random 64 bits from the std hasher's per-instance keys, mixed with a counter
+    // and the clock so ids stay unique even if the keys repeat
+    fn random_u64() -> u64 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_nanos())
+                .unwrap_or(0),
+        );
+        hasher.finish()
+    }
+
+    // Generates an id formatted as a version 4 UUID
+    pub fn generate() -> String {
+        let high = (random_u64() & !0xf000) | 0x4000;
+        let low = (random_u64() & !(0xc << 60)) | (0x8 << 60);
+        format!(
+            "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+            high >> 32,
+            (high >> 16) & 0xffff,
+            high & 0xffff,
+            low >> 48,
+            low & 0xffff_ffff_ffff
+        )
+    }
+
+    // Ids passed in by clients are echoed back and logged, so only accept short
+    // ones made of safe characters
+    pub fn is_valid(id: &str) -> bool {
+        !id.is_empty()
+            && id.len() <= 128
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn test_generates_unique_uuids() {
+            let a = generate();
+            let b = generate();
+            assert_ne!(a, b);
+            assert_eq!(a.len(), 36);
+            assert_eq!(&a[14..15], "4");
+            assert!(is_valid(&a));
+        }
+
+        #[test]
+        fn test_rejects_unsafe_ids() {
+            assert!(is_valid("req-42"));
+            assert!(!is_valid(""));
+            assert!(!is_valid("<script>"));
+            assert!(!is_valid(&"a".repeat(129)));
+        }
+    }
+}
+
+pub mod http_date {
+    use std::sync::RwLock;
+
+    use chrono::{DateTime, FixedOffset};
+
+    // The last formatted second. Every response carries a Date of roughly now, so
+    // under load most of them land in a second that was already formatted
+    static CACHED: RwLock<(i64, String)> = RwLock::new((i64::MIN, String::new()));
+
+    // Formats a date as RFC 2822, reusing the previous result within the same second
+    pub fn format(date: &DateTime<FixedOffset>) -> String {
+        // Only UTC dates are cached, the offset is part of the text
+        if date.offset().local_minus_utc() != 0 {
+            return date.to_rfc2822();
+        }
+        let second = date.timestamp();
+        if let Ok(cached) = CACHED.read() {
+            if cached.0 == second {
+                return cached.1.clone();
+            }
+        }
+        let formatted = date.to_rfc2822();
+        if let Ok(mut cached) = CACHED.write() {
+            // Don't let a slow thread move the cache back in time
+            if second > cached.0 {
+                *cached = (second, formatted.clone());
+            }
+        }
+        formatted
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use chrono::{Duration, Utc};
+
+        #[test]
+        fn test_matches_uncached_format() {
+            let now: DateTime<FixedOffset> = Utc::now().into();
+            assert_eq!(format(&now), now.to_rfc2822());
+            assert_eq!(format(&now), now.to_rfc2822());
+            let later = now + Duration::seconds(1);
+            assert_eq!(format(&later), later.to_rfc2822());
+            let earlier = now - Duration::days(1);
+            assert_eq!(format(&earlier), earlier.to_rfc2822());
+            let offset = now.with_timezone(&FixedOffset::east_opt(3600).unwrap());
+            assert_eq!(format(&offset), offset.to_rfc2822());
+        }
     }
 }